@@ -1,3 +1,21 @@
+use std::process::Command;
+
 fn main() {
     embuild::espidf::sysenv::output();
+
+    // Short hash of the commit being built, so units in the field can be
+    // told apart by build and not just by version. Builds outside of a git
+    // checkout get "unknown".
+    let git_hash = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|hash| hash.trim().to_string())
+        .filter(|hash| !hash.is_empty())
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=GIT_HASH={}", git_hash);
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
 }
@@ -0,0 +1,10 @@
+// Only modules that build without ESP-IDF belong here, the firmware crate
+// itself can't be compiled for the host. Their #[cfg(test)] modules are the
+// tests.
+
+#[path = "../../src/batch.rs"]
+pub mod batch;
+#[path = "../../src/credentials.rs"]
+pub mod credentials;
+#[path = "../../src/endpoint.rs"]
+pub mod endpoint;
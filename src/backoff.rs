@@ -0,0 +1,40 @@
+use rand::Rng;
+use std::time::Duration;
+
+// Capped exponential backoff with random jitter. A collector or AP outage
+// hits the whole fleet at once, without the jitter every device would retry
+// in lockstep and hammer the server the moment it's back.
+#[derive(Clone, Debug)]
+pub struct Backoff {
+    min: Duration,
+    max: Duration,
+    // Share of the delay that may be cut off at random, 0 to 1
+    jitter: f32,
+    current: Duration,
+}
+
+impl Backoff {
+    pub fn new(min: Duration, max: Duration, jitter_pct: u32) -> Self {
+        Self {
+            min,
+            max: max.max(min),
+            jitter: (jitter_pct.min(100) as f32) / 100.0,
+            current: min,
+        }
+    }
+
+    // The delay before the next attempt, doubling with every call up to the
+    // maximum. The jitter is cut off after clamping to the minimum, so the
+    // first retries are spread out too.
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.current.max(self.min);
+        let cut = delay.mul_f32(self.jitter * rand::thread_rng().gen::<f32>());
+        let delay = delay.saturating_sub(cut);
+        self.current = (self.current * 2).min(self.max);
+        delay
+    }
+
+    pub fn reset(&mut self) {
+        self.current = self.min;
+    }
+}
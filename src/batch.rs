@@ -0,0 +1,379 @@
+use anyhow::{bail, Result};
+use hecate_protobuf as proto;
+use proto::{Message, SensorDataSample};
+use serde::Serialize;
+
+// Turning samples into binary messages. Nothing in here touches the
+// hardware or the configuration, so it runs off-target as well.
+
+// Length and CRC32 in front of a batch with checksum
+pub const CHECKSUM_HEADER_LEN: usize = 8;
+
+// {"type":"sensor_data","samples":[]} around the samples of a JSON batch
+const JSON_ENVELOPE_LEN: usize = 35;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Encoding {
+    Protobuf,
+    // SensorData as JSON text frames, for debugging with tools that can't
+    // decode protobuf. Several times larger and slower to encode.
+    Json,
+}
+
+impl Encoding {
+    pub fn parse(name: &str) -> Result<Self> {
+        match name {
+            "protobuf" => Ok(Encoding::Protobuf),
+            "json" => Ok(Encoding::Json),
+            other => bail!("Invalid sample format \"{}\" (expected protobuf or json)", other),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Format {
+    pub encoding: Encoding,
+    pub checksum: bool,
+}
+
+// SensorData with the protobuf field names, tagged like the other text
+// messages: {"type":"sensor_data","samples":[{"time":0.01,"acceleration":
+// {"x":0.0,"y":0.0,"z":1.0},"magnetometer":{...},"gyroscope":{...}}]}
+#[derive(Serialize)]
+struct JsonSensorData {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    samples: Vec<JsonSample>,
+}
+
+#[derive(Serialize)]
+struct JsonSample {
+    time: f32,
+    acceleration: Xyz,
+    magnetometer: Xyz,
+    gyroscope: Xyz,
+}
+
+#[derive(Serialize)]
+struct Xyz {
+    x: f32,
+    y: f32,
+    z: f32,
+}
+
+impl From<&SensorDataSample> for JsonSample {
+    fn from(sample: &SensorDataSample) -> Self {
+        let (acc, mag, gyro) = (&sample.acceleration, &sample.magnetometer, &sample.gyroscope);
+        Self {
+            time: sample.time,
+            acceleration: Xyz { x: acc.x, y: acc.y, z: acc.z },
+            magnetometer: Xyz { x: mag.x, y: mag.y, z: mag.z },
+            gyroscope: Xyz { x: gyro.x, y: gyro.y, z: gyro.z },
+        }
+    }
+}
+
+fn json_sample_len(sample: &SensorDataSample) -> usize {
+    serde_json::to_vec(&JsonSample::from(sample)).map_or(usize::MAX, |json| json.len())
+}
+
+impl Format {
+    // Room for the encoded SensorData in a message of at most max_payload_len
+    pub fn max_len(&self, max_payload_len: usize) -> usize {
+        match self.checksum {
+            true => max_payload_len.saturating_sub(CHECKSUM_HEADER_LEN),
+            false => max_payload_len,
+        }
+    }
+
+    // JSON batches go out as text frames
+    pub fn is_text(&self) -> bool {
+        self.encoding == Encoding::Json
+    }
+
+    // With checksum, the encoded SensorData is preceded by its length and
+    // its CRC32, both little endian u32. A collector can then tell a
+    // truncated message from a corrupted one when decoding fails.
+    pub fn encode(&self, data: &proto::SensorData, max_len: usize) -> Result<Vec<u8>> {
+        if self.encoding == Encoding::Json {
+            return encode_json(data, max_len);
+        }
+
+        // Check the size up front, a frame that doesn't fit the write buffer
+        // would only fail later inside the framer
+        let len = data.encoded_len();
+        if len > max_len {
+            bail!("Encoded batch is {} bytes, frame payload limit is {} bytes", len, max_len);
+        }
+
+        let header_len = if self.checksum { CHECKSUM_HEADER_LEN } else { 0 };
+        let mut buf = Vec::with_capacity(header_len + len);
+        buf.resize(header_len, 0);
+        data.encode(&mut buf)?;
+
+        if self.checksum {
+            let crc = crc32(&buf[header_len..]);
+            buf[..4].copy_from_slice(&(len as u32).to_le_bytes());
+            buf[4..8].copy_from_slice(&crc.to_le_bytes());
+        }
+
+        Ok(buf)
+    }
+}
+
+// Checksums are protobuf only, validate() makes sure
+fn encode_json(data: &proto::SensorData, max_len: usize) -> Result<Vec<u8>> {
+    encode_json_as("sensor_data", data, max_len)
+}
+
+// Samples of a ringbuffer dump, as JSON whatever the sample format so a
+// collector can't take them for data to store. Tagged "dump_data", which is
+// no longer than "sensor_data", so split() with Encoding::Json sizes them.
+pub fn encode_dump(data: &proto::SensorData, max_len: usize) -> Result<Vec<u8>> {
+    encode_json_as("dump_data", data, max_len)
+}
+
+fn encode_json_as(kind: &'static str, data: &proto::SensorData, max_len: usize) -> Result<Vec<u8>> {
+    let json = serde_json::to_vec(&JsonSensorData {
+        kind,
+        samples: data.samples.iter().map(JsonSample::from).collect(),
+    })?;
+    if json.len() > max_len {
+        bail!("Encoded batch is {} bytes, frame payload limit is {} bytes", json.len(), max_len);
+    }
+    Ok(json)
+}
+
+// Splits the samples into batches that each fit into one message in the
+// given encoding. A single sample that is too large on its own still ends up
+// in a batch of its own, Format::encode() rejects it then.
+pub fn split(samples: Vec<SensorDataSample>, max_len: usize, encoding: Encoding) -> Vec<proto::SensorData> {
+    let mut batches = Vec::new();
+    let mut current = proto::SensorData { samples: Vec::new() };
+    // JSON is measured per sample, there's no encoded_len() for it. The
+    // samples are separated by commas.
+    let mut json_len = JSON_ENVELOPE_LEN;
+
+    for sample in samples {
+        let too_large = match encoding {
+            Encoding::Protobuf => {
+                current.samples.push(sample);
+                current.samples.len() > 1 && current.encoded_len() > max_len
+            },
+            Encoding::Json => {
+                let sample_len = json_sample_len(&sample) + usize::from(!current.samples.is_empty());
+                current.samples.push(sample);
+                json_len = json_len.saturating_add(sample_len);
+                current.samples.len() > 1 && json_len > max_len
+            },
+        };
+
+        if too_large {
+            let overflow: Vec<_> = current.samples.pop().into_iter().collect();
+            if encoding == Encoding::Json {
+                json_len = JSON_ENVELOPE_LEN + overflow.iter().map(json_sample_len).sum::<usize>();
+            }
+            batches.push(std::mem::replace(&mut current, proto::SensorData { samples: overflow }));
+        }
+    }
+    batches.push(current);
+
+    if batches.len() > 1 {
+        log::warn!("Batch exceeds the {} byte payload limit, sending it as {} messages. Consider lowering batch_size.",
+            max_len, batches.len());
+    }
+
+    batches
+}
+
+// CRC-32 as in zlib and Ethernet (reflected, polynomial 0x04C11DB7). Batches
+// are a few KB at most, no table needed.
+fn crc32(data: &[u8]) -> u32 {
+    !crc32_update(!0, data)
+}
+
+fn crc32_update(mut crc: u32, data: &[u8]) -> u32 {
+    for &byte in data {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xEDB8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    crc
+}
+
+// Rolling digest of a session's batches: the CRC-32 of every batch's seq
+// followed by its frame's CRC-32, both little endian, in the order sent. The
+// collector computes the same over what it got and compares at the end.
+#[derive(Clone, Copy, Debug)]
+pub struct SessionDigest {
+    pub first_seq: u32,
+    pub batches: u32,
+    crc: u32,
+}
+
+impl SessionDigest {
+    pub fn new(first_seq: u32) -> Self {
+        Self { first_seq, batches: 0, crc: !0 }
+    }
+
+    pub fn add(&mut self, seq: u32, frame: &[u8]) {
+        self.crc = crc32_update(self.crc, &seq.to_le_bytes());
+        self.crc = crc32_update(self.crc, &crc32(frame).to_le_bytes());
+        self.batches += 1;
+    }
+
+    pub fn value(&self) -> u32 {
+        !self.crc
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROTOBUF: Format = Format { encoding: Encoding::Protobuf, checksum: false };
+
+    fn sample(time: f32, value: f32) -> SensorDataSample {
+        SensorDataSample {
+            time,
+            acceleration: proto::Acceleration { x: value, y: -value, z: 1.0 },
+            magnetometer: proto::MagnetometerData { x: value, y: 0.5, z: -value },
+            gyroscope: proto::GyroscopeData { x: -0.5, y: value, z: value },
+        }
+    }
+
+    fn samples(count: usize) -> Vec<SensorDataSample> {
+        (0..count).map(|i| sample(i as f32 * 0.01, i as f32)).collect()
+    }
+
+    fn round_trip(data: &proto::SensorData) -> proto::SensorData {
+        let message = PROTOBUF.encode(data, usize::MAX).unwrap();
+        proto::SensorData::decode(&message[..]).unwrap()
+    }
+
+    #[test]
+    fn empty_batch_round_trips() {
+        let data = proto::SensorData { samples: Vec::new() };
+        assert_eq!(round_trip(&data), data);
+    }
+
+    #[test]
+    fn full_batch_round_trips() {
+        let data = proto::SensorData { samples: samples(100) };
+        assert_eq!(round_trip(&data), data);
+    }
+
+    #[test]
+    fn extreme_floats_round_trip() {
+        let values = [f32::MAX, f32::MIN, f32::MIN_POSITIVE, f32::from_bits(1), f32::EPSILON, -0.0, f32::INFINITY, f32::NEG_INFINITY];
+        let data = proto::SensorData {
+            samples: values.iter().map(|&value| sample(value, value)).collect(),
+        };
+        let decoded = round_trip(&data);
+        assert_eq!(decoded, data);
+        // == doesn't tell 0.0 from -0.0
+        let bits = |data: &proto::SensorData| data.samples.iter().map(|sample| sample.acceleration.x.to_bits()).collect::<Vec<_>>();
+        assert_eq!(bits(&decoded), bits(&data));
+    }
+
+    #[test]
+    fn nan_round_trips() {
+        let data = proto::SensorData { samples: vec![sample(0.0, f32::NAN)] };
+        let decoded = round_trip(&data);
+        assert!(decoded.samples[0].acceleration.x.is_nan());
+        assert!(decoded.samples[0].gyroscope.z.is_nan());
+    }
+
+    #[test]
+    fn batch_of_exactly_max_len_is_not_split() {
+        let samples = samples(10);
+        let max_len = proto::SensorData { samples: samples.clone() }.encoded_len();
+
+        let batches = split(samples.clone(), max_len, Encoding::Protobuf);
+        assert_eq!(batches.len(), 1);
+        assert_eq!(batches[0].samples, samples);
+        assert_eq!(PROTOBUF.encode(&batches[0], max_len).unwrap().len(), max_len);
+    }
+
+    #[test]
+    fn split_at_max_len_keeps_every_sample_in_order() {
+        let samples = samples(100);
+        let max_len = proto::SensorData { samples: samples[..10].to_vec() }.encoded_len();
+
+        let batches = split(samples.clone(), max_len, Encoding::Protobuf);
+        assert!(batches.len() > 1);
+        for data in &batches {
+            assert!(!data.samples.is_empty());
+            assert!(PROTOBUF.encode(data, max_len).is_ok());
+        }
+        let rejoined = batches.into_iter().flat_map(|data| data.samples).collect::<Vec<_>>();
+        assert_eq!(rejoined, samples);
+    }
+
+    #[test]
+    fn oversized_sample_is_rejected_on_its_own() {
+        let batches = split(samples(3), 1, Encoding::Protobuf);
+        assert_eq!(batches.len(), 3);
+        assert!(batches.iter().all(|data| PROTOBUF.encode(data, 1).is_err()));
+    }
+
+    #[test]
+    fn json_batches_fit_max_len() {
+        let json = Format { encoding: Encoding::Json, checksum: false };
+        let max_len = 1000;
+        let batches = split(samples(50), max_len, Encoding::Json);
+        assert!(batches.len() > 1);
+        for data in &batches {
+            let message = json.encode(data, max_len).unwrap();
+            let value: serde_json::Value = serde_json::from_slice(&message).unwrap();
+            assert_eq!(value["type"], "sensor_data");
+            assert_eq!(value["samples"].as_array().unwrap().len(), data.samples.len());
+        }
+    }
+
+    #[test]
+    fn dump_frames_are_tagged_and_fit_max_len() {
+        let max_len = 1000;
+        let batches = split(samples(50), max_len, Encoding::Json);
+        assert!(batches.len() > 1);
+        for data in &batches {
+            let message = encode_dump(data, max_len).unwrap();
+            let value: serde_json::Value = serde_json::from_slice(&message).unwrap();
+            assert_eq!(value["type"], "dump_data");
+            assert_eq!(value["samples"].as_array().unwrap().len(), data.samples.len());
+        }
+    }
+
+    #[test]
+    fn checksum_header_covers_the_message() {
+        let checksummed = Format { encoding: Encoding::Protobuf, checksum: true };
+        let data = proto::SensorData { samples: samples(5) };
+        let message = checksummed.encode(&data, usize::MAX).unwrap();
+
+        let payload = &message[CHECKSUM_HEADER_LEN..];
+        assert_eq!(u32::from_le_bytes(message[..4].try_into().unwrap()) as usize, payload.len());
+        assert_eq!(u32::from_le_bytes(message[4..8].try_into().unwrap()), crc32(payload));
+        assert_eq!(proto::SensorData::decode(payload).unwrap(), data);
+    }
+
+    #[test]
+    fn crc32_matches_zlib() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn session_digest_is_the_crc_of_seqs_and_frame_crcs() {
+        let frames: [&[u8]; 2] = [b"first frame", b"second frame"];
+        let mut digest = SessionDigest::new(41);
+        let mut stream = Vec::new();
+        for (seq, frame) in (41u32..).zip(frames) {
+            digest.add(seq, frame);
+            stream.extend(seq.to_le_bytes());
+            stream.extend(crc32(frame).to_le_bytes());
+        }
+        assert_eq!(digest.batches, 2);
+        assert_eq!(digest.value(), crc32(&stream));
+        assert_ne!(SessionDigest::new(41).value(), digest.value());
+    }
+}
@@ -0,0 +1,41 @@
+use anyhow::Result;
+use esp_idf_svc::hal::{
+    adc::{
+        attenuation,
+        oneshot::{config::AdcChannelConfig, AdcChannelDriver, AdcDriver},
+        ADC1,
+    },
+    gpio::Gpio35,
+};
+
+// The battery is connected to GPIO35 through a 1:1 divider
+const DIVIDER: u32 = 2;
+
+// Readings averaged into one measurement, the ADC is noisy
+const SAMPLES: u32 = 8;
+
+pub struct Battery {
+    channel: AdcChannelDriver<'static, Gpio35, AdcDriver<'static, ADC1>>,
+}
+
+impl Battery {
+    pub fn new(adc: ADC1, pin: Gpio35) -> Result<Self> {
+        let config = AdcChannelConfig {
+            attenuation: attenuation::DB_11,
+            calibration: true,
+            ..Default::default()
+        };
+
+        Ok(Self { channel: AdcChannelDriver::new(AdcDriver::new(adc)?, pin, &config)? })
+    }
+
+    // Battery voltage in millivolts
+    pub fn read_mv(&mut self) -> Result<u32> {
+        let mut sum = 0;
+        for _ in 0..SAMPLES {
+            sum += u32::from(self.channel.read()?);
+        }
+
+        Ok(sum / SAMPLES * DIVIDER)
+    }
+}
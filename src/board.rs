@@ -0,0 +1,39 @@
+use anyhow::{bail, Result};
+use esp_idf_svc::hal::gpio::{AnyIOPin, AnyInputPin, AnyOutputPin};
+
+// The ESP32's GPIOs. 6-11 are wired to the flash and 20 only exists on the
+// ESP32-PICO-V3 of the Feather V2, which is what we run on.
+pub fn is_gpio(gpio: i32) -> bool {
+    matches!(gpio, 0..=5 | 12..=23 | 25..=27 | 32..=39)
+}
+
+// GPIO34-39 have no output driver
+pub fn is_output_gpio(gpio: i32) -> bool {
+    is_gpio(gpio) && gpio < 34
+}
+
+// The pin constructors below take the pin number from the configuration
+// instead of the peripherals. That's only sound as long as no two
+// configured pins are the same and none of them is taken from the
+// peripherals as well, which Config::validate() checks.
+
+pub fn output_pin(gpio: i32) -> Result<AnyOutputPin> {
+    if !is_output_gpio(gpio) {
+        bail!("GPIO{} can't be used as an output", gpio);
+    }
+    Ok(unsafe { AnyOutputPin::new(gpio) })
+}
+
+pub fn input_pin(gpio: i32) -> Result<AnyInputPin> {
+    if !is_gpio(gpio) {
+        bail!("GPIO{} doesn't exist", gpio);
+    }
+    Ok(unsafe { AnyInputPin::new(gpio) })
+}
+
+pub fn io_pin(gpio: i32) -> Result<AnyIOPin> {
+    if !is_output_gpio(gpio) {
+        bail!("GPIO{} can't be used as an input and output", gpio);
+    }
+    Ok(unsafe { AnyIOPin::new(gpio) })
+}
@@ -0,0 +1,144 @@
+use anyhow::Result;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::imu::{ImuSensor, Triple};
+use crate::settings::SettingsStore;
+
+// Running mean and variance per axis
+#[derive(Default)]
+struct Stats {
+    count: u32,
+    sum: [f64; 3],
+    sum_sq: [f64; 3],
+}
+
+impl Stats {
+    fn add(&mut self, (x, y, z): Triple) {
+        for (axis, value) in [x, y, z].into_iter().enumerate() {
+            self.sum[axis] += f64::from(value);
+            self.sum_sq[axis] += f64::from(value) * f64::from(value);
+        }
+        self.count += 1;
+    }
+
+    fn mean(&self) -> [f32; 3] {
+        self.sum.map(|sum| (sum / f64::from(self.count)) as f32)
+    }
+
+    fn max_stddev(&self) -> f32 {
+        let n = f64::from(self.count);
+        (0..3)
+            .map(|axis| (self.sum_sq[axis] / n - (self.sum[axis] / n).powi(2)).max(0.0).sqrt() as f32)
+            .fold(0.0, f32::max)
+    }
+}
+
+// Estimates the gyroscope's zero-rate offset from readings taken while the
+// board is still. Returns None if the readings spread more than max_stddev
+// (in dps) on any axis, the board was moving then and the mean would be
+// rotation rather than offset.
+pub fn gyro_bias(sensor: &mut impl ImuSensor, samples: u32, interval: Duration, max_stddev: f32) -> Result<Option<[f32; 3]>> {
+    let mut stats = Stats::default();
+    let mut failures = 0;
+
+    while stats.count < samples {
+        match sensor.read_gyro() {
+            Ok(gyro) => stats.add(gyro),
+            // A few transient failures are fine, a dead sensor isn't
+            Err(e) if failures >= samples => return Err(e),
+            Err(_) => failures += 1,
+        }
+        std::thread::sleep(interval);
+    }
+
+    let stddev = stats.max_stddev();
+    if stddev > max_stddev {
+        log::warn!("Board was moving during gyroscope calibration (σ = {:.3} dps), skipping it", stddev);
+        return Ok(None);
+    }
+
+    Ok(Some(stats.mean()))
+}
+
+// Smallest spread per axis for a usable hard-iron estimate. The earth's field
+// is 0.25-0.65 gauss, so a full rotation spans at least twice that.
+const MIN_MAG_SPAN_GAUSS: f32 = 0.3;
+
+// Requested magnetometer calibration duration in seconds, 0 for none. Set by
+// the networking thread, picked up by the sampling thread.
+static MAG_REQUEST: AtomicU32 = AtomicU32::new(0);
+
+pub fn request_mag_calibration(duration: Duration) {
+    MAG_REQUEST.store(duration.as_secs().max(1) as u32, Ordering::Relaxed);
+}
+
+// Hard-iron calibration from the readings the sampling loop takes anyway:
+// while the board is rotated, the middle of each axis' min/max is the offset
+// the board itself adds to the field
+pub struct MagCalibrator {
+    store: Option<SettingsStore>,
+    run: Option<MagRun>,
+}
+
+struct MagRun {
+    until: Instant,
+    min: [f32; 3],
+    max: [f32; 3],
+}
+
+impl MagCalibrator {
+    // Results are stored in NVS if a store is given
+    pub fn new(store: Option<SettingsStore>, on_boot: Duration) -> Self {
+        if !on_boot.is_zero() {
+            request_mag_calibration(on_boot);
+        }
+        Self { store, run: None }
+    }
+
+    pub fn stored_offset(&self) -> Option<[f32; 3]> {
+        self.store.as_ref()?.load_mag_offset()
+            .inspect_err(|e| log::warn!("Failed to load magnetometer offset: {}", e))
+            .ok()?
+    }
+
+    // Returns the new offset once a calibration run completed
+    pub fn observe(&mut self, mag: &Result<Triple>) -> Option<[f32; 3]> {
+        let requested = MAG_REQUEST.swap(0, Ordering::Relaxed);
+        if requested > 0 {
+            log::info!("Calibrating magnetometer for {} s, rotate the board in all directions", requested);
+            self.run = Some(MagRun {
+                until: Instant::now() + Duration::from_secs(requested.into()),
+                min: [f32::MAX; 3],
+                max: [f32::MIN; 3],
+            });
+        }
+
+        let run = self.run.as_mut()?;
+        if let Ok((x, y, z)) = *mag {
+            for (axis, value) in [x, y, z].into_iter().enumerate() {
+                run.min[axis] = run.min[axis].min(value);
+                run.max[axis] = run.max[axis].max(value);
+            }
+        }
+
+        if Instant::now() < run.until {
+            return None;
+        }
+
+        let MagRun { min, max, .. } = self.run.take()?;
+        if (0..3).any(|axis| max[axis] - min[axis] < MIN_MAG_SPAN_GAUSS) {
+            log::warn!("Board wasn't rotated enough during magnetometer calibration (min {:?}, max {:?}), keeping the old offset", min, max);
+            return None;
+        }
+
+        let offset = [0, 1, 2].map(|axis| (min[axis] + max[axis]) / 2.0);
+        log::info!("Magnetometer hard-iron offset is {:?} gauss", offset);
+        if let Some(store) = &mut self.store {
+            _ = store.save_mag_offset(offset)
+                .inspect_err(|e| log::error!("Failed to store magnetometer offset: {}", e));
+        }
+
+        Some(offset)
+    }
+}
@@ -0,0 +1,138 @@
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::telemetry;
+
+// Sample times come from the ESP32's timer, whose crystal is off by a few
+// dozen ppm, so over days they drift away from wall-clock time. Every NTP
+// sync measures that drift and corrects for it. Corrected time is:
+//
+//   anchor_corrected + (t - anchor) * rate + slew over [anchor, slew_until]
+//
+// After a sync the remaining error is slewed away rather than stepped, so
+// corrected time never goes backwards and samples keep their spacing to
+// within MAX_SLEW.
+
+// Share of the elapsed time that slewing may add or remove
+const MAX_SLEW: f64 = 0.01;
+// Errors are slewed away over this long, or slower if MAX_SLEW requires it
+const SLEW_TIME_US: f64 = 60_000_000.0;
+// Beyond this the clock was set rather than drifted, e.g. by a DHCP
+// provided NTP server, and the origin is moved instead
+const MAX_ERROR_US: i64 = 60_000_000;
+// Syncs closer together than this don't tell the drift apart from the
+// network delay
+const MIN_SYNC_SPAN_US: i64 = 600_000_000;
+// More than any crystal is off, the sync was bogus then
+const MAX_DRIFT: f64 = 0.001;
+
+struct Clock {
+    anchor: i64,
+    anchor_corrected: i64,
+    // Corrected µs per timer µs
+    rate: f64,
+    slew: f64,
+    slew_until: i64,
+    // Timer and UTC µs of the last sync the drift was measured from
+    last_sync: Option<(i64, i64)>,
+    // UTC µs at corrected time zero, fixed by the first sync
+    boot_utc: Option<i64>,
+}
+
+static CLOCK: Mutex<Clock> = Mutex::new(Clock {
+    anchor: 0,
+    anchor_corrected: 0,
+    rate: 1.0,
+    slew: 0.0,
+    slew_until: 0,
+    last_sync: None,
+    boot_utc: None,
+});
+
+fn clock() -> std::sync::MutexGuard<'static, Clock> {
+    CLOCK.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn micros(duration: Duration) -> i64 {
+    duration.as_micros() as i64
+}
+
+impl Clock {
+    fn corrected(&self, t: i64) -> i64 {
+        let elapsed = (t - self.anchor) as f64;
+        let slewed = (t.min(self.slew_until) - self.anchor).max(0) as f64;
+        self.anchor_corrected + (elapsed * self.rate + slewed * self.slew) as i64
+    }
+
+    fn sync(&mut self, now: i64, utc: i64) {
+        let Some(boot_utc) = self.boot_utc else {
+            // Nothing to correct yet, corrected time is the timer's until
+            // the next sync
+            self.boot_utc = Some(utc - self.corrected(now));
+            self.last_sync = Some((now, utc));
+            log::info!("Clock synced, sample times are absolute from now on");
+            return;
+        };
+
+        let corrected = self.corrected(now);
+        let error = utc - boot_utc - corrected;
+
+        let mut rate = self.rate;
+        if let Some((last_now, last_utc)) = self.last_sync {
+            if now - last_now >= MIN_SYNC_SPAN_US {
+                let measured = (utc - last_utc) as f64 / (now - last_now) as f64;
+                if (measured - 1.0).abs() <= MAX_DRIFT {
+                    rate = measured;
+                } else {
+                    log::warn!("Ignoring implausible clock drift of {:.0} ppm", (measured - 1.0) * 1e6);
+                }
+                self.last_sync = Some((now, utc));
+            }
+        }
+
+        self.anchor = now;
+        self.anchor_corrected = corrected;
+        self.rate = rate;
+        if error.abs() > MAX_ERROR_US {
+            log::warn!("Clock was off by {:.1} s, moving the time origin", error as f64 / 1e6);
+            self.boot_utc = Some(utc - corrected);
+            self.last_sync = Some((now, utc));
+            self.slew = 0.0;
+            self.slew_until = now;
+            return;
+        }
+
+        self.slew = (error as f64 / SLEW_TIME_US).clamp(-MAX_SLEW, MAX_SLEW);
+        self.slew_until = match self.slew {
+            slew if slew == 0.0 => now,
+            slew => now + (error as f64 / slew) as i64,
+        };
+        log::info!("Clock resynced: drift {:.1} ppm, slewing {} µs", (rate - 1.0) * 1e6, error);
+    }
+}
+
+// Called with the new system time whenever SNTP synced
+pub fn on_sync(utc: Duration) {
+    clock().sync(micros(telemetry::uptime()), micros(utc));
+}
+
+// Corrected time from the origin to a timer reading, both since boot.
+// Monotonic in since_boot, also across syncs.
+pub fn since_origin(since_boot: Duration, origin: Duration) -> Duration {
+    let corrected = clock().corrected(micros(since_boot));
+    Duration::from_micros((corrected - micros(origin)).max(0) as u64)
+}
+
+// UTC in µs since the epoch of a timer reading taken as origin, None before
+// the first sync
+pub fn utc_of(origin: Duration) -> Option<u64> {
+    let boot_utc = clock().boot_utc?;
+    u64::try_from(boot_utc + micros(origin)).ok()
+}
+
+// Measured drift of the timer against NTP, None until two syncs were far
+// enough apart
+pub fn drift_ppm() -> Option<f32> {
+    let clock = clock();
+    (clock.rate != 1.0).then(|| ((clock.rate - 1.0) * 1e6) as f32)
+}
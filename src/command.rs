@@ -0,0 +1,75 @@
+use serde::Deserialize;
+
+// Commands the collector can send as JSON text frames, e.g.
+// {"cmd":"ota","url":"https://example.com/hecate-sensor.bin","token":"..."}
+#[derive(Debug, Deserialize)]
+#[serde(tag = "cmd", rename_all = "snake_case")]
+pub enum Command {
+    // token has to match ota_token, see ota::check_request()
+    Ota {
+        url: String,
+        #[serde(default)]
+        token: String,
+    },
+    // Rotate the board through all orientations meanwhile
+    CalibrateMag { duration_s: u32 },
+    // "off", "error", "warn", "info", "debug" or "trace", for this
+    // firmware's own log output
+    Loglevel { level: String },
+    // Polling on a timer sets the interval, when the sensor paces sampling
+    // its ODR is set to the next supported rate
+    Rate { hz: f32 },
+    // Longest time between two data batches, a full batch goes out earlier
+    SendInterval { ms: u32 },
+    // Answered with the firmware version
+    Version,
+    // Answered with the counters, reset zeroes them afterwards
+    Stats {
+        #[serde(default)]
+        reset: bool,
+    },
+    // The collector got a replayed spill segment, see telemetry::Replayed
+    SpillAck { segment: u32 },
+    // Output register values next to the scaled ones, see
+    // telemetry::RawSample
+    Raw { enabled: bool },
+    // Send everything buffered right away, answered with
+    // telemetry::Flushed once it went out
+    Flush,
+    // A copy of the ringbuffer, announced with telemetry::Dump. Unlike
+    // flush, the samples stay where they are and are sent as usual later.
+    Dump,
+    // Sends a marker that has to come back, see linktest.rs
+    Linktest,
+    // Stop or resume sampling, the connection and commands keep working.
+    // Kept across reboots.
+    Maintenance { enabled: bool },
+    // Restart the integrated gyroscope angle from zero, see gyro_integrate
+    ZeroAngle,
+}
+
+impl Command {
+    pub fn parse(text: &str) -> serde_json::Result<Self> {
+        serde_json::from_str(text)
+    }
+
+    // The "cmd" it was sent as
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Ota { .. } => "ota",
+            Command::CalibrateMag { .. } => "calibrate_mag",
+            Command::Loglevel { .. } => "loglevel",
+            Command::Rate { .. } => "rate",
+            Command::SendInterval { .. } => "send_interval",
+            Command::Version => "version",
+            Command::Stats { .. } => "stats",
+            Command::SpillAck { .. } => "spill_ack",
+            Command::Raw { .. } => "raw",
+            Command::Flush => "flush",
+            Command::Dump => "dump",
+            Command::Linktest => "linktest",
+            Command::Maintenance { .. } => "maintenance",
+            Command::ZeroAngle => "zero_angle",
+        }
+    }
+}
@@ -0,0 +1,82 @@
+use std::error::Error;
+use std::fmt::Display;
+
+// Limits of the heapless strings in ClientConfiguration
+pub const MAX_SSID_LEN: usize = 32;
+pub const MAX_PSK_LEN: usize = 64;
+// WPA2 doesn't accept shorter passphrases, an empty PSK is an open network
+pub const MIN_PSK_LEN: usize = 8;
+
+// An SSID or PSK no network will take, named by the SSID
+#[derive(Debug)]
+pub enum CredentialError {
+    EmptySsid,
+    SsidTooLong(String),
+    PskTooShort(String),
+    PskTooLong(String),
+}
+
+// What the conversion into ClientConfiguration would trip over, so it can
+// be caught before connecting
+pub fn check_credentials(ssid: &str, psk: &str) -> Vec<CredentialError> {
+    let mut errors = Vec::new();
+    if ssid.is_empty() {
+        errors.push(CredentialError::EmptySsid);
+    }
+    if ssid.len() > MAX_SSID_LEN {
+        errors.push(CredentialError::SsidTooLong(ssid.to_string()));
+    }
+    if !psk.is_empty() && psk.len() < MIN_PSK_LEN {
+        errors.push(CredentialError::PskTooShort(ssid.to_string()));
+    }
+    if psk.len() > MAX_PSK_LEN {
+        errors.push(CredentialError::PskTooLong(ssid.to_string()));
+    }
+    errors
+}
+
+impl Display for CredentialError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CredentialError::EmptySsid => write!(f, "No access point name"),
+            CredentialError::SsidTooLong(ssid) => write!(f, "SSID {} is longer than {} bytes", ssid, MAX_SSID_LEN),
+            CredentialError::PskTooShort(ssid) => write!(f, "PSK for {} is shorter than {} bytes", ssid, MIN_PSK_LEN),
+            CredentialError::PskTooLong(ssid) => write!(f, "PSK for {} is longer than {} bytes", ssid, MAX_PSK_LEN),
+        }
+    }
+}
+
+impl Error for CredentialError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overlong_credentials_are_caught() {
+        let ssid = "a".repeat(MAX_SSID_LEN + 1);
+        let psk = "b".repeat(MAX_PSK_LEN + 1);
+        let errors = check_credentials(&ssid, &psk);
+        assert_eq!(errors.len(), 2, "{:?}", errors);
+        assert!(matches!(&errors[0], CredentialError::SsidTooLong(name) if *name == ssid));
+        assert!(matches!(&errors[1], CredentialError::PskTooLong(name) if *name == ssid));
+    }
+
+    #[test]
+    fn credentials_at_the_limits_pass() {
+        assert!(check_credentials(&"a".repeat(MAX_SSID_LEN), &"b".repeat(MAX_PSK_LEN)).is_empty());
+        assert!(check_credentials("lab", &"b".repeat(MIN_PSK_LEN)).is_empty());
+        assert!(check_credentials("lab", "").is_empty());
+    }
+
+    #[test]
+    fn short_psk_is_caught() {
+        let errors = check_credentials("lab", &"b".repeat(MIN_PSK_LEN - 1));
+        assert!(matches!(&errors[..], [CredentialError::PskTooShort(name)] if name == "lab"), "{:?}", errors);
+    }
+
+    #[test]
+    fn empty_ssid_is_caught() {
+        assert!(matches!(check_credentials("", "password")[..], [CredentialError::EmptySsid]));
+    }
+}
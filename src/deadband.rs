@@ -0,0 +1,44 @@
+use std::time::Duration;
+
+type Triple = (f32, f32, f32);
+
+// Leaves out samples that don't tell the collector anything new: one is only
+// kept once a sensor moved by at least its threshold on some axis since the
+// last kept sample, or once KEEPALIVE passed without one. Comparing against
+// the last kept sample rather than the one before means a slow drift still
+// shows up once it adds up to the threshold.
+pub struct Deadband {
+    thresholds: (f32, f32, f32),
+    last: Option<(Duration, Triple, Triple, Triple)>,
+}
+
+// The collector tells a still device from a dead one by this
+const KEEPALIVE: Duration = Duration::from_secs(1);
+
+impl Deadband {
+    // Thresholds for accelerometer, gyroscope and magnetometer in their units
+    pub fn new(acc: f32, gyro: f32, mag: f32) -> Self {
+        Self { thresholds: (acc, gyro, mag), last: None }
+    }
+
+    pub fn keep(&mut self, time: Duration, acc: Triple, gyro: Triple, mag: Triple) -> bool {
+        let keep = match self.last {
+            None => true,
+            Some((last_time, last_acc, last_gyro, last_mag)) => {
+                time.saturating_sub(last_time) >= KEEPALIVE
+                    || moved(acc, last_acc, self.thresholds.0)
+                    || moved(gyro, last_gyro, self.thresholds.1)
+                    || moved(mag, last_mag, self.thresholds.2)
+            },
+        };
+
+        if keep {
+            self.last = Some((time, acc, gyro, mag));
+        }
+        keep
+    }
+}
+
+fn moved((x, y, z): Triple, (lx, ly, lz): Triple, threshold: f32) -> bool {
+    (x - lx).abs() >= threshold || (y - ly).abs() >= threshold || (z - lz).abs() >= threshold
+}
@@ -0,0 +1,50 @@
+use std::borrow::Cow;
+
+// Request target for the upgrade: a path with an optional query, like
+// "/ingest?device=feather&fmt=pb". The leading '/' is added if missing and
+// repeated slashes in the path are merged, the query is sent as it is.
+pub fn normalize_endpoint(endpoint: &str) -> Cow<'_, str> {
+    let endpoint = endpoint.trim();
+    let (path, query) = match endpoint.find('?') {
+        Some(i) => endpoint.split_at(i),
+        None => (endpoint, ""),
+    };
+
+    if path.starts_with('/') && !path.contains("//") {
+        return Cow::Borrowed(endpoint);
+    }
+
+    let mut normalized = String::with_capacity(endpoint.len() + 1);
+    for segment in path.split('/').filter(|segment| !segment.is_empty()) {
+        normalized.push('/');
+        normalized.push_str(segment);
+    }
+    // Keep a trailing slash, servers may route "/ingest/" differently
+    if normalized.is_empty() || path.ends_with('/') {
+        normalized.push('/');
+    }
+    normalized.push_str(query);
+
+    Cow::Owned(normalized)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn endpoint_is_normalized() {
+        for (endpoint, expected) in [
+            ("/ingest", "/ingest"),
+            ("ingest", "/ingest"),
+            ("//ingest", "/ingest"),
+            ("/a//b/", "/a/b/"),
+            ("", "/"),
+            (" /ingest ", "/ingest"),
+            ("ingest?device=feather&fmt=pb", "/ingest?device=feather&fmt=pb"),
+            ("//ingest?next=//a", "/ingest?next=//a"),
+        ] {
+            assert_eq!(normalize_endpoint(endpoint), expected, "for {:?}", endpoint);
+        }
+    }
+}
@@ -0,0 +1,198 @@
+use anyhow::{bail, Result};
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use crate::backoff::Backoff;
+use crate::transport::{Message, ReadBuffer, Transport};
+
+// Copies every batch to further collectors, e.g. a live dashboard next to an
+// archive, without a proxy in between. The session stays with the primary
+// collector: its errors end the session as before, commands and replies only
+// go through it, and the others get the batches it took. Each of those keeps
+// a connection and backoff of its own, one that's down is retried while the
+// rest carries on and misses whatever was sent meanwhile. Reconnecting
+// happens on the networking thread, so at most one collector is tried per
+// poll() and the connect is expected to give up quickly.
+
+#[derive(Clone, Copy, Debug)]
+pub struct Endpoint {
+    pub host: &'static str,
+    pub port: u16,
+    pub path: &'static str,
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}{}", self.host, self.port, self.path)
+    }
+}
+
+// Comma-separated host:port/path, the path defaults to /
+pub fn parse_endpoints(list: &'static str) -> Result<Vec<Endpoint>> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let (authority, path) = match entry.find('/') {
+                Some(slash) => entry.split_at(slash),
+                None => (entry, "/"),
+            };
+            let Some((host, port)) = authority.rsplit_once(':') else {
+                bail!("Collector \"{}\" has no port (expected host:port/path)", entry);
+            };
+            let port = match port.parse() {
+                Ok(port) if port != 0 && !host.is_empty() => port,
+                _ => bail!("Invalid collector \"{}\" (expected host:port/path)", entry),
+            };
+            Ok(Endpoint { host, port, path })
+        })
+        .collect()
+}
+
+// Opens a session with a collector, hello included
+pub type Connect<'a> = Box<dyn FnMut(&Endpoint) -> Result<Box<dyn Transport + 'a>> + 'a>;
+
+struct Collector<'a> {
+    endpoint: Endpoint,
+    client: Option<Box<dyn Transport + 'a>>,
+    backoff: Backoff,
+    retry_at: Instant,
+}
+
+impl Collector<'_> {
+    fn disconnect(&mut self, e: &anyhow::Error) {
+        let delay = self.backoff.next_delay();
+        log::warn!("Lost collector {}, reconnecting in {:?}: {}", self.endpoint, delay, e);
+        self.client = None;
+        self.retry_at = Instant::now() + delay;
+    }
+}
+
+pub struct FanOut<'a> {
+    primary: Box<dyn Transport + 'a>,
+    collectors: Vec<Collector<'a>>,
+    connect: Connect<'a>,
+}
+
+impl<'a> FanOut<'a> {
+    // The endpoints are connected one per poll(), starting with the first
+    pub fn new(primary: Box<dyn Transport + 'a>, endpoints: Vec<Endpoint>, backoff: Backoff, connect: Connect<'a>) -> Self {
+        let now = Instant::now();
+        let collectors = endpoints.into_iter()
+            .map(|endpoint| Collector { endpoint, client: None, backoff: backoff.clone(), retry_at: now })
+            .collect();
+        Self { primary, collectors, connect }
+    }
+
+    // Notices dead connections and reconnects the one that's been due the
+    // longest, the others wait for the next call
+    fn maintain(&mut self) {
+        for collector in &mut self.collectors {
+            if collector.client.as_ref().is_some_and(|client| !client.is_connected()) {
+                collector.disconnect(&anyhow::anyhow!("Connection closed"));
+            }
+        }
+
+        let now = Instant::now();
+        let due = self.collectors.iter_mut()
+            .filter(|collector| collector.client.is_none() && collector.retry_at <= now)
+            .min_by_key(|collector| collector.retry_at);
+        if let Some(collector) = due {
+            match (self.connect)(&collector.endpoint) {
+                Ok(client) => {
+                    log::info!("Connected to collector {}", collector.endpoint);
+                    collector.backoff.reset();
+                    collector.client = Some(client);
+                },
+                Err(e) => {
+                    let delay = collector.backoff.next_delay();
+                    log::warn!("Failed to connect to collector {}, retrying in {:?}: {}", collector.endpoint, delay, e);
+                    collector.retry_at = Instant::now() + delay;
+                },
+            }
+        }
+    }
+}
+
+impl Transport for FanOut<'_> {
+    fn is_connected(&self) -> bool {
+        self.primary.is_connected()
+    }
+
+    fn reconnect(&mut self) -> Result<()> {
+        self.primary.reconnect()
+    }
+
+    // Batches have to fit every collector
+    fn max_payload_len(&self) -> usize {
+        self.collectors.iter()
+            .filter_map(|collector| collector.client.as_ref())
+            .map(|client| client.max_payload_len())
+            .fold(self.primary.max_payload_len(), usize::min)
+    }
+
+    fn send_interval(&self) -> Duration {
+        self.primary.send_interval()
+    }
+
+    // Only the primary's messages are handled
+    fn receives(&self) -> bool {
+        self.primary.receives()
+    }
+
+    fn send_text(&mut self, text: &str) -> Result<()> {
+        self.primary.send_text(text)
+    }
+
+    fn send_binary(&mut self, buf: &[u8]) -> Result<()> {
+        self.primary.send_binary(buf)
+    }
+
+    // A batch the primary didn't take is sent again later, the others only
+    // get it then so they don't see it twice
+    fn send_batch(&mut self, message: &[u8], text: bool) -> Result<()> {
+        self.primary.send_batch(message, text)?;
+        for collector in &mut self.collectors {
+            let Some(client) = collector.client.as_mut() else {
+                continue;
+            };
+            if let Err(e) = client.send_batch(message, text) {
+                collector.disconnect(&e);
+            }
+        }
+        Ok(())
+    }
+
+    // The others are polled for pings and to notice them closing, what they
+    // send is dropped
+    fn poll(&mut self) -> Result<Vec<Message>> {
+        self.maintain();
+        for collector in &mut self.collectors {
+            let Some(client) = collector.client.as_mut() else {
+                continue;
+            };
+            match client.poll() {
+                Ok(messages) if !messages.is_empty() => {
+                    log::debug!("Ignoring {} messages from collector {}", messages.len(), collector.endpoint);
+                },
+                Ok(_) => {},
+                Err(e) => collector.disconnect(&e),
+            }
+        }
+        self.primary.poll()
+    }
+
+    fn read_buffer(&self) -> Option<ReadBuffer> {
+        self.primary.read_buffer()
+    }
+
+    fn close(&mut self) -> Result<()> {
+        for collector in &mut self.collectors {
+            if let Some(mut client) = collector.client.take() {
+                _ = client.close()
+                    .inspect_err(|e| log::warn!("Failed to close connection to collector {}: {}", collector.endpoint, e));
+            }
+        }
+        self.primary.close()
+    }
+}
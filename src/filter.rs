@@ -0,0 +1,140 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
+type Triple = (f32, f32, f32);
+
+// Per-axis moving average over the last `window` readings. The output lags
+// the input by (window - 1) / 2 samples, which the sample timestamps don't
+// account for. A window of 1 passes readings through unchanged.
+pub struct MovingAverage {
+    window: usize,
+    history: VecDeque<Triple>,
+}
+
+impl MovingAverage {
+    pub fn new(window: usize) -> Self {
+        let window = window.max(1);
+        Self { window, history: VecDeque::with_capacity(window) }
+    }
+
+    pub fn apply(&mut self, reading: Triple) -> Triple {
+        if self.window == 1 {
+            return reading;
+        }
+
+        if self.history.len() == self.window {
+            self.history.pop_front();
+        }
+        self.history.push_back(reading);
+
+        // Summing the window again instead of keeping a running sum avoids
+        // accumulating float error over millions of samples
+        let n = self.history.len() as f32;
+        let (x, y, z) = self.history.iter()
+            .fold((0.0, 0.0, 0.0), |(x, y, z), (rx, ry, rz)| (x + rx, y + ry, z + rz));
+        (x / n, y / n, z / n)
+    }
+}
+
+// Per-axis first order high-pass, removes gravity and other slow changes
+// from the acceleration and keeps the vibration. Goes by the sample times
+// rather than a fixed rate, so it stays right when the rate changes. The
+// first reading only primes it and comes out as zero.
+pub struct HighPass {
+    // RC time constant for the cutoff frequency, 1 / (2 pi f)
+    rc: f32,
+    last: Option<(Duration, Triple, Triple)>,
+}
+
+impl HighPass {
+    pub fn new(cutoff_hz: f32) -> Self {
+        Self { rc: 1.0 / (2.0 * std::f32::consts::PI * cutoff_hz), last: None }
+    }
+
+    pub fn apply(&mut self, time: Duration, reading: Triple) -> Triple {
+        let output = match self.last {
+            Some((last_time, (ix, iy, iz), (ox, oy, oz))) => {
+                // A long gap, e.g. after an OTA pause, makes this close to
+                // zero and restarts the filter from the current reading
+                let dt = time.saturating_sub(last_time).as_secs_f32();
+                let a = self.rc / (self.rc + dt);
+                let (x, y, z) = reading;
+                (a * (ox + x - ix), a * (oy + y - iy), a * (oz + z - iz))
+            },
+            None => (0.0, 0.0, 0.0),
+        };
+        self.last = Some((time, reading, output));
+        output
+    }
+}
+
+// Flags an event while the magnitude of the (high-pass filtered)
+// acceleration is above the threshold. Only the start of an event counts,
+// a vibration that stays above it is one event.
+pub struct EventDetector {
+    threshold: f32,
+    active: bool,
+}
+
+impl EventDetector {
+    pub fn new(threshold: f32) -> Self {
+        Self { threshold, active: false }
+    }
+
+    // Whether an event started with this reading
+    pub fn update(&mut self, (x, y, z): Triple) -> bool {
+        let above = (x * x + y * y + z * z).sqrt() > self.threshold;
+        let started = above && !self.active;
+        self.active = above;
+        started
+    }
+}
+
+// Nothing was measured during a longer gap, e.g. an OTA or maintenance
+// pause. The lowest sample rate is 1 Hz.
+const MAX_INTEGRATION_GAP: Duration = Duration::from_secs(2);
+
+// Per-axis integral of the gyroscope's rates, degrees from dps. Goes by the
+// sample times like HighPass, and drifts with whatever bias calibration left.
+#[derive(Default)]
+pub struct Integrator {
+    last_time: Option<Duration>,
+    angle: Triple,
+}
+
+impl Integrator {
+    pub fn apply(&mut self, time: Duration, (x, y, z): Triple) -> Triple {
+        if let Some(last_time) = self.last_time {
+            let dt = time.saturating_sub(last_time);
+            if dt <= MAX_INTEGRATION_GAP {
+                let dt = dt.as_secs_f32();
+                let (ax, ay, az) = self.angle;
+                self.angle = (ax + x * dt, ay + y * dt, az + z * dt);
+            }
+        }
+        self.last_time = Some(time);
+        self.angle
+    }
+
+    pub fn zero(&mut self) {
+        self.angle = (0.0, 0.0, 0.0);
+    }
+}
+
+pub struct Filters {
+    pub acc: MovingAverage,
+    pub gyro: MovingAverage,
+    pub mag: MovingAverage,
+    // Applied to the acceleration before the moving average
+    pub acc_highpass: Option<HighPass>,
+}
+
+impl Filters {
+    pub fn apply(&mut self, time: Duration, (acc, gyro, mag): (Triple, Triple, Triple)) -> (Triple, Triple, Triple) {
+        let acc = match &mut self.acc_highpass {
+            Some(highpass) => highpass.apply(time, acc),
+            None => acc,
+        };
+        (self.acc.apply(acc), self.gyro.apply(gyro), self.mag.apply(mag))
+    }
+}
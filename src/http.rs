@@ -0,0 +1,104 @@
+use anyhow::{bail, Result};
+use esp_idf_svc::{
+    http::{
+        client::{Configuration, EspHttpConnection},
+        Method,
+    },
+    sys::esp_crt_bundle_attach,
+};
+use std::time::Duration;
+
+use crate::transport::{Message, Transport};
+
+// SensorData batches are concatenated protobuf messages as far as the
+// server is concerned, so anything up to this works in one request
+const MAX_PAYLOAD_LEN: usize = 16384;
+
+// Posts sensor data to `<url>` and text messages (identification and status)
+// to `<url>/status`, for networks where the WebSocket upgrade is blocked.
+// There's no receive path, commands only work over the other transports.
+pub struct HttpTransport {
+    url: String,
+    status_url: String,
+    device_id: String,
+    interval: Duration,
+}
+
+impl HttpTransport {
+    pub fn new(url: &str, device_id: &str, interval: Duration) -> Self {
+        Self {
+            url: url.to_string(),
+            status_url: format!("{}/status", url.trim_end_matches('/')),
+            device_id: device_id.to_string(),
+            interval,
+        }
+    }
+
+    fn post(&self, url: &str, content_type: &str, body: &[u8]) -> Result<()> {
+        let mut connection = EspHttpConnection::new(&Configuration {
+            crt_bundle_attach: Some(esp_crt_bundle_attach),
+            ..Default::default()
+        })?;
+
+        let content_length = body.len().to_string();
+        let headers = [
+            ("Content-Type", content_type),
+            ("Content-Length", content_length.as_str()),
+            ("X-Device-Id", self.device_id.as_str()),
+        ];
+        connection.initiate_request(Method::Post, url, &headers)?;
+
+        let mut written = 0;
+        while written < body.len() {
+            written += connection.write(&body[written..])?;
+        }
+
+        connection.initiate_response()?;
+        match connection.status() {
+            200..=299 => Ok(()),
+            status => bail!("POST to {} failed with HTTP status {}", url, status),
+        }
+    }
+}
+
+impl Transport for HttpTransport {
+    // Every request stands on its own
+    fn is_connected(&self) -> bool {
+        true
+    }
+
+    fn reconnect(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn max_payload_len(&self) -> usize {
+        MAX_PAYLOAD_LEN
+    }
+
+    fn send_interval(&self) -> Duration {
+        self.interval
+    }
+
+    fn send_text(&mut self, text: &str) -> Result<()> {
+        self.post(&self.status_url, "text/plain", text.as_bytes())
+    }
+
+    fn send_binary(&mut self, buf: &[u8]) -> Result<()> {
+        self.post(&self.url, "application/x-protobuf", buf)
+    }
+
+    fn send_batch(&mut self, message: &[u8], text: bool) -> Result<()> {
+        match text {
+            true => self.post(&self.url, "application/json", message),
+            false => self.send_binary(message),
+        }
+    }
+
+    fn poll(&mut self) -> Result<Vec<Message>> {
+        Ok(Vec::new())
+    }
+
+    fn receives(&self) -> bool {
+        false
+    }
+}
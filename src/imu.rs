@@ -0,0 +1,114 @@
+use anyhow::{anyhow, bail, Result};
+use lsm9ds1::{interface::{I2cInterface, Interface, SpiInterface}, LSM9DS1};
+use std::fmt::Debug;
+
+use crate::sensor::{ChipSelect, Registers, Sensors, SharedI2c, SharedSpi};
+
+pub type Triple = (f32, f32, f32);
+
+// Output register values of the sensors that were asked for
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RawReading {
+    pub accel: Option<[i16; 3]>,
+    pub gyro: Option<[i16; 3]>,
+    pub mag: Option<[i16; 3]>,
+}
+
+// What the sampling loop needs from an IMU. Readings come in g, dps and
+// gauss, the units SensorDataSample carries.
+pub trait ImuSensor {
+    fn read_accel(&mut self) -> Result<Triple>;
+
+    fn read_gyro(&mut self) -> Result<Triple>;
+
+    fn read_mag(&mut self) -> Result<Triple>;
+
+    // Die temperature in °C
+    fn read_temp(&mut self) -> Result<f32>;
+
+    // Rate the sensor converts at on its own, for sensors that run free
+    fn odr_hz(&self) -> Result<f32> {
+        bail!("Sensor has no fixed output data rate")
+    }
+
+    // Switches to the slowest supported rate that is at least hz, returns
+    // the rate it converts at now
+    fn set_odr_hz(&mut self, hz: f32) -> Result<f32> {
+        bail!("Sensor has no configurable output data rate: {} Hz", hz)
+    }
+
+    // Signal new data on the sensor's interrupt pin
+    fn enable_data_ready_interrupt(&mut self) -> Result<()> {
+        bail!("Sensor has no data ready interrupt")
+    }
+
+    // The output registers as they are, for checking the scaling
+    fn read_raw(&mut self, sensors: Sensors) -> Result<RawReading> {
+        bail!("Sensor has no raw register access: {:?}", sensors)
+    }
+}
+
+pub struct Lsm9ds1<I> {
+    sensor: LSM9DS1<I>,
+    registers: Registers,
+}
+
+impl<I: Interface> Lsm9ds1<I> {
+    pub fn new(sensor: LSM9DS1<I>, registers: Registers) -> Self {
+        Self { sensor, registers }
+    }
+
+    // For the features beyond ImuSensor, like the FIFO
+    pub fn registers(&self) -> &Registers {
+        &self.registers
+    }
+}
+
+// The sensor over either bus. The sampling thread is instantiated for both,
+// so neither pays for dynamic dispatch per reading.
+pub enum AnyLsm9ds1 {
+    I2c(Lsm9ds1<I2cInterface<SharedI2c>>),
+    Spi(Lsm9ds1<SpiInterface<SharedSpi, ChipSelect, ChipSelect>>),
+}
+
+impl<I> ImuSensor for Lsm9ds1<I>
+where
+    I: Interface,
+    I::Error: Debug,
+{
+    fn read_accel(&mut self) -> Result<Triple> {
+        self.sensor.read_accel().map_err(|e| anyhow!("Failed to read accelerometer: {:?}", e))
+    }
+
+    fn read_gyro(&mut self) -> Result<Triple> {
+        self.sensor.read_gyro().map_err(|e| anyhow!("Failed to read gyroscope: {:?}", e))
+    }
+
+    fn read_mag(&mut self) -> Result<Triple> {
+        self.sensor.read_mag().map_err(|e| anyhow!("Failed to read magnetometer: {:?}", e))
+    }
+
+    fn read_temp(&mut self) -> Result<f32> {
+        self.registers.read_temperature()
+    }
+
+    fn odr_hz(&self) -> Result<f32> {
+        self.registers.gyro_odr_hz()
+    }
+
+    fn set_odr_hz(&mut self, hz: f32) -> Result<f32> {
+        self.registers.set_gyro_odr_hz(hz)
+    }
+
+    fn enable_data_ready_interrupt(&mut self) -> Result<()> {
+        self.registers.enable_data_ready_interrupt()
+    }
+
+    fn read_raw(&mut self, sensors: Sensors) -> Result<RawReading> {
+        Ok(RawReading {
+            accel: sensors.accel.then(|| self.registers.read_raw_accel()).transpose()?,
+            gyro: sensors.gyro.then(|| self.registers.read_raw_gyro()).transpose()?,
+            mag: sensors.mag.then(|| self.registers.read_raw_mag()).transpose()?,
+        })
+    }
+}
@@ -0,0 +1,60 @@
+use serde::Deserialize;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::telemetry;
+
+// End-to-end check of a connection: the linktest command sends a marker
+// with a random nonce, and the test passes once the collector, or an echo
+// server, sends it back. Either way the result goes out as
+// telemetry::LinktestResult, so the collector and the log both see it.
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+struct Pending {
+    nonce: u32,
+    sent_at: Instant,
+}
+
+// Only one test at a time, a new one replaces the one still waiting
+static PENDING: Mutex<Option<Pending>> = Mutex::new(None);
+
+// What bounced back, any other fields are ignored
+#[derive(Deserialize)]
+struct Echo {
+    #[serde(rename = "type")]
+    kind: String,
+    nonce: u32,
+}
+
+// Returns the marker to send
+pub fn start() -> serde_json::Result<String> {
+    let nonce = rand::random();
+    if let Ok(mut pending) = PENDING.lock() {
+        *pending = Some(Pending { nonce, sent_at: Instant::now() });
+    }
+    log::info!("Link test {} started", nonce);
+    telemetry::Linktest { nonce }.to_json()
+}
+
+// The result if text is the marker of the test that's waiting
+pub fn answer(text: &str) -> Option<telemetry::LinktestResult> {
+    let echo = serde_json::from_str::<Echo>(text).ok().filter(|echo| echo.kind == "linktest")?;
+    let mut pending = PENDING.lock().ok()?;
+    let sent_at = pending.as_ref().filter(|pending| pending.nonce == echo.nonce)?.sent_at;
+    *pending = None;
+
+    let rtt = sent_at.elapsed();
+    log::info!("Link test {} passed, round trip {:?}", echo.nonce, rtt);
+    Some(telemetry::LinktestResult { nonce: echo.nonce, ok: true, rtt_ms: Some(rtt.as_millis() as u32) })
+}
+
+// The failed result once the marker didn't come back in time
+pub fn expired() -> Option<telemetry::LinktestResult> {
+    let mut pending = PENDING.lock().ok()?;
+    let nonce = pending.as_ref().filter(|pending| pending.sent_at.elapsed() >= TIMEOUT)?.nonce;
+    *pending = None;
+
+    log::warn!("Link test {} failed, nothing came back within {:?}", nonce, TIMEOUT);
+    Some(telemetry::LinktestResult { nonce, ok: false, rtt_ms: None })
+}
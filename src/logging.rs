@@ -0,0 +1,57 @@
+use esp_idf_svc::log::EspLogger;
+use log::{LevelFilter, Log, Metadata, Record};
+use std::sync::OnceLock;
+
+// EspLogger with every message prefixed by the device ID and the name of the
+// thread that logged it, e.g. "[hecate-a0b1c2d3e4f5/networking thread]". With
+// a fleet logging to one place the lines can be told apart, and so can the
+// interleaved output of the threads. The module is in the target EspLogger
+// already prints.
+
+static DEVICE_ID: OnceLock<String> = OnceLock::new();
+static LOGGER: PrefixedLogger = PrefixedLogger;
+
+struct PrefixedLogger;
+
+impl Log for PrefixedLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        EspLogger.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let thread = std::thread::current();
+        let thread = thread.name().unwrap_or("main");
+        let device_id = DEVICE_ID.get().map(String::as_str).unwrap_or("-");
+
+        // format_args!() only lives until the end of the statement, hence
+        // building and passing on the record in one go
+        EspLogger.log(&Record::builder()
+            .args(format_args!("[{}/{}] {}", device_id, thread, record.args()))
+            .metadata(record.metadata().clone())
+            .module_path(record.module_path())
+            .file(record.file())
+            .line(record.line())
+            .build());
+    }
+
+    fn flush(&self) {}
+}
+
+// Replaces EspLogger::initialize_default(). The firmware is built with debug
+// as the maximum level, the per-target levels are still EspLogger's.
+pub fn init() {
+    if let Err(e) = log::set_logger(&LOGGER) {
+        println!("Failed to install logger: {}", e);
+        return;
+    }
+    log::set_max_level(LevelFilter::Debug);
+}
+
+// Lines logged before this is called show "-" instead of the ID
+pub fn set_device_id(device_id: &str) {
+    let _ = DEVICE_ID.set(device_id.to_string());
+}
@@ -1,22 +1,64 @@
-use anyhow::Result;
+use anyhow::{anyhow, bail, Result};
 use esp_idf_svc::{
     eventloop::EspSystemEventLoop,
     hal::{
         self,
-        gpio::{PinDriver, Output, OutputPin},
-        i2c::I2cDriver,
+        gpio::{AnyOutputPin, PinDriver, Output, OutputPin},
+        i2c::{I2cConfig, I2cDriver},
+        spi::{self, SpiDeviceDriver, SpiDriver, SpiDriverConfig},
+        task::thread::ThreadSpawnConfiguration,
+        units::KiloHertz,
     },
     nvs::EspDefaultNvsPartition,
+    sntp::{EspSntp, SntpConf, SyncStatus},
+    sys::{self, esp},
     wifi::EspWifi,
     timer::EspTimerService,
 };
-use lsm9ds1::{LSM9DS1Init, LSM9DS1};
-use std::time::Duration;
-use std::sync::{Arc, Mutex};
+use lsm9ds1::{accel, gyro, interface::{I2cInterface, Interface, SpiInterface}, mag, LSM9DS1Init, LSM9DS1};
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock, TryLockError};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 use hecate_protobuf as proto;
-use proto::{Message, SensorDataSample};
+use proto::SensorDataSample;
 use ringbuffer::{RingBuffer, AllocRingBuffer};
+use imu::{ImuSensor, Triple};
 
+mod backoff;
+mod batch;
+mod battery;
+mod board;
+mod calibrate;
+mod clock;
+mod command;
+mod credentials;
+mod deadband;
+mod endpoint;
+mod fanout;
+mod filter;
+mod http;
+mod imu;
+mod linktest;
+mod logging;
+mod neopixel;
+mod mdns;
+mod mqtt;
+mod ota;
+mod power;
+mod provision;
+mod queue;
+mod sensor;
+mod settings;
+mod shutdown;
+mod spill;
+mod status;
+mod status_page;
+mod telemetry;
+mod transport;
+mod udp;
+mod watchdog;
 mod wifi;
 mod ws;
 
@@ -26,174 +68,3519 @@ struct Config {
     wifi_ssid: &'static str,
     #[default("BiBiBiBiBi")]
     wifi_psk: &'static str,
+    #[default("wpa2")]
+    wifi_auth_method: &'static str,
+    // WPA2-Enterprise for wifi_ssid instead of wifi_psk, when a username is
+    // set. The identity is the outer one sent before the tunnel is up, empty
+    // to send the username. The CA certificate is PEM, empty to accept any
+    // RADIUS server.
+    #[default("")]
+    wifi_eap_identity: &'static str,
+    #[default("")]
+    wifi_eap_username: &'static str,
+    #[default("")]
+    wifi_eap_password: &'static str,
+    #[default("")]
+    wifi_eap_ca_cert: &'static str,
+    // Tried after wifi_ssid, as "ssid:psk,ssid:psk"
+    #[default("")]
+    wifi_fallback_networks: &'static str,
+    // Per network, for association and getting an address together
+    #[default(30)]
+    wifi_connect_timeout_s: u32,
+    // Regulatory domain as two letter country code, e.g. "DE". The default
+    // "01" is worldwide, channels 1-11 at the lowest common TX power limit.
+    // Empty keeps whatever the ESP-IDF build defaults to.
+    #[default("01")]
+    wifi_country: &'static str,
+    // After this many failed rounds of connection attempts in a row, open a
+    // setup network to enter SSID and PSK, see README.md. 0 turns it off.
+    #[default(0)]
+    provisioning_after_rounds: u32,
+    // WPA2 passphrase of the setup network, required with provisioning on.
+    // Anyone who joins it can point the unit at a network of their own.
+    #[default("")]
+    provisioning_psk: &'static str,
+    // Without a submission the known networks are tried again after this
+    #[default(600)]
+    provisioning_timeout_s: u32,
+    #[default("")]
+    static_ip: &'static str,
+    #[default("")]
+    static_gateway: &'static str,
+    #[default("")]
+    static_netmask: &'static str,
+    // Empty to not sync the clock, samples then only have relative times
+    #[default("pool.ntp.org")]
+    ntp_server: &'static str,
+    // Identifies this device to the collector, empty to derive it from the
+    // WiFi MAC address
+    #[default("")]
+    device_id: &'static str,
+    // "ws", "mqtt" or "udp"
+    #[default("ws")]
+    transport: &'static str,
+    #[default("mqtt://broker.local:1883")]
+    mqtt_url: &'static str,
+    // Sensor data is published here, status to <topic>/status, commands
+    // are taken from <topic>/command
+    #[default("hecate/sensor")]
+    mqtt_topic: &'static str,
+    // Every batch goes out as one datagram, nothing is resent
+    #[default("")]
+    udp_host: &'static str,
+    #[default(8001)]
+    udp_port: u16,
+    // Used when the WebSocket handshake keeps failing (e.g. a proxy blocks
+    // the upgrade), empty to disable. Sensor data is POSTed here once per
+    // interval, so batch_size has to cover the samples of one interval.
+    #[default("")]
+    http_fallback_url: &'static str,
+    #[default(1000)]
+    http_post_interval_ms: u32,
+    #[default(3)]
+    ws_handshake_attempts: u32,
+    // Delay between reconnection attempts: starts at the minimum, doubles
+    // up to the maximum, and is cut by up to jitter_pct at random, but never
+    // below the minimum
+    #[default(1000)]
+    reconnect_min_delay_ms: u32,
+    #[default(60000)]
+    reconnect_max_delay_ms: u32,
+    #[default(50)]
+    reconnect_jitter_pct: u32,
+    // How often to try upgrading back to the WebSocket from the fallback
+    #[default(300)]
+    ws_upgrade_interval_s: u32,
     #[default("echo.websocket.org")]
     ws_host: &'static str,
     #[default(8000)]
     ws_port: u16,
+    // Path and optional query of the upgrade request, e.g.
+    // "/ingest?device=feather&fmt=pb". A missing leading '/' is added.
     #[default("/")]
     ws_endpoint: &'static str,
+    // Bearer token for the upgrade request, empty to not authenticate
+    #[default("")]
+    ws_token: &'static str,
+    // Requested via Sec-WebSocket-Protocol, empty for none
+    #[default("")]
+    ws_subprotocol: &'static str,
+    #[default(false)]
+    ws_tls: bool,
+    #[default(false)]
+    ws_tls_insecure: bool,
+    // TCP connect per address, and the TLS and upgrade handshake after it
+    #[default(5000)]
+    ws_connect_timeout_ms: u32,
+    #[default(5000)]
+    ws_handshake_timeout_ms: u32,
+    #[default(5000)]
+    ws_read_timeout_ms: u32,
+    #[default(5000)]
+    ws_write_timeout_ms: u32,
+    // TCP keepalive: seconds without traffic before the first probe, 0 to
+    // disable, then a probe every interval until count went unanswered
+    #[default(30)]
+    ws_keepalive_idle_s: u32,
+    #[default(5)]
+    ws_keepalive_interval_s: u32,
+    #[default(3)]
+    ws_keepalive_count: u32,
+    // Further WebSocket collectors that get a copy of every batch, comma
+    // separated host:port/path, e.g. "archive.local:8000/ingest". They use
+    // the TLS settings of the main one, see README.md.
+    #[default("")]
+    extra_collectors: &'static str,
+    // Bearer token for the extra collectors, empty to send none. ws_token
+    // only ever goes to the main collector.
+    #[default("")]
+    extra_collectors_token: &'static str,
+    // Bounds both the TCP connect and each handshake step with an extra
+    // collector, sending waits for those while one is reconnected
+    #[default(1000)]
+    extra_collector_timeout_ms: u32,
+    // The ota command has to carry this token, empty to refuse OTA updates
+    #[default("")]
+    ota_token: &'static str,
+    // Comma separated https:// URL prefixes images may be downloaded from,
+    // e.g. "https://firmware.example.com/hecate/"
+    #[default("")]
+    ota_url_prefixes: &'static str,
+    // Samples retained while the server is unreachable, each one takes
+    // size_of::<SensorDataSample>() bytes of heap
+    #[default(128)]
+    ringbuffer_capacity: u32,
+    // Newest samples in the ringbuffer that are logged when a worker thread
+    // dies or the firmware panics, 0 for none
+    #[default(20)]
+    post_mortem_samples: u32,
+    // The running batch total is written to NVS every this many batches, 0
+    // to not keep it across reboots. Lower means a smaller jump after a
+    // reboot and more flash writes, see README.md.
+    #[default(1000)]
+    batch_total_save_interval: u32,
+    // What to give up when the ringbuffer is full: "overwrite_oldest" keeps
+    // the most recent data, "drop_newest" the start of the gap
+    #[default("overwrite_oldest")]
+    overflow_policy: &'static str,
+    // Maximum samples per SensorData message, smaller batches are sent if
+    // they wouldn't fit the WebSocket frame
+    #[default(100)]
+    batch_size: u32,
+    // Longest time between two SensorData messages, a full batch_size of
+    // samples goes out right away. Can be changed with the send_interval
+    // command.
+    #[default(100)]
+    send_interval_ms: u32,
+    // Collect this long between sends, 0 to send by batch_size and
+    // send_interval_ms. What piled up goes out in as few frames as fit the
+    // transport, regardless of batch_size, so ringbuffer_capacity has to
+    // hold one window of samples.
+    #[default(0)]
+    coalesce_window_ms: u32,
+    // Encoded messages waiting for the transport, and what happens when
+    // that many are queued: "block" until the oldest went out, or
+    // "drop_oldest"/"drop_newest" to lose a message instead
+    #[default(4)]
+    send_queue_len: u32,
+    #[default("block")]
+    send_queue_policy: &'static str,
+    // Prefix every SensorData message with its length and CRC32, for
+    // collectors that check them. Announced in the hello message.
+    #[default(false)]
+    batch_checksum: bool,
+    // Keep a rolling CRC over the seq and frame CRC of every batch and send
+    // it before closing the connection, for collectors that audit sessions
+    #[default(false)]
+    session_digest: bool,
+    // "json" sends SensorData as JSON text frames with the protobuf field
+    // names instead of protobuf, for debugging with websocat and jq. Larger
+    // and slower, and batch_checksum doesn't apply.
+    #[default("protobuf")]
+    sample_format: &'static str,
+    // Pin assignments of the Feather V2, change them for other boards.
+    // power_gpio switches the regulator of the QWIIC connector and the
+    // NeoPixel. GPIO35 is taken by the battery voltage divider.
+    #[default(2)]
+    power_gpio: i32,
+    // How long the sensor gets after power-up before it's talked to, at
+    // most 1000. One that doesn't answer WHO_AM_I by then is polled for a
+    // while longer before initialization fails.
+    #[default(20)]
+    power_up_delay_ms: u32,
+    #[default(0)]
+    neopixel_gpio: i32,
+    // Scales all status colors, 0-255. 0 is stealth mode: the NeoPixel stays
+    // dark, and with the sensor on SPI power_gpio is left off altogether.
+    // Can be overridden in NVS.
+    #[default(32)]
+    neopixel_brightness: u8,
+    // While streaming, tint the NeoPixel from green to yellow as the link
+    // quality drops, instead of the usual cyan
+    #[default(false)]
+    neopixel_link_quality: bool,
+    #[default(13)]
+    led_gpio: i32,
+    #[default(22)]
+    i2c_sda_gpio: i32,
+    #[default(20)]
+    i2c_scl_gpio: i32,
+    #[default(5)]
+    imu_spi_sclk_gpio: i32,
+    #[default(19)]
+    imu_spi_mosi_gpio: i32,
+    #[default(21)]
+    imu_spi_miso_gpio: i32,
+    #[default("auto")]
+    imu_ag_address: &'static str,
+    #[default("auto")]
+    imu_mag_address: &'static str,
+    // "i2c" for the QWIIC connector, or "spi" on the imu_spi_*_gpio pins
+    // with the chip selects below
+    #[default("i2c")]
+    imu_interface: &'static str,
+    // Sensor bus clock. The LSM9DS1 does up to 400 kHz, long QWIIC cables
+    // may only work at 100 kHz.
+    #[default(100)]
+    i2c_frequency_khz: u32,
+    #[default(-1)]
+    imu_spi_ag_cs_gpio: i32,
+    #[default(-1)]
+    imu_spi_mag_cs_gpio: i32,
+    // Up to 10 MHz
+    #[default(1000)]
+    imu_spi_frequency_khz: u32,
+    // Full-scale ranges in ±g, ±dps and ±gauss. A wider range clips later
+    // but costs resolution, and changes the numeric scale of the values in
+    // SensorDataSample, so calibration has to be redone after changing them.
+    #[default(2)]
+    accel_range_g: u8,
+    #[default(245)]
+    gyro_range_dps: u16,
+    #[default(4)]
+    mag_range_gauss: u8,
+    // Rates the dies convert at. For polling they should be at or a little
+    // above the poll rate: below it readings repeat, far above it the
+    // signal aliases. With the gyroscope on, ag_odr_hz is one of 14.9, 59.5,
+    // 119, 238, 476 or 952, without it 10, 50, 119, 238, 476 or 952. The
+    // magnetometer takes 0.625 to 80 in steps of two. With the FIFO or
+    // imu_drdy_gpio, samples come at ag_odr_hz until a rate command.
+    #[default(119.0)]
+    ag_odr_hz: f32,
+    #[default(80.0)]
+    mag_odr_hz: f32,
+    // Disabled sensors stay powered down and aren't read, their fields in
+    // SensorDataSample are zero. The FIFO and imu_drdy_gpio need the
+    // gyroscope, it sets the pace for both.
+    #[default(true)]
+    enable_accel: bool,
+    #[default(true)]
+    enable_gyro: bool,
+    #[default(true)]
+    enable_mag: bool,
+    // Polling only: read the magnetometer every nth sample and repeat the
+    // last reading in between. It converts at 80 Hz at most.
+    #[default(1)]
+    mag_read_divisor: u32,
+    // Polling only: timestamp right after the accelerometer read instead of
+    // after all three, closer to its sample but ahead of the other two
+    #[default(false)]
+    timestamp_on_accel_read: bool,
+    // "poll" or "fifo"
+    #[default("poll")]
+    sampling_mode: &'static str,
+    // Polling only: also report the sensors' output register values, to
+    // check the driver's scaling against the datasheet. Can be switched with
+    // the raw command.
+    #[default(false)]
+    raw_diagnostics: bool,
+    #[default(10)]
+    sample_interval_ms: u32,
+    // Polling only: while the ringbuffer is filled beyond the high-water
+    // mark, keep doubling the interval up to the maximum, and halve it again
+    // once it drained below the low-water mark
+    #[default(false)]
+    adaptive_sampling: bool,
+    // Warn when the achieved sample rate stays this far below the target
+    #[default(10)]
+    sample_rate_tolerance_pct: u32,
+    // Polling only: intervals between sample timestamps the jitter is
+    // measured over, and the standard deviation past which it's warned about
+    #[default(1000)]
+    jitter_window_samples: u32,
+    #[default(1000)]
+    jitter_warn_us: u32,
+    #[default(100)]
+    max_sample_interval_ms: u32,
+    #[default(75)]
+    buffer_high_water_pct: u32,
+    #[default(25)]
+    buffer_low_water_pct: u32,
+    // GPIO wired to the accel/gyro INT1 pin, -1 to poll on the interval
+    // above instead
+    #[default(-1)]
+    imu_drdy_gpio: i32,
+    // Light sleep between data ready interrupts, needs imu_drdy_gpio. The
+    // WiFi only wakes for DTIM beacons then, which delays ping/pong and
+    // other incoming frames and can trip server keepalive timeouts.
+    #[default(false)]
+    light_sleep: bool,
+    // Deep sleep between bursts of activity: after motion_window_s awake,
+    // only the accelerometer keeps watching, and wakes the board through
+    // motion_wake_gpio (an RTC GPIO wired to the accel/gyro INT1 pin) once
+    // any axis changes by more than motion_threshold_mg
+    #[default(false)]
+    wake_on_motion: bool,
+    #[default(-1)]
+    motion_wake_gpio: i32,
+    #[default(100)]
+    motion_threshold_mg: u32,
+    #[default(60)]
+    motion_window_s: u32,
+    // FreeRTOS priorities of the worker threads. Sampling goes first so
+    // sample timing doesn't suffer from sends, both stay below the WiFi
+    // driver and lwIP. The others run at BACKGROUND_THREAD_PRIORITY.
+    #[default(10)]
+    sampling_priority: u8,
+    #[default(5)]
+    networking_priority: u8,
+    // Stack sizes in bytes, 0 for ESP-IDF's default
+    // (CONFIG_PTHREAD_TASK_STACK_SIZE_DEFAULT). The diagnostics show how
+    // much of them is left.
+    #[default(0)]
+    sampling_stack_size: u32,
+    #[default(16384)]
+    networking_stack_size: u32,
+    // The board resets if the sampling or networking thread stalls this long
+    #[default(30)]
+    watchdog_timeout_s: u32,
+    // Below this the indicator shows a low battery pattern, 0 to disable
+    #[default(3500)]
+    battery_low_mv: u32,
+    // Status messages go out this often, also as heartbeat while no sensor
+    // data is sent
+    #[default(1000)]
+    status_interval_ms: u32,
+    // Adds minimum free heap and stack high-water marks to the status
+    // message, they are logged every DIAGNOSTICS_INTERVAL either way
+    #[default(false)]
+    status_diagnostics: bool,
+    // Serve the device status as JSON at http://<device-ip>/status. Costs a
+    // socket and a thread, so it's off by default.
+    #[default(false)]
+    status_page: bool,
+    #[default(80)]
+    status_page_port: u16,
+    // Keep samples the ringbuffer can't hold on flash while offline, see
+    // README.md
+    #[default(false)]
+    spill_to_flash: bool,
+    #[default(524288)]
+    spill_max_bytes: u32,
+    // Keep replayed segments on flash until the collector acknowledges them,
+    // see README.md. Unacknowledged ones are replayed again next session.
+    #[default(false)]
+    spill_require_ack: bool,
+    // Store and forward: keep the radio off and only connect every this
+    // many minutes to send everything sampled since, 0 to stream
+    // continuously. Needs spill_to_flash for anything beyond the
+    // ringbuffer, see README.md.
+    #[default(0)]
+    burst_interval_min: u32,
+    // Calibration as "x,y,z", applied as (raw - offset) * scale
+    #[default("")]
+    accel_offset: &'static str,
+    #[default("")]
+    accel_scale: &'static str,
+    #[default("")]
+    gyro_offset: &'static str,
+    #[default("")]
+    gyro_scale: &'static str,
+    // Run the sensors' built-in self-test on every initialization, it adds
+    // about half a second. A failing sensor is treated like one that
+    // doesn't answer, and eventually shows the sensor error pattern.
+    #[default(false)]
+    sensor_self_test: bool,
+    // Measure the gyroscope offset at boot instead of using gyro_offset. The
+    // board has to lie still for about gyro_zero_samples * 10 ms, if the
+    // readings spread more than gyro_zero_max_stddev_dps it's skipped.
+    #[default(false)]
+    gyro_auto_zero: bool,
+    #[default(200)]
+    gyro_zero_samples: u32,
+    #[default(1.0)]
+    gyro_zero_max_stddev_dps: f32,
+    #[default("")]
+    mag_offset: &'static str,
+    #[default("")]
+    mag_scale: &'static str,
+    // Run the magnetometer hard-iron calibration for this long after boot,
+    // 0 to only run it on a calibrate_mag command. The result is stored in
+    // NVS and replaces mag_offset from then on.
+    #[default(0)]
+    mag_calibrate_on_boot_s: u32,
+    // Moving average window per sensor in samples, 1 to not filter. The
+    // filtered values lag their timestamps by (window - 1) / 2 samples.
+    #[default(1)]
+    accel_filter_window: u32,
+    #[default(1)]
+    gyro_filter_window: u32,
+    #[default(1)]
+    mag_filter_window: u32,
+    // High-pass cutoff for the acceleration in mHz, e.g. 500 for 0.5 Hz, to
+    // stream vibration with gravity removed. 0 streams raw acceleration.
+    #[default(0)]
+    accel_highpass_mhz: u32,
+    // An event is counted whenever the magnitude of the filtered
+    // acceleration rises above this, 0 to not detect events. Needs
+    // accel_highpass_mhz, the raw magnitude includes gravity.
+    #[default(0)]
+    accel_event_threshold_mg: u32,
+    // With burst_interval_min, send a burst right away on an event
+    #[default(false)]
+    burst_on_event: bool,
+    // Leave out samples while the unit is still: a sample is only kept once
+    // a sensor moved by its threshold on some axis since the last one that
+    // was, and at least once a second either way. Thresholds in mg, mdps
+    // and mgauss, after calibration and filtering.
+    #[default(false)]
+    deadband: bool,
+    #[default(20)]
+    accel_deadband_mg: u32,
+    #[default(500)]
+    gyro_deadband_mdps: u32,
+    #[default(10)]
+    mag_deadband_mgauss: u32,
+    // Integrate the gyroscope into an angle per axis, reported in status
+    // messages and zeroed with the zero_angle command. Drifts by the
+    // remaining bias, so gyro_auto_zero or gyro_offset matter.
+    #[default(false)]
+    gyro_integrate: bool,
 }
 
+impl Config {
+    // Over-long credentials would only fail once a connect converts them,
+    // see credentials::check_credentials()
+    fn credential_errors(&self) -> impl Iterator<Item = credentials::CredentialError> {
+        let networks = wifi::parse_networks(self.wifi_fallback_networks, wifi::parse_auth_method(self.wifi_auth_method))
+            .unwrap_or_default();
+        std::iter::once((self.wifi_ssid, self.wifi_psk))
+            .chain(networks.into_iter().map(|network| (network.ssid, network.psk)))
+            .flat_map(|(ssid, psk)| credentials::check_credentials(ssid, psk))
+    }
+
+    // Catches what would otherwise only fail deep inside a connect with an
+    // error that doesn't point at the setting. The collector is checked as
+    // in settings, which NVS may have changed from the compiled values.
+    fn validate(&self, settings: &settings::Settings) -> Result<()> {
+        let mut problems = Vec::new();
+
+        if let Err(e) = wifi::parse_networks(self.wifi_fallback_networks, wifi::parse_auth_method(self.wifi_auth_method)) {
+            problems.push(e.to_string());
+        }
+        problems.extend(self.credential_errors().map(|e| e.to_string()));
+        if let Some(enterprise) = wifi_enterprise() {
+            problems.extend(enterprise.check());
+        }
+        if !self.wifi_country.is_empty() && !(self.wifi_country.len() == 2 && self.wifi_country.bytes().all(|b| b.is_ascii_alphanumeric())) {
+            problems.push(format!("wifi_country \"{}\" is not a two letter country code", self.wifi_country));
+        }
+
+        match self.transport {
+            "ws" => {
+                if settings.ws_host.is_empty() {
+                    problems.push("ws_host is empty".to_string());
+                }
+                if settings.ws_port == 0 {
+                    problems.push("ws_port is 0".to_string());
+                }
+                if settings.ws_endpoint.trim().contains(char::is_whitespace) {
+                    problems.push(format!("ws_endpoint \"{}\" contains whitespace", settings.ws_endpoint.trim()));
+                }
+                if settings.ws_token.contains(|c: char| c.is_whitespace() || c.is_control()) {
+                    problems.push("ws_token contains whitespace".to_string());
+                }
+                if self.ws_connect_timeout_ms == 0 || self.ws_handshake_timeout_ms == 0 {
+                    problems.push("ws_connect_timeout_ms and ws_handshake_timeout_ms must be at least 1".to_string());
+                }
+                if self.ws_keepalive_idle_s > 0 && (self.ws_keepalive_interval_s == 0 || self.ws_keepalive_count == 0) {
+                    problems.push("ws_keepalive_interval_s and ws_keepalive_count must be at least 1".to_string());
+                }
+            },
+            "mqtt" => {
+                if self.mqtt_url.is_empty() {
+                    problems.push("mqtt_url is empty".to_string());
+                }
+            },
+            "udp" => {
+                if self.udp_host.is_empty() {
+                    problems.push("udp_host is empty".to_string());
+                }
+                if self.udp_port == 0 {
+                    problems.push("udp_port is 0".to_string());
+                }
+                if self.spill_require_ack {
+                    problems.push("spill_require_ack needs a transport that receives, UDP only sends".to_string());
+                }
+            },
+            other => problems.push(format!("Invalid transport \"{}\" (expected ws, mqtt or udp)", other)),
+        }
+
+        if self.ws_tls_insecure && !cfg!(esp_idf_esp_tls_insecure) {
+            problems.push("ws_tls_insecure needs a build with sdkconfig.insecure, see README.md".to_string());
+        }
+        match fanout::parse_endpoints(self.extra_collectors) {
+            Ok(endpoints) if !endpoints.is_empty() && self.extra_collector_timeout_ms == 0 => {
+                problems.push("extra_collector_timeout_ms must be at least 1".to_string());
+            },
+            Ok(_) => {},
+            Err(e) => problems.push(e.to_string()),
+        }
+        if !self.ota_token.is_empty() {
+            if let Err(e) = ota::parse_url_prefixes(self.ota_url_prefixes) {
+                problems.push(e.to_string());
+            }
+        }
+        if let Err(e) = OverflowPolicy::parse(self.overflow_policy) {
+            problems.push(e.to_string());
+        }
+        if let Err(e) = queue::FullPolicy::parse(self.send_queue_policy) {
+            problems.push(e.to_string());
+        }
+        for (name, value) in [
+            ("send_queue_len", self.send_queue_len),
+            ("ringbuffer_capacity", self.ringbuffer_capacity),
+            ("batch_size", self.batch_size),
+            ("sample_interval_ms", self.sample_interval_ms),
+            ("gyro_zero_samples", self.gyro_zero_samples),
+            ("wifi_connect_timeout_s", self.wifi_connect_timeout_s),
+            ("watchdog_timeout_s", self.watchdog_timeout_s),
+        ] {
+            if value == 0 {
+                problems.push(format!("{} must be at least 1", name));
+            }
+        }
+        // Longer gaps between samples would show as a stall
+        let intervals = [
+            Some(("sample_interval_ms", self.sample_interval_ms)),
+            self.adaptive_sampling.then_some(("max_sample_interval_ms", self.max_sample_interval_ms)),
+        ];
+        for (name, value) in intervals.into_iter().flatten() {
+            if u128::from(value) >= status::STALL_TIMEOUT.as_millis() {
+                problems.push(format!("{} must be below {} ms", name, status::STALL_TIMEOUT.as_millis()));
+            }
+        }
+        if let Err(e) = sensor::ag_odr(self.ag_odr_hz, self.enable_gyro) {
+            problems.push(e.to_string());
+        }
+        if let Err(e) = sensor::mag_odr(self.mag_odr_hz) {
+            problems.push(e.to_string());
+        }
+        // Otherwise only found while bringing up the sensor, which retries
+        // it as if the sensor didn't answer
+        let ranges = [
+            sensor::accel_scale(self.accel_range_g).err(),
+            sensor::gyro_scale(self.gyro_range_dps).err(),
+            sensor::mag_scale(self.mag_range_gauss).err(),
+        ];
+        problems.extend(ranges.into_iter().flatten().map(|e| e.to_string()));
+        for (name, offset, scale) in [
+            ("accel", self.accel_offset, self.accel_scale),
+            ("gyro", self.gyro_offset, self.gyro_scale),
+            ("mag", self.mag_offset, self.mag_scale),
+        ] {
+            if let Err(e) = sensor::AxisCalibration::parse(offset, scale) {
+                problems.push(format!("{}_offset or {}_scale: {}", name, name, e));
+            }
+        }
+        match batch::Encoding::parse(self.sample_format) {
+            Ok(batch::Encoding::Json) if self.batch_checksum => {
+                problems.push("batch_checksum only applies to sample_format \"protobuf\"".to_string());
+            },
+            Ok(_) => (),
+            Err(e) => problems.push(e.to_string()),
+        }
+
+        if !(self.enable_accel || self.enable_gyro || self.enable_mag) {
+            problems.push("At least one of enable_accel, enable_gyro and enable_mag has to be set".to_string());
+        }
+        if let Err(e) = sensor::SamplingMode::parse(self.sampling_mode) {
+            problems.push(e.to_string());
+        }
+        if self.raw_diagnostics && self.sampling_mode == "fifo" {
+            problems.push("raw_diagnostics needs sampling_mode poll, reading the output registers would pop the FIFO".to_string());
+        }
+        if !self.enable_gyro && (self.sampling_mode == "fifo" || self.imu_drdy_gpio >= 0) {
+            problems.push("sampling_mode fifo and imu_drdy_gpio need enable_gyro".to_string());
+        }
+
+        match self.imu_interface {
+            "i2c" => {
+                if !(MIN_I2C_FREQUENCY_KHZ..=MAX_I2C_FREQUENCY_KHZ).contains(&self.i2c_frequency_khz) {
+                    problems.push(format!("i2c_frequency_khz {} is outside of {}-{} kHz", self.i2c_frequency_khz, MIN_I2C_FREQUENCY_KHZ, MAX_I2C_FREQUENCY_KHZ));
+                }
+                for (name, setting) in [("imu_ag_address", self.imu_ag_address), ("imu_mag_address", self.imu_mag_address)] {
+                    if let Err(e) = sensor::check_address_setting(setting) {
+                        problems.push(format!("{}: {}", name, e));
+                    }
+                }
+            },
+            "spi" => {
+                if self.imu_spi_ag_cs_gpio < 0 || self.imu_spi_mag_cs_gpio < 0 {
+                    problems.push("SPI needs imu_spi_ag_cs_gpio and imu_spi_mag_cs_gpio".to_string());
+                }
+                if !(MIN_SPI_FREQUENCY_KHZ..=MAX_SPI_FREQUENCY_KHZ).contains(&self.imu_spi_frequency_khz) {
+                    problems.push(format!("imu_spi_frequency_khz {} is outside of {}-{} kHz", self.imu_spi_frequency_khz, MIN_SPI_FREQUENCY_KHZ, MAX_SPI_FREQUENCY_KHZ));
+                }
+            },
+            other => problems.push(format!("Invalid imu_interface \"{}\" (expected i2c or spi)", other)),
+        }
+
+        if self.power_up_delay_ms > MAX_POWER_UP_DELAY_MS {
+            problems.push(format!("power_up_delay_ms must be at most {}", MAX_POWER_UP_DELAY_MS));
+        }
+
+        // Outputs, then inputs. Every pin can be used only once.
+        let mut outputs = vec![("power_gpio", self.power_gpio), ("neopixel_gpio", self.neopixel_gpio), ("led_gpio", self.led_gpio)];
+        let mut inputs = Vec::new();
+        match self.imu_interface {
+            "spi" => {
+                outputs.extend([
+                    ("imu_spi_sclk_gpio", self.imu_spi_sclk_gpio),
+                    ("imu_spi_mosi_gpio", self.imu_spi_mosi_gpio),
+                    ("imu_spi_ag_cs_gpio", self.imu_spi_ag_cs_gpio),
+                    ("imu_spi_mag_cs_gpio", self.imu_spi_mag_cs_gpio),
+                ]);
+                inputs.push(("imu_spi_miso_gpio", self.imu_spi_miso_gpio));
+            },
+            _ => outputs.extend([("i2c_sda_gpio", self.i2c_sda_gpio), ("i2c_scl_gpio", self.i2c_scl_gpio)]),
+        }
+        if self.imu_drdy_gpio >= 0 {
+            inputs.push(("imu_drdy_gpio", self.imu_drdy_gpio));
+        }
+        if self.wake_on_motion {
+            inputs.push(("motion_wake_gpio", self.motion_wake_gpio));
+        }
+        let mut used = vec![("the battery monitor", BATTERY_GPIO)];
+        for (i, &(name, gpio)) in outputs.iter().chain(inputs.iter()).enumerate() {
+            // A missing chip select is reported above already
+            if gpio < 0 && name.ends_with("_cs_gpio") {
+                continue;
+            }
+            if i < outputs.len() && !board::is_output_gpio(gpio) {
+                problems.push(format!("{} {} can't be used as an output", name, gpio));
+            } else if !board::is_gpio(gpio) {
+                problems.push(format!("{} {} isn't a usable GPIO", name, gpio));
+            }
+            match used.iter().find(|(_, other)| *other == gpio) {
+                Some((other, _)) => problems.push(format!("{} {} is already used by {}", name, gpio, other)),
+                None => used.push((name, gpio)),
+            }
+        }
+
+        if self.wake_on_motion {
+            if !power::is_rtc_gpio(self.motion_wake_gpio) {
+                problems.push(format!("motion_wake_gpio {} can't wake from deep sleep", self.motion_wake_gpio));
+            }
+            if !self.enable_accel {
+                problems.push("wake_on_motion needs enable_accel".to_string());
+            }
+            if self.motion_window_s == 0 || self.motion_threshold_mg == 0 {
+                problems.push("wake_on_motion needs motion_window_s and motion_threshold_mg".to_string());
+            }
+        }
+
+        if self.burst_interval_min > 0 && !self.spill_to_flash {
+            problems.push("burst_interval_min needs spill_to_flash".to_string());
+        }
+
+        if self.deadband && (self.accel_deadband_mg == 0 || self.gyro_deadband_mdps == 0 || self.mag_deadband_mgauss == 0) {
+            problems.push("deadband needs all of accel_deadband_mg, gyro_deadband_mdps and mag_deadband_mgauss".to_string());
+        }
+        if self.accel_event_threshold_mg > 0 && self.accel_highpass_mhz == 0 {
+            problems.push("accel_event_threshold_mg needs accel_highpass_mhz".to_string());
+        }
+        if (self.accel_highpass_mhz > 0 || self.accel_event_threshold_mg > 0) && !self.enable_accel {
+            problems.push("accel_highpass_mhz and accel_event_threshold_mg need enable_accel".to_string());
+        }
+        if self.gyro_integrate && !self.enable_gyro {
+            problems.push("gyro_integrate needs enable_gyro".to_string());
+        }
+        if self.burst_on_event && (self.burst_interval_min == 0 || self.accel_event_threshold_mg == 0) {
+            problems.push("burst_on_event needs burst_interval_min and accel_event_threshold_mg".to_string());
+        }
+
+        if self.reconnect_min_delay_ms == 0 {
+            problems.push("reconnect_min_delay_ms must be at least 1".to_string());
+        }
+        if self.reconnect_max_delay_ms < self.reconnect_min_delay_ms {
+            problems.push(format!("reconnect_max_delay_ms {} is below reconnect_min_delay_ms {}", self.reconnect_max_delay_ms, self.reconnect_min_delay_ms));
+        }
+        if self.reconnect_jitter_pct > 100 {
+            problems.push(format!("reconnect_jitter_pct {} is above 100", self.reconnect_jitter_pct));
+        }
+
+        if self.status_page && self.status_page_port == 0 {
+            problems.push("status_page_port must not be 0".to_string());
+        }
+        if self.provisioning_after_rounds > 0 {
+            let psk_len = self.provisioning_psk.len();
+            if !(credentials::MIN_PSK_LEN..=credentials::MAX_PSK_LEN).contains(&psk_len) {
+                problems.push(format!("provisioning_psk has to be {}-{} bytes", credentials::MIN_PSK_LEN, credentials::MAX_PSK_LEN));
+            }
+            if self.provisioning_timeout_s == 0 {
+                problems.push("provisioning_timeout_s must be at least 1".to_string());
+            }
+            if self.status_page && self.status_page_port == provision::PORT {
+                problems.push(format!("status_page_port {} is taken by the setup page", provision::PORT));
+            }
+        }
+        if self.coalesce_window_ms > 0 && !SEND_INTERVAL_RANGE_MS.contains(&self.coalesce_window_ms) {
+            problems.push(format!("coalesce_window_ms {} is outside of {}-{}", self.coalesce_window_ms, SEND_INTERVAL_RANGE_MS.start(), SEND_INTERVAL_RANGE_MS.end()));
+        }
+        if !SEND_INTERVAL_RANGE_MS.contains(&self.send_interval_ms) {
+            problems.push(format!("send_interval_ms {} is outside of {}-{}", self.send_interval_ms, SEND_INTERVAL_RANGE_MS.start(), SEND_INTERVAL_RANGE_MS.end()));
+        }
+        if self.mag_read_divisor == 0 {
+            problems.push("mag_read_divisor has to be at least 1".to_string());
+        }
+        if self.jitter_window_samples < 2 {
+            problems.push("jitter_window_samples has to be at least 2".to_string());
+        }
+
+        if self.adaptive_sampling && self.buffer_low_water_pct >= self.buffer_high_water_pct {
+            problems.push("buffer_low_water_pct has to be below buffer_high_water_pct".to_string());
+        }
+
+        for (name, priority) in [("sampling_priority", self.sampling_priority), ("networking_priority", self.networking_priority)] {
+            if !(1..=MAX_THREAD_PRIORITY).contains(&priority) {
+                problems.push(format!("{} {} is outside of 1-{}", name, priority, MAX_THREAD_PRIORITY));
+            }
+        }
+        for (name, stack_size) in [("sampling_stack_size", self.sampling_stack_size), ("networking_stack_size", self.networking_stack_size)] {
+            if stack_size != 0 && stack_size < MIN_THREAD_STACK_SIZE {
+                problems.push(format!("{} {} is below {} bytes", name, stack_size, MIN_THREAD_STACK_SIZE));
+            }
+        }
+
+        if !problems.is_empty() {
+            bail!("{}", problems.join("; "));
+        }
+
+        Ok(())
+    }
+}
+
+// Modules of this crate that log, "" for main
+const LOG_MODULES: &[&str] = &[
+    "", "backoff", "batch", "battery", "board", "calibrate", "clock", "command", "deadband", "fanout",
+    "filter", "http", "imu", "linktest", "logging", "neopixel", "mdns", "mqtt", "ota", "power", "provision",
+    "queue", "sensor", "settings", "shutdown", "spill", "status", "status_page", "telemetry", "transport", "udp",
+    "watchdog", "wifi", "ws",
+];
+
+const MIN_I2C_FREQUENCY_KHZ: u32 = 10;
+const MAX_I2C_FREQUENCY_KHZ: u32 = 400;
+const MIN_SPI_FREQUENCY_KHZ: u32 = 100;
+const MAX_SPI_FREQUENCY_KHZ: u32 = 10_000;
+// p.pins.gpio35, the only pin that isn't configurable
+const BATTERY_GPIO: i32 = 35;
+const MOTION_SLEEP_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(2);
+const SENSOR_INIT_ATTEMPTS: u32 = 5;
+// Extra wait for a sensor that doesn't answer after power_up_delay_ms, see
+// wait_for_sensor()
+const SENSOR_READY_TIMEOUT: Duration = Duration::from_millis(500);
+const SENSOR_READY_MIN_POLL_INTERVAL: Duration = Duration::from_millis(5);
+const MAX_POWER_UP_DELAY_MS: u32 = 1000;
+// An ODR this many times the poll rate is flagged as aliasing
+const ODR_ALIASING_FACTOR: f32 = 2.0;
+const WIFI_CONNECT_ATTEMPTS: u32 = 10;
+const DIAGNOSTICS_INTERVAL: Duration = Duration::from_secs(60);
+// Readings for the smoothed RSSI, see wifi::sample_rssi()
+const RSSI_INTERVAL: Duration = Duration::from_secs(1);
+const RATE_PERIOD: Duration = Duration::from_secs(1);
+const RATE_WINDOW_PERIODS: usize = 10;
+const WS_RECONNECT_ATTEMPTS: u32 = 5;
+// Package version and the commit it was built from, e.g. "0.1.0+1a2b3c4"
+const FIRMWARE_VERSION: &str = concat!(env!("CARGO_PKG_VERSION"), "+", env!("GIT_HASH"));
+
+// Lowest rate the rate command accepts
+const MIN_SAMPLE_RATE_HZ: f32 = 1.0;
+
+// configMAX_PRIORITIES on the ESP32 is 25, the WiFi driver runs at 23 and
+// lwIP at 18
+const MAX_THREAD_PRIORITY: u8 = 17;
+const BACKGROUND_THREAD_PRIORITY: u8 = 2;
+const MIN_THREAD_STACK_SIZE: u32 = 4096;
+const SEND_INTERVAL_RANGE_MS: std::ops::RangeInclusive<u32> = 10..=60_000;
+// The networking thread sleeps at most this long between iterations, so
+// pings, commands and status messages don't wait for a long send interval
+const MAX_NETWORKING_SLEEP: Duration = Duration::from_millis(100);
+const DRDY_TIMEOUT: Duration = Duration::from_secs(1);
+// How often a paused sampling thread checks whether to go on, during an
+// update or in maintenance mode
+const OTA_SAMPLING_PAUSE: Duration = Duration::from_millis(500);
+const BATTERY_INTERVAL: Duration = Duration::from_secs(10);
+const EARLY_BOOT_FAILURE_LOG_INTERVAL: Duration = Duration::from_secs(10);
+// Two rounds of the SOS blink
+const PANIC_RESTART_DELAY: Duration = Duration::from_secs(7);
+const GYRO_ZERO_INTERVAL: Duration = Duration::from_millis(10);
+// A networking thread that ran this long starts over with the shortest
+// restart delay
+const NETWORKING_STABLE_TIME: Duration = Duration::from_secs(300);
+// On I2C every attempt power cycles the sensor, and the NeoPixel with it
+const SENSOR_RESTART_DELAY: Duration = Duration::from_secs(30);
+// How long a burst waits for the collector to acknowledge a replayed spill
+// segment before it leaves the rest of the spill for the next burst
+const SPILL_ACK_TIMEOUT: Duration = Duration::from_secs(5);
+const SPILL_ACK_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+// Sample rate set with the rate command as f32 bits, NaN to sample as
+// configured. Kept over restarts of the sampling thread.
+static RATE_OVERRIDE: AtomicU32 = AtomicU32::new(0x7FC0_0000);
+
+fn set_rate_override(hz: f32) {
+    RATE_OVERRIDE.store(hz.to_bits(), Ordering::Relaxed);
+}
+
+fn rate_override() -> Option<f32> {
+    let hz = f32::from_bits(RATE_OVERRIDE.load(Ordering::Relaxed));
+    (!hz.is_nan()).then_some(hz)
+}
+
+// Send interval in ms, send_interval_ms until changed by the send_interval
+// command. Kept over restarts of the networking thread.
+static SEND_INTERVAL_MS: AtomicU32 = AtomicU32::new(CONFIG.send_interval_ms);
+
+fn send_interval() -> Duration {
+    Duration::from_millis(SEND_INTERVAL_MS.load(Ordering::Relaxed).into())
+}
+
+// raw_diagnostics until changed by the raw command
+static RAW_DIAGNOSTICS: AtomicBool = AtomicBool::new(CONFIG.raw_diagnostics);
+
+// Set by the flush command, the networking thread sends all of the
+// ringbuffer on its next iteration and clears it
+static FLUSH_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+// Set by the dump command, like FLUSH_REQUESTED
+static DUMP_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+// Set by the zero_angle command, the sampling loop zeroes the integrated
+// angle with its next sample and clears it
+static ZERO_ANGLE_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+// Samples pushed into the ringbuffer so far, wrapping. Only changed with the
+// ringbuffer locked, so together with its length it numbers the buffered
+// samples, see oldest_index(). Timestamps can't do that: after about a day
+// and a half an f32 second count is coarser than the sample interval.
+static SAMPLES_PUSHED: AtomicU32 = AtomicU32::new(0);
+
+// The ringbuffer for the panic hook, which can't be handed anything
+static RINGBUFFER: OnceLock<Arc<Mutex<AllocRingBuffer<SensorDataSample>>>> = OnceLock::new();
+
+// Whether the magnetometer answered at the last sensor initialization.
+// Without one the samples carry zeros in its place.
+static MAG_PRESENT: AtomicBool = AtomicBool::new(true);
+
+static RINGBUFFER_POISON_LOGGED: AtomicBool = AtomicBool::new(false);
+
 fn main() -> anyhow::Result<()> {
     // It is necessary to call this function once. Otherwise some patches to the runtime
     // implemented by esp-idf-sys might not link properly. See https://github.com/esp-rs/esp-idf-template/issues/71
     esp_idf_svc::sys::link_patches();
 
-    // Bind the log crate to the ESP Logging facilities
-    esp_idf_svc::log::EspLogger::initialize_default();
+    // Bind the log crate to the ESP Logging facilities, with the device ID
+    // in front of every line
+    logging::init();
+    let device_id = device_id().unwrap_or_else(|e| early_boot_failure("device ID", e));
+    logging::set_device_id(&device_id);
+    log::info!("Firmware {}, device ID is {}", FIRMWARE_VERSION, device_id);
+
+    install_panic_hook();
 
     // Get peripherals
-    let p = hal::peripherals::Peripherals::take()?;
-    let sysloop = EspSystemEventLoop::take()?;
-    let nvs = EspDefaultNvsPartition::take()?;
+    let p = hal::peripherals::Peripherals::take().unwrap_or_else(|e| early_boot_failure("peripherals", e));
+    let sysloop = EspSystemEventLoop::take().unwrap_or_else(|e| early_boot_failure("system event loop", e));
+    let nvs = EspDefaultNvsPartition::take().unwrap_or_else(|e| early_boot_failure("NVS partition", e));
+
+    // Runtime settings from NVS, falling back to the compiled-in CONFIG
+    let settings = settings::SettingsStore::new(nvs.clone())
+        .and_then(|store| store.load())
+        .unwrap_or_else(|e| {
+            log::warn!("Failed to load settings from NVS, using compiled defaults: {}", e);
+            settings::Settings::default()
+        });
+
+    restore_counters(&nvs);
+    if settings.maintenance {
+        log::info!("Staying in maintenance mode, sampling is paused");
+        status::set_maintenance(true);
+    }
+
+    // Before any pin is claimed, rather than failing halfway through setup.
+    // The pins are only sound to claim once validate() passed, so an invalid
+    // configuration gets the status led and nothing else.
+    if let Err(e) = CONFIG.validate(&settings) {
+        log::error!("Invalid configuration, not starting: {}", e);
+        // Credentials get a signal of their own, they're the most likely
+        // thing to be mistyped on site
+        let credentials_invalid = CONFIG.credential_errors().next().is_some();
+        status::fail(if credentials_invalid { status::State::CredentialsError } else { status::State::Error });
+        match board::output_pin(CONFIG.led_gpio) {
+            Ok(led) => return status_indicator(PinDriver::output(led)?),
+            Err(_) => loop {
+                log::error!("Invalid configuration and no usable led_gpio, not starting: {}", e);
+                std::thread::sleep(EARLY_BOOT_FAILURE_LOG_INTERVAL);
+            },
+        }
+    }
 
     // Power pin setup
     // QWIIC connector power pin is driven by a regulator controlled by the same
     // pin as power to the neopixel.
-    let mut power = PinDriver::output(p.pins.gpio2)?;
-    power.set_high()?;
-    std::thread::sleep(Duration::from_millis(20)); // Sensor needs some time for proper power-up
+    let mut power = power::PowerRail::new(
+        PinDriver::output(board::output_pin(CONFIG.power_gpio)?)?,
+        Duration::from_millis(CONFIG.power_up_delay_ms.into()),
+    );
+    power::release(&[CONFIG.power_gpio])?;
+    if sensor_on_power_pin() || settings.neopixel_brightness > 0 {
+        power.power_up()?;
+    } else {
+        log::info!("Stealth mode, leaving the NeoPixel unpowered");
+    }
+
+    if CONFIG.wake_on_motion && power::woken_by_gpio() {
+        log::info!("Woken up by motion");
+    }
+
+    // Start the status NeoPixel early, so it also shows the boot phase
+    let status_light = neopixel::Neopixel::new(p.rmt.channel0, board::output_pin(CONFIG.neopixel_gpio)?)?;
+    let brightness = settings.neopixel_brightness;
+    spawn_thread("status light", 0, BACKGROUND_THREAD_PRIORITY, move ||
+        status_light_thread(status_light, brightness)
+            .inspect_err(|e| log::error!("Status light died: {e}"))
+    ).expect("Failed to create status light thread");
+
+    // Credentials stored in NVS replace the compiled ones validate() checked.
+    // The setup network can replace them too, so with provisioning they're
+    // left for connect_wifi() to give up on.
+    let stored_credentials = match (settings.wifi_ssid.as_str(), settings.wifi_psk.as_str()) {
+        _ if CONFIG.provisioning_after_rounds > 0 => Vec::new(),
+        credentials if credentials == (CONFIG.wifi_ssid, CONFIG.wifi_psk) => Vec::new(),
+        (ssid, psk) => credentials::check_credentials(ssid, psk),
+    };
+    for e in &stored_credentials {
+        log::error!("Invalid WiFi credentials in NVS, not starting: {}", e);
+    }
+    if !stored_credentials.is_empty() {
+        status::fail(status::State::CredentialsError);
+        return status_indicator(PinDriver::output(board::output_pin(CONFIG.led_gpio)?)?);
+    }
 
     // Sensor setup
-    let ag_addr = lsm9ds1::interface::i2c::AgAddress::_2;
-    let mag_addr = lsm9ds1::interface::i2c::MagAddress::_2;
-    let sensor_i2c = I2cDriver::new(p.i2c0, p.pins.gpio22, p.pins.gpio20, &Default::default()).unwrap();
-    let sensor_interface = lsm9ds1::interface::I2cInterface::init(sensor_i2c, ag_addr, mag_addr);
-    let mut sensor = LSM9DS1Init::default().with_interface(sensor_interface);
+    let sensor_bus = match CONFIG.imu_interface {
+        "spi" => {
+            let driver = SpiDriver::new(
+                p.spi2,
+                board::output_pin(CONFIG.imu_spi_sclk_gpio)?,
+                board::output_pin(CONFIG.imu_spi_mosi_gpio)?,
+                Some(board::input_pin(CONFIG.imu_spi_miso_gpio)?),
+                &SpiDriverConfig::new(),
+            )?;
+            let spi_config = spi::config::Config::new()
+                .baudrate(KiloHertz(CONFIG.imu_spi_frequency_khz).into())
+                .data_mode(spi::config::MODE_3);
+            // The chip selects are left to the lsm9ds1 driver
+            let device = SpiDeviceDriver::new(driver, Option::<AnyOutputPin>::None, &spi_config)?;
+            log::info!("Sensor bus is SPI at {} kHz", CONFIG.imu_spi_frequency_khz);
+            sensor::Bus::Spi {
+                spi: sensor::SharedSpi::new(device),
+                ag_cs: sensor::ChipSelect::new(CONFIG.imu_spi_ag_cs_gpio)?,
+                mag_cs: sensor::ChipSelect::new(CONFIG.imu_spi_mag_cs_gpio)?,
+            }
+        },
+        _ => {
+            let i2c_config = I2cConfig::new().baudrate(KiloHertz(CONFIG.i2c_frequency_khz).into());
+            log::info!("Sensor bus is I2C at {} kHz", CONFIG.i2c_frequency_khz);
+            let sda = board::io_pin(CONFIG.i2c_sda_gpio)?;
+            let scl = board::io_pin(CONFIG.i2c_scl_gpio)?;
+            sensor::Bus::I2c(sensor::SharedI2c::new(I2cDriver::new(p.i2c0, sda, scl, &i2c_config).unwrap()))
+        },
+    };
+    let sensor = init_sensor(&sensor_bus, &mut power);
+
+    // All checked by validate()
+    let calibration = sensor::Calibration {
+        accel: sensor::AxisCalibration::parse(CONFIG.accel_offset, CONFIG.accel_scale)
+            .expect("Invalid accelerometer calibration"),
+        gyro: sensor::AxisCalibration::parse(CONFIG.gyro_offset, CONFIG.gyro_scale)
+            .expect("Invalid gyroscope calibration"),
+        mag: sensor::AxisCalibration::parse(CONFIG.mag_offset, CONFIG.mag_scale)
+            .expect("Invalid magnetometer calibration"),
+    };
+
+    let sampling_mode = sensor::SamplingMode::parse(CONFIG.sampling_mode)
+        .expect("Invalid sampling mode");
+
+    let overflow_policy = OverflowPolicy::parse(CONFIG.overflow_policy)
+        .expect("Invalid overflow policy");
+
+    if CONFIG.light_sleep {
+        if CONFIG.imu_drdy_gpio < 0 {
+            log::warn!("light_sleep needs imu_drdy_gpio, staying awake");
+        } else {
+            power::enable_light_sleep(CONFIG.imu_drdy_gpio).expect("Failed to enable light sleep");
+            log::info!("Light sleep enabled");
+        }
+    }
+
+    if CONFIG.spill_to_flash {
+        _ = spill::init(CONFIG.spill_max_bytes, telemetry::boot_count())
+            .inspect_err(|e| log::error!("Failed to set up spill storage, samples won't be kept on flash: {}", e));
+    }
 
-    sensor.begin_accel().expect("Failed to initialize accelerometer");
-    sensor.begin_gyro().expect("Failed to initialize gyroscope");
-    sensor.begin_mag().expect("Failed to initialize magnetometer");
+    watchdog::configure(Duration::from_secs(CONFIG.watchdog_timeout_s.into()))
+        .expect("Failed to configure task watchdog");
 
     // Create ringbuffer for sensor data
-    let sensor_data_ringbuffer = AllocRingBuffer::<proto::SensorDataSample>::new(128);
+    let capacity = CONFIG.ringbuffer_capacity as usize;
+    let sensor_data_ringbuffer = AllocRingBuffer::<proto::SensorDataSample>::new(capacity);
+    log::info!("Ringbuffer holds {} samples ({} bytes)", capacity, capacity * std::mem::size_of::<proto::SensorDataSample>());
     let sensor_data_ringbuffer_mutex = Arc::new(Mutex::new(sensor_data_ringbuffer));
+    _ = RINGBUFFER.set(sensor_data_ringbuffer_mutex.clone());
 
-    // Spawn sensor sampling thread
-    let mutex_clone = sensor_data_ringbuffer_mutex.clone();
-    std::thread::Builder::new()
-        .name("sensor sampling thread".into())
-        .spawn(move ||
-            sensor_sampling_thread(sensor, mutex_clone)
-                .inspect_err(|e| log::error!("Sensor sampling thread died: {e}"))
-        ).expect("Failed to create sensor sampling thread");
+    // Spawn sensor sampling thread. Without a sensor the rest keeps
+    // running, so the device still reports status and takes commands.
+    let spawn_sampling = |sensor| {
+        let mutex_clone = sensor_data_ringbuffer_mutex.clone();
+        let store = settings::SettingsStore::new(nvs.clone())
+            .inspect_err(|e| log::warn!("Magnetometer calibration won't be stored: {}", e))
+            .ok();
+        spawn_thread("sensor sampling thread", CONFIG.sampling_stack_size, CONFIG.sampling_priority, move || {
+            let _running = shutdown::register();
+            let result = match sensor {
+                imu::AnyLsm9ds1::I2c(sensor) => sensor_sampling_thread(sensor, sampling_mode, calibration, overflow_policy, store, mutex_clone),
+                imu::AnyLsm9ds1::Spi(sensor) => sensor_sampling_thread(sensor, sampling_mode, calibration, overflow_policy, store, mutex_clone),
+            };
+            result.inspect_err(|e| {
+                log::error!("Sensor sampling thread died: {e}");
+                status::fail(status::State::SensorError);
+            })
+        }).expect("Failed to create sensor sampling thread")
+    };
+    let mut sampling = match sensor {
+        Ok(sensor) => Some(spawn_sampling(sensor)),
+        Err(e) => {
+            log::error!("Giving up on the sensor: {}", e);
+            status::fail(status::State::SensorError);
+            None
+        },
+    };
 
         
     // Setup networking
-    let wifi = EspWifi::new(p.modem, sysloop.clone(), Some(nvs.clone()))?;    
+    let wifi = EspWifi::new(p.modem, sysloop.clone(), Some(nvs.clone()))?;
+    if !CONFIG.wifi_country.is_empty() {
+        wifi::set_country(CONFIG.wifi_country)?;
+    }
     let wifi_mutex = Arc::new(Mutex::new(wifi));
+
+    // Kept for as long as main runs, which is until the restart
+    let _status_page = match CONFIG.status_page {
+        true => status_page::start(CONFIG.status_page_port, FIRMWARE_VERSION, &device_id)
+            .inspect_err(|e| log::warn!("Failed to start the status page: {}", e))
+            .ok(),
+        false => None,
+    };
     
-    let wifi_mutex_clone = wifi_mutex.clone();
-    let sysloop_clone = sysloop.clone();
-    let buffer_mutex_clone = sensor_data_ringbuffer_mutex.clone();
-    std::thread::Builder::new()
-        .name("networking thread".into())
-        .stack_size(16384)
-        .spawn(move ||
-            networking_thread(wifi_mutex_clone, sysloop_clone, buffer_mutex_clone)
-                .inspect_err(|e| log::error!("Networking thread died: {e}"))
-        ).expect("Failed to create networking thread");
+    let spawn_networking = || {
+        let settings = settings.clone();
+        let wifi_mutex_clone = wifi_mutex.clone();
+        let sysloop_clone = sysloop.clone();
+        let nvs_clone = nvs.clone();
+        let buffer_mutex_clone = sensor_data_ringbuffer_mutex.clone();
+        spawn_thread("networking thread", CONFIG.networking_stack_size, CONFIG.networking_priority, move || {
+            let _running = shutdown::register();
+            networking_thread(settings, wifi_mutex_clone, sysloop_clone, nvs_clone, buffer_mutex_clone)
+                .inspect_err(|e| {
+                    log::error!("Networking thread died: {e}");
+                    status::fail(status::State::Error);
+                })
+        }).expect("Failed to create networking thread")
+    };
+    let mut networking = Some(spawn_networking());
+    let mut networking_started = Instant::now();
+    let mut networking_restart: Option<Instant> = None;
+    let mut networking_backoff = reconnect_backoff();
     
-    // Start WiFi indicator led
-    let indicator_led = PinDriver::output(p.pins.gpio13)?;
-    let wifi_mutex_clone = wifi_mutex.clone();
-    std::thread::Builder::new()
-        .name("WiFi indicator".into())
-        .spawn(move ||
-            wifi_indicator(indicator_led, wifi_mutex_clone)
-                .inspect_err(|e| log::error!("WiFi Indicator died (;ω;) ({e})"))
-        ).expect("Failed to create wifi indicator thread");
+    // Start battery monitor
+    let battery = battery::Battery::new(p.adc1, p.pins.gpio35)?;
+    spawn_thread("battery monitor", 0, BACKGROUND_THREAD_PRIORITY, move ||
+        battery_monitor(battery)
+            .inspect_err(|e| log::error!("Battery monitor died: {e}"))
+    ).expect("Failed to create battery monitor thread");
+
+    // Start status indicator led
+    let indicator_led = PinDriver::output(board::output_pin(CONFIG.led_gpio)?)?;
+    spawn_thread("status indicator", 0, BACKGROUND_THREAD_PRIORITY, move ||
+        status_indicator(indicator_led)
+            .inspect_err(|e| log::error!("Status indicator died (;ω;) ({e})"))
+    ).expect("Failed to create status indicator thread");
 
     
+    // Supervise the worker threads. They share nothing but the WiFi driver
+    // and the ringbuffer, which outlive them here, so a dead one is simply
+    // started again while the other keeps going.
+    let mut last_sensor_attempt = Instant::now();
+    let awake_since = Instant::now();
+    let mut counter_store = settings::SettingsStore::new(nvs.clone())
+        .inspect_err(|e| log::warn!("The batch total and maintenance mode won't be saved: {}", e))
+        .ok();
+    let mut saved_batch_total = telemetry::batch_total();
+    let mut saved_maintenance = status::maintenance();
     loop {
         std::thread::sleep(Duration::from_millis(100));
+
+        if let Some(store) = counter_store.as_mut() {
+            save_batch_total(store, &mut saved_batch_total);
+            save_maintenance(store, &mut saved_maintenance);
+        }
+
+        if CONFIG.wake_on_motion && awake_since.elapsed() >= Duration::from_secs(CONFIG.motion_window_s.into()) {
+            if let Err(e) = sleep_until_motion(&sensor_bus) {
+                log::error!("Failed to sleep until motion, restarting: {}", e);
+                hal::reset::restart();
+            }
+        }
+
+        // A reboot is coming, threads that stopped for it stay down
+        if shutdown::requested() {
+            continue;
+        }
+
+        if networking.as_ref().is_some_and(|handle| handle.is_finished()) {
+            log_post_mortem(&snapshot(&sensor_data_ringbuffer_mutex));
+            match networking.take().map(|handle| handle.join()) {
+                // Stays down, the fault stays on the indicators
+                Some(Ok(Err(e))) if is_permanent(&e) => {
+                    log::error!("Not restarting networking thread until the configuration is fixed");
+                },
+                result => {
+                    if result.is_some_and(|result| result.is_err()) {
+                        log::error!("Networking thread panicked");
+                        status::fail(status::State::Error);
+                        // Whatever the driver was doing gets redone from scratch
+                        wifi_mutex.clear_poison();
+                    }
+                    if networking_started.elapsed() >= NETWORKING_STABLE_TIME {
+                        networking_backoff.reset();
+                    }
+                    let delay = networking_backoff.next_delay();
+                    log::warn!("Restarting networking thread in {:?}", delay);
+                    networking_restart = Some(Instant::now() + delay);
+                },
+            }
+        }
+
+        // Restarted without blocking the supervisor meanwhile
+        if networking_restart.is_some_and(|at| Instant::now() >= at) {
+            networking_restart = None;
+            status::clear_fault(status::State::Error);
+            networking = Some(spawn_networking());
+            networking_started = Instant::now();
+        }
+
+        if sampling.as_ref().is_some_and(|handle| handle.is_finished()) {
+            log_post_mortem(&snapshot(&sensor_data_ringbuffer_mutex));
+            if sampling.take().is_some_and(|handle| handle.join().is_err()) {
+                log::error!("Sensor sampling thread panicked");
+                status::fail(status::State::SensorError);
+            }
+        }
+
+        // The sampling thread dropped the sensor with it, so it starts over
+        // from scratch including the power cycling
+        if sampling.is_none() && last_sensor_attempt.elapsed() >= SENSOR_RESTART_DELAY {
+            log::warn!("Restarting sensor sampling thread");
+            match init_sensor(&sensor_bus, &mut power) {
+                Ok(sensor) => {
+                    status::clear_fault(status::State::SensorError);
+                    sampling = Some(spawn_sampling(sensor));
+                },
+                Err(e) => log::error!("Sensor still unavailable: {}", e),
+            }
+            last_sensor_attempt = Instant::now();
+        }
     }
 }
 
-fn networking_thread<R>(wifi_mutex: Arc<Mutex<EspWifi>>, sysloop: EspSystemEventLoop, data_buffer: Arc<Mutex<R>>) -> Result<()>
-where
-    R: RingBuffer<proto::SensorDataSample>,
-{
-    
-    // Connect to WiFi
-    log::info!("Connecting to WiFi {}", CONFIG.wifi_ssid);
-    wifi::connect(wifi_mutex.clone(), CONFIG.wifi_ssid, CONFIG.wifi_psk, sysloop.clone())
-        .inspect_err(|e| log::error!("Error during WiFi connection attempt: {}", e))?;
-    log::info!("Connected");
+// Stops the worker threads, leaves the accelerometer watching for motion
+// and sleeps until it sees some. Only returns on failure.
+fn sleep_until_motion(bus: &sensor::Bus) -> Result<()> {
+    log::info!("Sleeping until motion");
+    shutdown::request(MOTION_SLEEP_SHUTDOWN_TIMEOUT);
 
-    // Open WS connection
-    log::info!("Connecting to {}:{}{}", CONFIG.ws_host, CONFIG.ws_port, CONFIG.ws_endpoint);
-    let mut client = Box::new(ws::WebSocketClient::<4096>::new());
-    client.connect(CONFIG.ws_host, CONFIG.ws_port, CONFIG.ws_endpoint)
-        .expect("Websocket client failed to connect");
-    log::info!("Connected");
+    let (ag, mag) = match bus {
+        sensor::Bus::I2c(bus) => {
+            let (ag_addr, mag_addr) = sensor::detect_addresses(bus, CONFIG.imu_ag_address, CONFIG.imu_mag_address)?;
+            (sensor::Device::accel_gyro(bus, &ag_addr), sensor::Device::magnetometer(bus, &mag_addr))
+        },
+        sensor::Bus::Spi { spi, ag_cs, mag_cs } => (
+            sensor::Device::Spi { spi: spi.clone(), cs: ag_cs.clone() },
+            sensor::Device::Spi { spi: spi.clone(), cs: mag_cs.clone() },
+        ),
+    };
+    let mag = MAG_PRESENT.load(Ordering::Relaxed).then_some(&mag);
+    sensor::power_down_unused(&ag, mag, sensor::Sensors { accel: true, gyro: false, mag: false })?;
+    sensor::Registers::new(ag).enable_motion_wake(CONFIG.motion_threshold_mg as f32 / 1000.0, CONFIG.accel_range_g)?;
+
+    // The sensor needs its supply to keep watching
+    power::deep_sleep_until_high(CONFIG.motion_wake_gpio, &[CONFIG.power_gpio])
+}
+
+// Failures before the status indicators run would otherwise just end main
+// with nothing to see on the board. The status led is lit on its own,
+// without the peripherals, and the error is repeated for a console attached
+// later.
+fn early_boot_failure(what: &str, e: impl std::fmt::Display) -> ! {
+    let led = board::output_pin(CONFIG.led_gpio)
+        .and_then(|pin| Ok(PinDriver::output(pin)?))
+        .and_then(|mut led| {
+            led.set_high()?;
+            Ok(led)
+        });
+    if let Err(e) = &led {
+        log::error!("Failed to light the status led: {}", e);
+    }
+
+    loop {
+        log::error!("Failed to get the {}, not starting: {}", what, e);
+        std::thread::sleep(EARLY_BOOT_FAILURE_LOG_INTERVAL);
+    }
+}
 
-    // Send ID as text
-    client.send_text("Feather")?;
+// A panic anywhere leaves the other threads running against a dead peer, so
+// show it on the indicators for a moment and start over
+fn install_panic_hook() {
+    std::panic::set_hook(Box::new(|info| {
+        log::error!("{}, restarting in {:?}", info, PANIC_RESTART_DELAY);
+        status::fail(status::State::Panic);
+        // The panicking thread may be holding the ringbuffer, waiting for it
+        // would never end
+        match RINGBUFFER.get().map(|buffer| buffer.try_lock()) {
+            Some(Ok(buffer)) => log_post_mortem(&buffer.iter().cloned().collect::<Vec<_>>()),
+            Some(Err(TryLockError::Poisoned(e))) => log_post_mortem(&e.into_inner().iter().cloned().collect::<Vec<_>>()),
+            Some(Err(TryLockError::WouldBlock)) => log::error!("Ringbuffer is locked, not logging its samples"),
+            None => {},
+        }
+        let start = Instant::now();
+        shutdown::request(PANIC_RESTART_DELAY);
+        std::thread::sleep(PANIC_RESTART_DELAY.saturating_sub(start.elapsed()));
+        unsafe { esp_idf_svc::sys::esp_restart() };
+    }));
+}
 
+// A flaky QWIIC cable or a sensor that wasn't ready in time usually comes
+// back after cycling its power
+fn init_sensor(bus: &sensor::Bus, power: &mut power::PowerRail) -> Result<imu::AnyLsm9ds1> {
+
+    let mut attempt = 1;
     loop {
-        let samples = data_buffer.lock()
-            .inspect_err(|e| log::error!("Failed to lock ringbuffer mutex: {}", e))
-            .ok()
-            .and_then(|mut buffer| Some(buffer.drain().take(100).collect::<Vec<_>>()));
+        wait_for_sensor(bus, power.power_up_delay());
+        match try_init_sensor(bus) {
+            Ok(sensor) => return Ok(sensor),
+            Err(e) if attempt >= SENSOR_INIT_ATTEMPTS => {
+                bail!("Sensor initialization failed after {} attempts: {}", attempt, e)
+            },
+            Err(e) => log::warn!("Sensor initialization attempt {}/{} failed: {}", attempt, SENSOR_INIT_ATTEMPTS, e),
+        }
 
-        if let Some(samples) = samples {
-            let message = proto::SensorData {
-                samples,
-            }.encode_to_vec();
+        if sensor_on_power_pin() {
+            log::info!("Power cycling the sensor");
+            power.power_cycle()?;
+        }
+        // Give it longer with every attempt, in case it's just slow
+        attempt += 1;
+        std::thread::sleep(power.power_up_delay() * attempt);
+    }
+}
 
-            _ = client.send_binary(&message)
-                .inspect_err(|e| log::error!("Failed to send data: {}", e))
-                .and_then(|_| { log::info!("Sent data"); Ok(())});
+// Some boards take longer than power_up_delay_ms to come up on a cold boot.
+// Polls WHO_AM_I at doubling intervals until the sensor answers, giving up
+// after SENSOR_READY_TIMEOUT and leaving the error to the initialization.
+fn wait_for_sensor(bus: &sensor::Bus, power_up_delay: Duration) {
+    let start = Instant::now();
+    let mut delay = power_up_delay.max(SENSOR_READY_MIN_POLL_INTERVAL);
+    while !sensor::responds(bus) {
+        if start.elapsed() >= SENSOR_READY_TIMEOUT {
+            log::warn!("Sensor didn't answer WHO_AM_I within {:?}", SENSOR_READY_TIMEOUT);
+            return;
         }
+        std::thread::sleep(delay.min(SENSOR_READY_TIMEOUT.saturating_sub(start.elapsed())));
+        delay *= 2;
+    }
 
-        std::thread::sleep(Duration::from_millis(100));
+    let waited = start.elapsed();
+    if waited >= SENSOR_READY_MIN_POLL_INTERVAL {
+        log::info!("Sensor took {:?} longer than power_up_delay_ms to answer", waited);
     }
 }
 
-fn wifi_indicator<P>(mut led: PinDriver<P, Output>, wifi_mutex: Arc<Mutex<EspWifi>>) -> Result<()>
-where
-    P: OutputPin {
+// The QWIIC connector is supplied through power_gpio, a sensor on SPI is
+// expected to be wired to 3V3 directly
+fn sensor_on_power_pin() -> bool {
+    CONFIG.imu_interface == "i2c"
+}
 
-    loop {
+fn try_init_sensor(bus: &sensor::Bus) -> Result<imu::AnyLsm9ds1> {
+
+    let (accel_odr, gyro_odr) = sensor::ag_odr(CONFIG.ag_odr_hz, CONFIG.enable_gyro)?;
+    let settings = LSM9DS1Init {
+        accel: accel::AccelSettings {
+            scale: sensor::accel_scale(CONFIG.accel_range_g)?,
+            sample_rate: accel_odr,
+            ..Default::default()
+        },
+        gyro: gyro::GyroSettings {
+            scale: sensor::gyro_scale(CONFIG.gyro_range_dps)?,
+            sample_rate: gyro_odr,
+            ..Default::default()
+        },
+        mag: mag::MagSettings {
+            scale: sensor::mag_scale(CONFIG.mag_range_gauss)?,
+            sample_rate: sensor::mag_odr(CONFIG.mag_odr_hz)?,
+            ..Default::default()
+        },
+    };
+
+    match bus {
+        sensor::Bus::I2c(bus) => {
+            // Board variants differ in which address the jumpers select, so
+            // either use the configured one or probe both for a WHO_AM_I answer
+            let (ag_addr, mag_addr) = sensor::detect_addresses(bus, CONFIG.imu_ag_address, CONFIG.imu_mag_address)?;
+            let ag = sensor::Device::accel_gyro(bus, &ag_addr);
+            let mag = sensor::Device::magnetometer(bus, &mag_addr);
+            prepare_sensor(&ag, &mag)?;
+            let sensor = begin_sensor(settings.with_interface(I2cInterface::init(bus.clone(), ag_addr, mag_addr)))?;
+            Ok(imu::AnyLsm9ds1::I2c(imu::Lsm9ds1::new(sensor, sensor::Registers::new(ag).with_magnetometer(mag))))
+        },
+        sensor::Bus::Spi { spi, ag_cs, mag_cs } => {
+            let ag = sensor::Device::Spi { spi: spi.clone(), cs: ag_cs.clone() };
+            let mag = sensor::Device::Spi { spi: spi.clone(), cs: mag_cs.clone() };
+            prepare_sensor(&ag, &mag)?;
+            let sensor = begin_sensor(settings.with_interface(SpiInterface::init(spi.clone(), ag_cs.clone(), mag_cs.clone())))?;
+            Ok(imu::AnyLsm9ds1::Spi(imu::Lsm9ds1::new(sensor, sensor::Registers::new(ag).with_magnetometer(mag))))
+        },
+    }
+}
 
-        let is_up = wifi_mutex.lock().ok()
-            .and_then(|wifi| wifi.is_up().ok())
-            .unwrap_or(false);
-        _ = led.set_level(is_up.into());
+fn prepare_sensor(ag: &sensor::Device, mag: &sensor::Device) -> Result<()> {
+    sensor::verify_identity(ag)?;
+    let mag_present = sensor::mag_present(mag);
+    MAG_PRESENT.store(mag_present, Ordering::Relaxed);
+    let sensors = enabled_sensors();
+    if CONFIG.enable_mag && !sensors.mag {
+        if !(sensors.accel || sensors.gyro) {
+            bail!("Only the magnetometer is enabled, but there is none");
+        }
+        log::warn!("No magnetometer, streaming samples without it");
+    }
 
-        std::thread::sleep(Duration::from_millis(200));
+    let mag = mag_present.then_some(mag);
+    sensor::power_down_unused(ag, mag, sensors)?;
+    if CONFIG.sensor_self_test {
+        sensor::self_test(ag, mag, sensors)?;
+        log::info!("Sensor self-test passed");
     }
+    Ok(())
 }
 
-fn sensor_sampling_thread<I, R>(mut sensor: LSM9DS1<I>, buffer_mutex: Arc<Mutex<R>>) -> Result<()>
+fn begin_sensor<I>(mut sensor: LSM9DS1<I>) -> Result<LSM9DS1<I>>
 where
-    I: lsm9ds1::interface::Interface,
-    R: RingBuffer<SensorDataSample>,
+    I: Interface,
+    I::Error: std::fmt::Debug,
 {
-    let timer = EspTimerService::new().expect("Failed to initialize timer service");
-    let start_time = timer.now();
+    let sensors = enabled_sensors();
+    if sensors.accel {
+        sensor.begin_accel().map_err(|e| anyhow!("Failed to initialize accelerometer: {:?}", e))?;
+    }
+    if sensors.gyro {
+        sensor.begin_gyro().map_err(|e| anyhow!("Failed to initialize gyroscope: {:?}", e))?;
+    }
+    if sensors.mag {
+        sensor.begin_mag().map_err(|e| anyhow!("Failed to initialize magnetometer: {:?}", e))?;
+    }
+
+    Ok(sensor)
+}
+
+fn enabled_sensors() -> sensor::Sensors {
+    sensor::Sensors {
+        accel: CONFIG.enable_accel,
+        gyro: CONFIG.enable_gyro,
+        mag: CONFIG.enable_mag && MAG_PRESENT.load(Ordering::Relaxed),
+    }
+}
+
+fn networking_thread<R>(
+    settings: settings::Settings,
+    wifi_mutex: Arc<Mutex<EspWifi>>,
+    sysloop: EspSystemEventLoop,
+    nvs: EspDefaultNvsPartition,
+    data_buffer: Arc<Mutex<R>>,
+) -> Result<()>
+where
+    R: RingBuffer<proto::SensorDataSample>,
+{
+    
+    let static_ip = wifi::StaticIp::parse(CONFIG.static_ip, CONFIG.static_gateway, CONFIG.static_netmask)
+        .inspect_err(|e| log::error!("Invalid static IP configuration: {}", e))?;
+
+    let auth_method = wifi::parse_auth_method(CONFIG.wifi_auth_method);
+    let mut networks = vec![wifi::Credentials {
+        ssid: &settings.wifi_ssid,
+        psk: &settings.wifi_psk,
+        auth_method,
+        // The EAP login belongs to the compiled in network, not to one
+        // provisioned or stored in NVS since
+        enterprise: (settings.wifi_ssid == CONFIG.wifi_ssid).then(wifi_enterprise).flatten(),
+    }];
+    networks.extend(wifi::parse_networks(CONFIG.wifi_fallback_networks, auth_method)
+        .inspect_err(|e| log::error!("Invalid fallback network configuration: {}", e))?);
+
+    let device_id = device_id()?;
+
+    let (_wifi_subscription, wifi_disconnected) = wifi::subscribe_disconnects(&sysloop)?;
+
+    let watchdog = watchdog::Watchdog::subscribe()?;
+
+    // Counted across calls, so bursts get to provisioning as well
+    let failed_rounds = Cell::new(0);
+    // Connect to WiFi, there's nothing to do without it so keep trying
+    // unless the configuration itself is broken. With provisioning on, the
+    // setup network is the way out of both. With single_round, a failed
+    // round of attempts that may work later is returned instead of retried,
+    // for bursts that rather try again at the next one. A round and the
+    // setup network each have their own limit and may take longer than the
+    // watchdog timeout, it's fed in between.
+    let connect_wifi = |watchdog: &watchdog::Watchdog, single_round: bool| -> Result<()> {
+        status::set(status::State::ConnectingWifi);
+        log::info!("Connecting to WiFi {}", networks.iter().map(|n| n.ssid).collect::<Vec<_>>().join(", "));
+        let timeout = Duration::from_secs(CONFIG.wifi_connect_timeout_s.into());
+        // Kept across rounds of attempts, so a long outage settles at the
+        // maximum delay
+        let mut backoff = reconnect_backoff();
+        while let Err(e) = watchdog.unwatched(|| wifi::connect_with_retry(wifi_mutex.clone(), &networks, static_ip, sysloop.clone(), WIFI_CONNECT_ATTEMPTS, timeout, &mut backoff)) {
+            watchdog.feed();
+            log::error!("{}", e);
+            failed_rounds.set(failed_rounds.get() + 1);
+            let provisioning = CONFIG.provisioning_after_rounds > 0;
+            if !e.last_error.is_retryable() && !provisioning {
+                return Err(e.into());
+            }
+            if provisioning && (failed_rounds.get() >= CONFIG.provisioning_after_rounds || !e.last_error.is_retryable()) {
+                // Only returns without a submission, the known networks get
+                // another full set of rounds then
+                watchdog.unwatched(|| provision::run(&wifi_mutex, &sysloop, &nvs, &device_id, CONFIG.provisioning_psk,
+                    Duration::from_secs(CONFIG.provisioning_timeout_s.into())))?;
+                watchdog.feed();
+                failed_rounds.set(0);
+                status::set(status::State::ConnectingWifi);
+            } else if single_round {
+                return Err(e.into());
+            }
+        }
+        failed_rounds.set(0);
+        // Disconnects seen while connecting are dealt with
+        wifi_disconnected.store(false, Ordering::Relaxed);
+        status::set(status::State::WifiUp);
+        log::info!("Connected");
+        Ok(())
+    };
+
+    if CONFIG.burst_interval_min > 0 {
+        return store_and_forward(&settings, &wifi_mutex, &connect_wifi, &device_id, &data_buffer, &watchdog);
+    }
+
+    connect_wifi(&watchdog, false)?;
+
+    // The SNTP client keeps resyncing in the background for as long as it's
+    // alive
+    let sntp = match CONFIG.ntp_server {
+        "" => None,
+        server => start_sntp(server)
+            .inspect_err(|e| log::warn!("Failed to start SNTP, sample times stay relative: {}", e))
+            .ok(),
+    };
+    let mut time_synced = false;
+
+    // Handshake retries may wait longer than the watchdog timeout
+    let (mut client, mut on_fallback) = watchdog.unwatched(|| connect_transport(&settings, &device_id))
+        .inspect_err(|e| log::error!("Failed to connect to the collector: {}", e))?;
+    log::info!("Connected");
+
+    // Getting this far means an updated image works
+    _ = ota::mark_running_slot_valid()
+        .inspect_err(|e| log::warn!("Failed to mark firmware as valid: {}", e));
 
+    // Identification is sent as the first text message of every session
+    send_hello(client.as_mut(), &device_id)?;
+    status::set(status::State::Streaming);
+
+    let mut connection_lost = false;
+    let mut last_status = Instant::now();
+    let status_interval = Duration::from_millis(CONFIG.status_interval_ms.into());
+    let mut last_diagnostics = Instant::now();
+    let mut last_frame_totals = telemetry::frame_totals();
+    let mut last_rssi = Instant::now();
+    let mut dropped_samples = 0;
+    // Number of the next batch, continues the batch total so it keeps
+    // counting up across connections and reboots for gap detection
+    let mut seq = telemetry::batch_total();
+    // Also holds on to a batch whose send failed until the connection is
+    // back, the ringbuffer may overwrite its samples meanwhile
+    let mut send_queue = queue::SendQueue::new(
+        CONFIG.send_queue_len as usize,
+        queue::FullPolicy::parse(CONFIG.send_queue_policy).expect("Invalid send queue policy"),
+    );
+    // Ringbuffer index of the oldest sample that isn't queued yet, None for
+    // the oldest buffered one. The queued ones stay buffered until sent.
+    let mut next_sample = None;
+    let mut last_send = Instant::now();
+    let mut last_upgrade_attempt = Instant::now();
+    let upgrade_interval = Duration::from_secs(CONFIG.ws_upgrade_interval_s.into());
     loop {
-        let acc = sensor.read_accel();
-        let gyro = sensor.read_gyro();
-        let mag = sensor.read_mag();
-
-        if let (Ok((ax, ay, az)), Ok((gx, gy, gz)), Ok((mx, my, mz))) = (acc, gyro, mag) {
-            let time = timer.now() - start_time;
-            let sample = proto::SensorDataSample {
-                time: time.as_secs_f32(),
-                acceleration: proto::Acceleration{ x: ax, y: ay, z: az },
-                magnetometer: proto::MagnetometerData { x: mx, y: my, z: mz },
-                gyroscope: proto::GyroscopeData { x: gx, y: gy, z: gz },
-            };
+        watchdog.feed();
+
+        if shutdown::requested() {
+            log::info!("Closing connection to the collector for shutdown");
+            close_session(client.as_mut());
+            return Ok(());
+        }
+
+        // Move back to the WebSocket as soon as the network lets us
+        if on_fallback && last_upgrade_attempt.elapsed() >= upgrade_interval {
+            last_upgrade_attempt = Instant::now();
+            match connect_ws(&settings).map_err(anyhow::Error::from).and_then(|mut ws_client| {
+                send_hello(ws_client.as_mut(), &device_id)?;
+                with_extra_collectors(ws_client, &settings, &device_id)
+            }) {
+                Ok(ws_client) => {
+                    log::info!("WebSocket reachable again, leaving the HTTP fallback");
+                    client = ws_client;
+                    on_fallback = false;
+                },
+                Err(e) => log::info!("WebSocket still unavailable: {}", e),
+            }
+        }
+
+        if wifi_disconnected.load(Ordering::Relaxed) {
+            log::warn!("WiFi connection lost");
+            connect_wifi(&watchdog, false)?;
+            connection_lost = true;
+        }
+
+        // Don't touch the ringbuffer while disconnected, samples drained now
+        // would have nowhere to go
+        if connection_lost || !client.is_connected() {
+            log::warn!("Connection to the collector lost");
+            status::set(status::State::WifiUp);
+            reconnect(client.as_mut(), &device_id, &watchdog)?;
+            status::set(status::State::Streaming);
+            connection_lost = false;
+        }
+
+        // Handle whatever the server sent since the last iteration, this also
+        // answers pings so the server doesn't time us out
+        match client.poll() {
+            Ok(messages) => messages.into_iter()
+                .for_each(|message| handle_message(message, client.as_mut(), &watchdog)),
+            Err(e) => {
+                log::warn!("Failed to receive from the collector: {}", e);
+                connection_lost = true;
+                continue;
+            },
+        }
+
+        if last_status.elapsed() >= status_interval {
+            last_status = Instant::now();
+            telemetry::record_stack_high_water(telemetry::Task::Networking);
+            telemetry::set_read_buffer(client.read_buffer());
 
-            match buffer_mutex.lock() {
-                Ok(mut buffer) => buffer.push(sample),
-                Err(e) => log::error!("Error locking ringbuffer mutex: {}", e),
+            let synced = sntp.as_ref().is_some_and(|sntp| sntp.get_sync_status() == SyncStatus::Completed);
+            if synced && !time_synced {
+                log::info!("Clock synced over NTP");
             }
+            time_synced |= synced;
+            send_status(client.as_mut(), &device_id, seq, time_synced);
+
+            // Lower than before after a reset by the stats command
+            let dropped = telemetry::dropped_samples();
+            if dropped < dropped_samples {
+                dropped_samples = 0;
+            }
+            if dropped != dropped_samples {
+                log::warn!("Ringbuffer overflowed, {} samples dropped ({} in total)", dropped - dropped_samples, dropped);
+                dropped_samples = dropped;
+            }
+        }
+
+        if let Some(result) = linktest::expired() {
+            send_linktest_result(client.as_mut(), &result);
+        }
+
+        if last_rssi.elapsed() >= RSSI_INTERVAL {
+            last_rssi = Instant::now();
+            wifi::sample_rssi();
+        }
+
+        if last_diagnostics.elapsed() >= DIAGNOSTICS_INTERVAL {
+            last_diagnostics = Instant::now();
+            log::info!("{:?}", telemetry::diagnostics());
+            let (frames, bytes) = telemetry::frame_totals();
+            let (interval_frames, interval_bytes) = (frames.wrapping_sub(last_frame_totals.0), bytes.wrapping_sub(last_frame_totals.1));
+            if interval_frames > 0 {
+                log::info!(
+                    "Sent {:.1} data frames/s of {} bytes on average",
+                    interval_frames as f32 / DIAGNOSTICS_INTERVAL.as_secs_f32(),
+                    interval_bytes / interval_frames,
+                );
+            }
+            last_frame_totals = (frames, bytes);
+        }
+
+        if DUMP_REQUESTED.swap(false, Ordering::Relaxed) {
+            if let Err(e) = send_dump(client.as_mut(), snapshot(&data_buffer)) {
+                log::warn!("Failed to send ringbuffer dump: {}", e);
+                connection_lost = true;
+                continue;
+            }
+        }
+
+        // A flush doesn't wait for anything, not even the transport's
+        // minimum interval
+        let flush = FLUSH_REQUESTED.swap(false, Ordering::Relaxed);
+        let wait = match flush {
+            true => Duration::ZERO,
+            false => send_wait(client.send_interval(), last_send, &data_buffer),
+        };
+        if !wait.is_zero() {
+            std::thread::sleep(wait);
+            continue;
+        }
+        last_send = Instant::now();
+
+        // Whatever failed to go out before the connection dropped goes
+        // first, so the collector gets the samples in order. Failures are
+        // logged by the queue.
+        let result = send_queue.flush(client.as_mut(), &mut seq);
+        discard_sent(&mut send_queue, &data_buffer);
+        if result.is_err() {
+            connection_lost = true;
+            // Picked up again once reconnected
+            FLUSH_REQUESTED.fetch_or(flush, Ordering::Relaxed);
+            continue;
+        }
+
+        let (mut index, samples) = samples_from(&data_buffer, next_sample, if flush { usize::MAX } else { samples_per_send() });
+        let mut flushed = telemetry::Flushed { samples: 0, last_time: None };
+
+        // A batch too large for one message goes out as several. The
+        // ringbuffer keeps the samples until their frame was sent.
+        let format = batch_format();
+        let max_len = format.max_len(client.max_payload_len());
+        for data in batch::split(samples, max_len, format.encoding) {
+            let last_index = index.wrapping_add(data.samples.len() as u32).wrapping_sub(1);
+            index = index.wrapping_add(data.samples.len() as u32);
+            match format.encode(&data, max_len) {
+                Ok(message) => {
+                    let result = send_queue.send(queue::Frame::new(message, format.is_text(), last_index), client.as_mut(), &mut seq);
+                    discard_sent(&mut send_queue, &data_buffer);
+                    if result.is_err() {
+                        connection_lost = true;
+                        break;
+                    }
+                    flushed.samples += data.samples.len();
+                    flushed.last_time = data.samples.last().map(|sample| sample.time).or(flushed.last_time);
+                },
+                Err(e) => {
+                    let skipped = telemetry::record_skipped_batch();
+                    log::error!("Skipping batch of {} samples ({} skipped so far): {}", data.samples.len(), skipped, e);
+                    // Otherwise they go with the next frame that's sent
+                    if send_queue.is_empty() {
+                        discard_through(&data_buffer, last_index);
+                    }
+                },
+            }
+
+            // Skipped ones too, they went out as far as the ringbuffer is
+            // concerned
+            next_sample = Some(index);
+        }
+
+        if !connection_lost {
+            let result = send_queue.flush(client.as_mut(), &mut seq);
+            discard_sent(&mut send_queue, &data_buffer);
+            connection_lost = result.is_err();
         }
 
-        std::thread::sleep(Duration::from_millis(10));
+        // After the batches they were read for
+        if !connection_lost {
+            send_raw_samples(client.as_mut());
+        }
+
+        // Only confirmed once the queue is empty, what's left after a lost
+        // connection is flushed again when it's back
+        if flush {
+            match connection_lost {
+                true => FLUSH_REQUESTED.store(true, Ordering::Relaxed),
+                false => {
+                    log::info!("Flushed {} samples", flushed.samples);
+                    if let Err(e) = flushed.to_json().map_err(anyhow::Error::from).and_then(|json| client.send_text(&json)) {
+                        log::warn!("Failed to send flush confirmation: {}", e);
+                    }
+                },
+            }
+        }
+
+        // Catch up on what was spilled to flash during an outage, one
+        // segment per iteration so live data keeps flowing
+        if !connection_lost && spill::has_pending() {
+            connection_lost = replay_spilled(client.as_mut(), &mut seq).is_none();
+        }
     }
 }
+
+// How long until the next batch is due, zero if it is now: a full batch is
+// waiting or the send interval is up, whichever comes first. The minimum
+// interval of the transport applies either way. While waiting, the time the
+// ringbuffer takes to fill up at the current sample rate caps the sleep, so
+// a full batch doesn't sit there for the rest of the interval.
+fn send_wait<R>(min_interval: Duration, last_send: Instant, data_buffer: &Mutex<R>) -> Duration
+where
+    R: RingBuffer<proto::SensorDataSample>,
+{
+    let elapsed = last_send.elapsed();
+    if elapsed < min_interval {
+        return (min_interval - elapsed).min(MAX_NETWORKING_SLEEP);
+    }
+
+    if CONFIG.coalesce_window_ms > 0 {
+        let window = Duration::from_millis(CONFIG.coalesce_window_ms.into());
+        return window.saturating_sub(elapsed).min(MAX_NETWORKING_SLEEP);
+    }
+
+    let interval = send_interval().max(min_interval);
+    let pending = lock_ringbuffer(data_buffer).len();
+    let missing = (CONFIG.batch_size as usize).saturating_sub(pending);
+    if elapsed >= interval || missing == 0 {
+        return Duration::ZERO;
+    }
+
+    let until_full = telemetry::sample_rate_hz()
+        .filter(|hz| *hz > 0.0)
+        .map(|hz| Duration::from_secs_f32(missing as f32 / hz));
+    (interval - elapsed)
+        .min(until_full.unwrap_or(Duration::MAX))
+        .min(MAX_NETWORKING_SLEEP)
+        // Don't spin on a batch that fills up in a few microseconds
+        .max(Duration::from_millis(1))
+}
+
+// When coalescing, everything collected over the window goes out at once
+fn samples_per_send() -> usize {
+    match CONFIG.coalesce_window_ms {
+        0 => CONFIG.batch_size as usize,
+        _ => usize::MAX,
+    }
+}
+
+// Returns how many samples were sent, or None if sending failed. The
+// segment is kept for another try then.
+fn replay_spilled(client: &mut dyn transport::Transport, seq: &mut u32) -> Option<usize> {
+    let Some(mut segment) = spill::oldest() else {
+        return Some(0);
+    };
+
+    let samples = std::mem::take(&mut segment.samples);
+    let count = samples.len();
+    let report = telemetry::Replayed {
+        segment: segment.index(),
+        boot_count: segment.boot_count,
+        samples: count,
+        first_time: samples.first().map(|sample| sample.time),
+        last_time: samples.last().map(|sample| sample.time),
+    };
+    let format = batch_format();
+    let max_len = format.max_len(client.max_payload_len());
+    for data in batch::split(samples, max_len, format.encoding) {
+        match format.encode(&data, max_len) {
+            Ok(message) => {
+                if let Err(e) = client.send_batch(&message, format.is_text()) {
+                    log::error!("Failed to send spilled data: {}", e);
+                    return None;
+                }
+                telemetry::record_batch_sent(*seq, &message);
+                *seq = seq.wrapping_add(1);
+            },
+            Err(e) => {
+                let skipped = telemetry::record_skipped_batch();
+                log::error!("Skipping spilled batch of {} samples ({} skipped so far): {}", data.samples.len(), skipped, e);
+            },
+        }
+    }
+
+    log::info!("Sent {} samples spilled to flash", count);
+    // Without the report the collector can't place the samples or acknowledge
+    // them, so the segment gets replayed again
+    if let Err(e) = report.to_json().map_err(anyhow::Error::from).and_then(|json| client.send_text(&json)) {
+        log::error!("Failed to report replayed spill segment {}: {}", report.segment, e);
+        return None;
+    }
+    // The HTTP fallback can't deliver an acknowledgement, waiting for one
+    // would hold up every later segment
+    match CONFIG.spill_require_ack && client.receives() {
+        true => spill::await_ack(segment),
+        false => spill::remove(segment),
+    }
+    Some(count)
+}
+
+// Instead of streaming, brings WiFi up every burst_interval_min, sends all
+// samples collected since and turns the radio off again. The first burst
+// goes out right away, so a broken setup shows up early.
+fn store_and_forward<R>(
+    settings: &settings::Settings,
+    wifi_mutex: &Mutex<EspWifi>,
+    connect_wifi: &dyn Fn(&watchdog::Watchdog, bool) -> Result<()>,
+    device_id: &str,
+    data_buffer: &Mutex<R>,
+    watchdog: &watchdog::Watchdog,
+) -> Result<()>
+where
+    R: RingBuffer<proto::SensorDataSample>,
+{
+    let interval = Duration::from_secs(u64::from(CONFIG.burst_interval_min) * 60);
+    log::info!("Store and forward, sending a burst every {:?}", interval);
+
+    let mut next_burst = Instant::now();
+    let mut events = telemetry::events();
+    // Started with the first WiFi connection and kept, so the clock syncs
+    // whenever the radio is on
+    let mut sntp = None;
+    loop {
+        while Instant::now() < next_burst {
+            watchdog.feed();
+            if shutdown::requested() {
+                return Ok(());
+            }
+            if CONFIG.burst_on_event && telemetry::events() != events {
+                log::info!("Event detected, sending a burst early");
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        events = telemetry::events();
+        // Keep to the schedule, unless a burst took longer than the interval
+        next_burst = (next_burst + interval).max(Instant::now());
+
+        // One round of attempts, an AP that's out of reach now gets another
+        // chance at the next burst rather than keeping the radio on
+        let result = connect_wifi(watchdog, true).and_then(|()| {
+            if sntp.is_none() && !CONFIG.ntp_server.is_empty() {
+                sntp = start_sntp(CONFIG.ntp_server)
+                    .inspect_err(|e| log::warn!("Failed to start SNTP, sample times stay relative: {}", e))
+                    .ok();
+            }
+            send_burst(settings, device_id, data_buffer, watchdog, Instant::now() + interval)
+        });
+
+        let mut wifi = wifi_mutex.lock().map_err(|_| anyhow!("WiFi mutex is poisoned"))?;
+        _ = wifi.disconnect();
+        wifi.stop()?;
+        drop(wifi);
+        status::set(status::State::Storing);
+
+        match result {
+            Ok(flushed) => {
+                log::info!("Burst sent {} samples, next one in {:?}", flushed, next_burst - Instant::now());
+                _ = ota::mark_running_slot_valid()
+                    .inspect_err(|e| log::warn!("Failed to mark firmware as valid: {}", e));
+            },
+            // Whatever wasn't sent stays buffered for the next burst
+            Err(e) if is_permanent(&e) => return Err(e),
+            Err(e) => log::error!("Burst failed, retrying in {:?}: {}", next_burst - Instant::now(), e),
+        }
+    }
+}
+
+// Sends the spilled samples, oldest first, then the ringbuffer, until both
+// are empty or the deadline passed. With spill_require_ack
+// every segment is acknowledged before the next one is replayed. Returns
+// the number of samples sent.
+fn send_burst<R>(
+    settings: &settings::Settings,
+    device_id: &str,
+    data_buffer: &Mutex<R>,
+    watchdog: &watchdog::Watchdog,
+    deadline: Instant,
+) -> Result<usize>
+where
+    R: RingBuffer<proto::SensorDataSample>,
+{
+    // Handshake retries may wait longer than the watchdog timeout
+    let (mut client, _) = watchdog.unwatched(|| connect_transport(settings, device_id))?;
+    send_hello(client.as_mut(), device_id)?;
+    status::set(status::State::Streaming);
+    spill::retry_unacked();
+
+    let mut flushed = 0;
+    let mut seq = telemetry::batch_total();
+    // Cleared when the collector doesn't acknowledge a segment in time
+    let mut replaying = true;
+    let mut ack_deadline = Instant::now();
+    loop {
+        watchdog.feed();
+        for message in client.poll()? {
+            handle_message(message, client.as_mut(), watchdog);
+        }
+        if Instant::now() >= deadline {
+            log::warn!("Burst took a whole interval, leaving the rest buffered for the next one");
+            break;
+        }
+
+        if replaying && spill::has_pending() {
+            flushed += replay_spilled(client.as_mut(), &mut seq)
+                .ok_or_else(|| anyhow!("Failed to send spilled samples"))?;
+            ack_deadline = Instant::now() + SPILL_ACK_TIMEOUT;
+            continue;
+        }
+        // Otherwise the segment would be sent again with every burst
+        if replaying && spill::awaiting_ack() {
+            if Instant::now() < ack_deadline {
+                std::thread::sleep(SPILL_ACK_POLL_INTERVAL);
+                continue;
+            }
+            log::warn!("Replayed spill segment wasn't acknowledged within {:?}, leaving the rest for the next burst", SPILL_ACK_TIMEOUT);
+            replaying = false;
+        }
+
+        let (mut index, samples) = samples_from(data_buffer, None, CONFIG.batch_size as usize);
+        // Sampling goes on meanwhile, but sending is much faster
+        if samples.is_empty() {
+            break;
+        }
+
+        let format = batch_format();
+        let max_len = format.max_len(client.max_payload_len());
+        for data in batch::split(samples, max_len, format.encoding) {
+            let last_index = index.wrapping_add(data.samples.len() as u32).wrapping_sub(1);
+            index = index.wrapping_add(data.samples.len() as u32);
+            match format.encode(&data, max_len) {
+                Ok(message) => {
+                    client.send_batch(&message, format.is_text())?;
+                    telemetry::record_batch_sent(seq, &message);
+                    seq = seq.wrapping_add(1);
+                    flushed += data.samples.len();
+                },
+                Err(e) => {
+                    let skipped = telemetry::record_skipped_batch();
+                    log::error!("Skipping batch of {} samples ({} skipped so far): {}", data.samples.len(), skipped, e);
+                },
+            }
+            if !data.samples.is_empty() {
+                discard_through(data_buffer, last_index);
+            }
+        }
+    }
+
+    close_session(client.as_mut());
+    Ok(flushed)
+}
+
+// Shared by all reconnection paths: WiFi, the collector connection and
+// restarts of the networking thread
+fn reconnect_backoff() -> backoff::Backoff {
+    backoff::Backoff::new(
+        Duration::from_millis(CONFIG.reconnect_min_delay_ms.into()),
+        Duration::from_millis(CONFIG.reconnect_max_delay_ms.into()),
+        CONFIG.reconnect_jitter_pct,
+    )
+}
+
+// Returns the transport and whether it's the HTTP fallback
+fn connect_transport<'a>(settings: &'a settings::Settings, device_id: &str) -> Result<(Box<dyn transport::Transport + 'a>, bool)> {
+    let (client, on_fallback) = connect_collector(settings, device_id)?;
+    Ok((with_extra_collectors(client, settings, device_id)?, on_fallback))
+}
+
+// Connects to the collector the session is with
+fn connect_collector<'a>(settings: &'a settings::Settings, device_id: &str) -> Result<(Box<dyn transport::Transport + 'a>, bool)> {
+    match CONFIG.transport {
+        "ws" => {
+            let mut attempt = 1;
+            let mut backoff = reconnect_backoff();
+            loop {
+                match connect_ws(settings) {
+                    Ok(client) => return Ok((client, false)),
+                    // Only a failing handshake hints at something in the
+                    // way of the upgrade, anything else is a plain error
+                    Err(e) if is_handshake_failure(&e) && !CONFIG.http_fallback_url.is_empty() => {
+                        log::warn!("WebSocket handshake failed (attempt {}/{}): {}", attempt, CONFIG.ws_handshake_attempts, e);
+                        // A timeout may pass, a rejection comes back the same
+                        if attempt >= CONFIG.ws_handshake_attempts || !e.is_retryable() {
+                            log::warn!("Falling back to HTTP POST to {}", CONFIG.http_fallback_url);
+                            let interval = Duration::from_millis(CONFIG.http_post_interval_ms.into());
+                            return Ok((Box::new(http::HttpTransport::new(CONFIG.http_fallback_url, device_id, interval)), true));
+                        }
+                        attempt += 1;
+                        let delay = backoff.next_delay();
+                        log::info!("Retrying the handshake in {:?}", delay);
+                        std::thread::sleep(delay);
+                    },
+                    Err(e) => return Err(e.into()),
+                }
+            }
+        },
+        "mqtt" => {
+            log::info!("Connecting to {} (topic {})", CONFIG.mqtt_url, CONFIG.mqtt_topic);
+            Ok((Box::new(mqtt::MqttTransport::connect(CONFIG.mqtt_url, device_id, CONFIG.mqtt_topic)?), false))
+        },
+        "udp" => {
+            log::info!("Sending to udp://{}:{}", CONFIG.udp_host, CONFIG.udp_port);
+            Ok((Box::new(udp::UdpTransport::connect(CONFIG.udp_host, CONFIG.udp_port)?), false))
+        },
+        other => bail!("Invalid transport \"{}\" (expected ws, mqtt or udp)", other),
+    }
+}
+
+// Copies the batches sent through client to CONFIG.extra_collectors, if any
+fn with_extra_collectors<'a>(
+    client: Box<dyn transport::Transport + 'a>,
+    settings: &'a settings::Settings,
+    device_id: &str,
+) -> Result<Box<dyn transport::Transport + 'a>> {
+    let endpoints = fanout::parse_endpoints(CONFIG.extra_collectors)?;
+    if endpoints.is_empty() {
+        return Ok(client);
+    }
+
+    // A single DNS lookup and short timeouts, a collector that's down only
+    // holds up the main one for so long
+    let timeout = Duration::from_millis(CONFIG.extra_collector_timeout_ms.into());
+    let timeouts = ws::SocketTimeouts {
+        resolve: Duration::ZERO,
+        connect: timeout,
+        handshake: timeout,
+        ..socket_timeouts()
+    };
+    let device_id = device_id.to_string();
+    let connect: fanout::Connect<'a> = Box::new(move |endpoint: &fanout::Endpoint| -> Result<Box<dyn transport::Transport + 'a>> {
+        let mut client = connect_ws_to(endpoint.host, endpoint.port, endpoint.path, CONFIG.extra_collectors_token, timeouts)?;
+        send_hello(client.as_mut(), &device_id)?;
+        Ok(client)
+    });
+    Ok(Box::new(fanout::FanOut::new(client, endpoints, reconnect_backoff(), connect)))
+}
+
+// Errors that restarting the networking thread won't fix, only a change of
+// configuration will
+fn is_permanent(e: &anyhow::Error) -> bool {
+    e.chain().any(|cause| match cause.downcast_ref::<wifi::WifiError>() {
+        Some(e) => !e.is_retryable(),
+        None => cause.downcast_ref::<ws::WebSocketClientError>().is_some_and(|e| !e.is_retryable()),
+    })
+}
+
+fn is_handshake_failure(e: &ws::WebSocketClientError) -> bool {
+    matches!(
+        e,
+        ws::WebSocketClientError::WebSocketError(_)
+            | ws::WebSocketClientError::HandshakeRejected(_)
+            | ws::WebSocketClientError::HandshakeTimeout
+    )
+}
+
+fn connect_ws(settings: &settings::Settings) -> Result<Box<ws::WebSocketClient<'_, { ws::BUFFER_SIZE }>>, ws::WebSocketClientError> {
+    connect_ws_to(&settings.ws_host, settings.ws_port, &settings.ws_endpoint, &settings.ws_token, socket_timeouts())
+}
+
+fn socket_timeouts() -> ws::SocketTimeouts {
+    ws::SocketTimeouts {
+        resolve: Duration::from_secs(1),
+        connect: Duration::from_millis(CONFIG.ws_connect_timeout_ms.into()),
+        handshake: Duration::from_millis(CONFIG.ws_handshake_timeout_ms.into()),
+        read: Duration::from_millis(CONFIG.ws_read_timeout_ms.into()),
+        write: Duration::from_millis(CONFIG.ws_write_timeout_ms.into()),
+        keepalive: (CONFIG.ws_keepalive_idle_s > 0).then(|| ws::Keepalive {
+            idle: Duration::from_secs(CONFIG.ws_keepalive_idle_s.into()),
+            interval: Duration::from_secs(CONFIG.ws_keepalive_interval_s.into()),
+            count: CONFIG.ws_keepalive_count,
+        }),
+    }
+}
+
+fn connect_ws_to<'a>(host: &'a str, port: u16, endpoint: &'a str, token: &str, timeouts: ws::SocketTimeouts) -> Result<Box<ws::WebSocketClient<'a, { ws::BUFFER_SIZE }>>, ws::WebSocketClientError> {
+    let scheme = match (CONFIG.ws_tls, CONFIG.ws_tls_insecure) {
+        (false, _) => ws::Scheme::Ws,
+        (true, false) => ws::Scheme::Wss(ws::CertVerification::Bundle),
+        (true, true) => ws::Scheme::Wss(ws::CertVerification::Insecure),
+    };
+    log::info!("Connecting to {}:{}{} ({:?})", host, port, endpoint, scheme);
+
+    let mut client = Box::new(ws::WebSocketClient::<{ ws::BUFFER_SIZE }>::new());
+    client.set_handshake(ws::Handshake {
+        headers: match token {
+            "" => Vec::new(),
+            token => vec![format!("Authorization: Bearer {}", token)],
+        },
+        sub_protocol: (!CONFIG.ws_subprotocol.is_empty()).then(|| CONFIG.ws_subprotocol.to_string()),
+    });
+    client.connect(host, port, endpoint, scheme, timeouts)?;
+    Ok(client)
+}
+
+fn handle_message(message: transport::Message, client: &mut dyn transport::Transport, watchdog: &watchdog::Watchdog) {
+    log::debug!("Received {:?}", message);
+
+    let transport::Message::Text(text) = message else {
+        return;
+    };
+    if let Some(result) = linktest::answer(&text) {
+        send_linktest_result(client, &result);
+        return;
+    }
+    let command = match command::Command::parse(&text) {
+        Ok(command) => command,
+        Err(e) => {
+            log::debug!("Not a command: {}", e);
+            return;
+        },
+    };
+
+    let cmd = command.name();
+    match run_command(command, watchdog) {
+        Ok(None) => (),
+        Ok(Some(reply)) => _ = client.send_text(&reply)
+            .inspect_err(|e| log::warn!("Failed to send reply to {}: {}", cmd, e)),
+        Err(e) => {
+            log::error!("Command {} failed: {}", cmd, e);
+
+            // Losing the reply isn't worth more than the log line, a broken
+            // connection shows up on the next poll
+            let reply = telemetry::CommandError { cmd, error: e.to_string() };
+            match reply.to_json() {
+                Ok(json) => _ = client.send_text(&json)
+                    .inspect_err(|e| log::warn!("Failed to send command error: {}", e)),
+                Err(e) => log::error!("Failed to serialize command error: {}", e),
+            }
+        },
+    }
+}
+
+// Returns the reply for commands that have one
+fn run_command(command: command::Command, watchdog: &watchdog::Watchdog) -> Result<Option<String>> {
+    match command {
+        command::Command::CalibrateMag { duration_s } => {
+            calibrate::request_mag_calibration(Duration::from_secs(duration_s.into()));
+        },
+        command::Command::Loglevel { level } => set_log_level(&level)?,
+        command::Command::Ota { url, token } => {
+            ota::check_request(&url, &token, CONFIG.ota_token, CONFIG.ota_url_prefixes)?;
+            watchdog.unwatched(|| ota::update(&url))
+                .map_err(|e| anyhow!("{}, keeping current firmware", e))?;
+        },
+        command::Command::Rate { hz } => {
+            if !(MIN_SAMPLE_RATE_HZ..=sensor::MAX_ODR_HZ).contains(&hz) {
+                bail!("Sample rate {} Hz is outside of {}-{} Hz", hz, MIN_SAMPLE_RATE_HZ, sensor::MAX_ODR_HZ);
+            }
+            log::info!("Sample rate of {} Hz requested", hz);
+            set_rate_override(hz);
+        },
+        command::Command::SendInterval { ms } => {
+            if !SEND_INTERVAL_RANGE_MS.contains(&ms) {
+                bail!("Send interval {} ms is outside of {}-{} ms", ms, SEND_INTERVAL_RANGE_MS.start(), SEND_INTERVAL_RANGE_MS.end());
+            }
+            log::info!("Sending at least every {} ms", ms);
+            SEND_INTERVAL_MS.store(ms, Ordering::Relaxed);
+        },
+        command::Command::Version => {
+            let version = telemetry::Version { firmware_version: FIRMWARE_VERSION };
+            return Ok(Some(version.to_json()?));
+        },
+        command::Command::Raw { enabled } => {
+            if enabled && CONFIG.sampling_mode == "fifo" {
+                bail!("Raw output needs sampling_mode poll");
+            }
+            log::info!("Raw sensor output {}", if enabled { "on" } else { "off" });
+            RAW_DIAGNOSTICS.store(enabled, Ordering::Relaxed);
+        },
+        command::Command::Flush => {
+            // A burst sends everything anyway, and there's no loop in
+            // between bursts to pick the request up
+            if CONFIG.burst_interval_min > 0 {
+                bail!("Flushing needs continuous streaming, bursts send everything buffered already");
+            }
+            FLUSH_REQUESTED.store(true, Ordering::Relaxed);
+        },
+        // The marker is the reply, whatever comes back is checked in
+        // handle_message()
+        command::Command::Linktest => {
+            if CONFIG.burst_interval_min > 0 {
+                bail!("The link test needs continuous streaming");
+            }
+            return Ok(Some(linktest::start()?));
+        },
+        command::Command::Dump => {
+            if CONFIG.burst_interval_min > 0 {
+                bail!("Dumping the ringbuffer needs continuous streaming");
+            }
+            DUMP_REQUESTED.store(true, Ordering::Relaxed);
+        },
+        command::Command::SpillAck { segment } => {
+            if !spill::ack(segment) {
+                bail!("Spill segment {} isn't awaiting acknowledgement", segment);
+            }
+            log::info!("Collector acknowledged spill segment {}", segment);
+        },
+        command::Command::Maintenance { enabled } => {
+            log::info!("Maintenance mode {}, sampling {}", if enabled { "on" } else { "off" }, if enabled { "paused" } else { "resumed" });
+            status::set_maintenance(enabled);
+        },
+        command::Command::ZeroAngle => {
+            if !CONFIG.gyro_integrate {
+                bail!("Zeroing the angle needs gyro_integrate");
+            }
+            ZERO_ANGLE_REQUESTED.store(true, Ordering::Relaxed);
+        },
+        command::Command::Stats { reset } => {
+            let stats = telemetry::Stats::take(reset);
+            if reset {
+                log::info!("Counters reset");
+            }
+            return Ok(Some(stats.to_json()?));
+        },
+    }
+
+    Ok(None)
+}
+
+fn send_linktest_result(client: &mut dyn transport::Transport, result: &telemetry::LinktestResult) {
+    if let Err(e) = result.to_json().map_err(anyhow::Error::from).and_then(|json| client.send_text(&json)) {
+        log::warn!("Failed to send link test result: {}", e);
+    }
+}
+
+// Log targets are module paths, and ESP-IDF keeps the level per exact tag
+fn set_log_level(level: &str) -> Result<()> {
+    let filter = level.parse::<log::LevelFilter>()
+        .or_else(|_| bail!("Invalid log level \"{}\"", level))?;
+
+    let crate_name = module_path!();
+    for module in LOG_MODULES {
+        let target = match *module {
+            "" => crate_name.to_string(),
+            module => format!("{}::{}", crate_name, module),
+        };
+        esp_idf_svc::log::EspLogger.set_target_level(&target, filter)?;
+    }
+    log::info!("Log level set to {}", filter);
+
+    Ok(())
+}
+
+// ESP-IDF takes the FreeRTOS priority of a new thread from the spawning
+// thread's spawn configuration rather than the Builder, so it's set just
+// around the spawn. A stack size of 0 leaves it to ESP-IDF as well.
+fn spawn_thread<F, T>(name: &str, stack_size: u32, priority: u8, f: F) -> std::io::Result<std::thread::JoinHandle<T>>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let conf = ThreadSpawnConfiguration { priority, ..Default::default() };
+    if let Err(e) = conf.set() {
+        log::warn!("Failed to set the priority of the {} to {}: {}", name, priority, e);
+    }
+
+    let mut builder = std::thread::Builder::new().name(name.into());
+    if stack_size > 0 {
+        builder = builder.stack_size(stack_size as usize);
+    }
+    let result = builder.spawn(f);
+
+    _ = ThreadSpawnConfiguration::default().set()
+        .inspect_err(|e| log::warn!("Failed to reset the thread spawn configuration: {}", e));
+    result
+}
+
+fn start_sntp(server: &'static str) -> Result<EspSntp<'static>> {
+    let mut conf = SntpConf::default();
+    conf.servers[0] = server;
+    log::info!("Syncing clock with {}", server);
+    Ok(EspSntp::new_with_callback(&conf, clock::on_sync)?)
+}
+
+// Counts this boot and continues the batch total where the last save left
+// it. Batches sent after that save are lost in the reboot, so the total
+// skips a whole save interval ahead rather than repeat any of them.
+fn restore_counters(nvs: &EspDefaultNvsPartition) {
+    let result = settings::SettingsStore::new(nvs.clone()).and_then(|mut store| {
+        let boot_count = store.load_u32(settings::BOOT_COUNT)?.unwrap_or(0).wrapping_add(1);
+        store.save_u32(settings::BOOT_COUNT, boot_count)?;
+        let batch_total = match CONFIG.batch_total_save_interval {
+            0 => 0,
+            interval => store.load_u32(settings::BATCH_TOTAL)?.map_or(0, |saved| saved.wrapping_add(interval)),
+        };
+        // Another reboot before the next save must not go back to the same
+        // numbers
+        if CONFIG.batch_total_save_interval > 0 {
+            store.save_u32(settings::BATCH_TOTAL, batch_total)?;
+        }
+        Ok((boot_count, batch_total))
+    });
+
+    match result {
+        Ok((boot_count, batch_total)) => {
+            log::info!("Boot {}, batch total continues at {}", boot_count, batch_total);
+            telemetry::restore_counters(boot_count, batch_total);
+        },
+        Err(e) => log::warn!("Failed to restore the boot count and batch total, starting from zero: {}", e),
+    }
+}
+
+// Writes the batch total once it moved on by the save interval since the
+// last write
+fn save_batch_total(store: &mut settings::SettingsStore, saved: &mut u32) {
+    let total = telemetry::batch_total();
+    if CONFIG.batch_total_save_interval == 0 || total.wrapping_sub(*saved) < CONFIG.batch_total_save_interval {
+        return;
+    }
+    match store.save_u32(settings::BATCH_TOTAL, total) {
+        Ok(()) => *saved = total,
+        Err(e) => log::warn!("Failed to save the batch total: {}", e),
+    }
+}
+
+// Written from here rather than by the command, so the networking thread
+// never waits for the flash
+fn save_maintenance(store: &mut settings::SettingsStore, saved: &mut bool) {
+    let enabled = status::maintenance();
+    if enabled == *saved {
+        return;
+    }
+    match store.save_maintenance(enabled) {
+        Ok(()) => *saved = enabled,
+        Err(e) => log::warn!("Failed to save maintenance mode: {}", e),
+    }
+}
+
+fn wifi_enterprise() -> Option<wifi::Enterprise> {
+    wifi::Enterprise::new(CONFIG.wifi_eap_identity, CONFIG.wifi_eap_username, CONFIG.wifi_eap_password, CONFIG.wifi_eap_ca_cert)
+}
+
+fn device_id() -> Result<String> {
+    match CONFIG.device_id {
+        "" => mac_device_id(),
+        id => Ok(id.to_string()),
+    }
+}
+
+// Unique per board, unlike anything in the compiled configuration. Read from
+// eFuse, so it's available before WiFi is up for the log prefix.
+fn mac_device_id() -> Result<String> {
+    let mut mac = [0u8; 6];
+    esp!(unsafe { sys::esp_read_mac(mac.as_mut_ptr(), sys::esp_mac_type_t_ESP_MAC_WIFI_STA) })?;
+
+    Ok(format!("hecate-{}", mac.iter().map(|b| format!("{:02x}", b)).collect::<String>()))
+}
+
+fn send_hello(client: &mut dyn transport::Transport, device_id: &str) -> Result<()> {
+
+    let hello = telemetry::Hello {
+        firmware_version: FIRMWARE_VERSION,
+        device_id,
+        sampling_mode: CONFIG.sampling_mode,
+        sample_rate_hz: telemetry::sample_rate_hz(),
+        accel_range_g: CONFIG.accel_range_g,
+        gyro_range_dps: CONFIG.gyro_range_dps,
+        mag_range_gauss: CONFIG.mag_range_gauss,
+        batch_checksum: CONFIG.batch_checksum,
+        sample_format: CONFIG.sample_format,
+        boot_count: telemetry::boot_count(),
+        seq: telemetry::batch_total(),
+    };
+
+    client.send_text(&hello.to_json()?)?;
+    if CONFIG.session_digest {
+        telemetry::start_session_digest(hello.seq);
+    }
+    Ok(())
+}
+
+// With session_digest the collector gets the digest of everything sent in
+// the session first. A session that ends by losing the connection has
+// none, the next hello starts a new one.
+fn close_session(client: &mut dyn transport::Transport) {
+    if let Some(digest) = telemetry::take_session_digest() {
+        let end = telemetry::SessionEnd { first_seq: digest.first_seq, batches: digest.batches, crc32: digest.value() };
+        if let Err(e) = end.to_json().map_err(anyhow::Error::from).and_then(|json| client.send_text(&json)) {
+            log::warn!("Failed to send the session digest: {}", e);
+        }
+    }
+    _ = client.close()
+        .inspect_err(|e| log::warn!("Failed to close connection: {}", e));
+}
+
+fn send_raw_samples(client: &mut dyn transport::Transport) {
+    for raw in telemetry::take_raw_samples() {
+        if let Err(e) = raw.to_json().map_err(anyhow::Error::from).and_then(|json| client.send_text(&json)) {
+            log::warn!("Failed to send raw sample: {}", e);
+            return;
+        }
+    }
+}
+
+fn send_status(client: &mut dyn transport::Transport, device_id: &str, seq: u32, time_synced: bool) {
+
+    let status = telemetry::Status {
+        device_id,
+        uptime_s: telemetry::uptime().as_secs() as u32,
+        free_heap: telemetry::free_heap(),
+        seq,
+        batch_total: telemetry::batch_total(),
+        rssi: wifi::rssi(),
+        rssi_smoothed: wifi::smoothed_rssi(),
+        link_quality: wifi::link_quality().map(wifi::LinkQuality::name),
+        temperature: telemetry::temperature(),
+        battery_mv: telemetry::battery_mv(),
+        dropped_samples: telemetry::dropped_samples(),
+        skipped_batches: telemetry::skipped_batches(),
+        maintenance: status::maintenance(),
+        clipped_samples: telemetry::clipped_samples(),
+        clipped_axes: Some(telemetry::take_clipped_axes()).filter(|axes| *axes != 0),
+        events: (CONFIG.accel_event_threshold_mg > 0).then(telemetry::events),
+        gyro_angle_deg: telemetry::gyro_angle(),
+        time_synced,
+        time_origin_us: time_synced.then(telemetry::time_origin_utc).flatten(),
+        clock_drift_ppm: clock::drift_ppm(),
+        diagnostics: CONFIG.status_diagnostics.then(telemetry::diagnostics),
+    };
+
+    match status.to_json() {
+        Ok(json) => {
+            _ = client.send_text(&json)
+                .inspect_err(|e| log::warn!("Failed to send status: {}", e));
+        },
+        Err(e) => log::error!("Failed to encode status: {}", e),
+    }
+}
+
+// Copies the buffered samples, oldest first, leaving them for sending
+fn snapshot<R>(data_buffer: &Mutex<R>) -> Vec<SensorDataSample>
+where
+    R: RingBuffer<proto::SensorDataSample>,
+{
+    lock_ringbuffer(data_buffer).iter().cloned().collect()
+}
+
+// The newest post_mortem_samples of a snapshot, for seeing what the sensor
+// read up to a fatal error
+fn log_post_mortem(samples: &[SensorDataSample]) {
+    let count = samples.len().min(CONFIG.post_mortem_samples as usize);
+    if count == 0 {
+        return;
+    }
+
+    log::error!("Last {} of {} buffered samples:", count, samples.len());
+    for sample in &samples[samples.len() - count..] {
+        let (acc, gyro, mag) = (&sample.acceleration, &sample.gyroscope, &sample.magnetometer);
+        log::error!(
+            "  {:.3} s: acc {:.3} {:.3} {:.3} g, gyro {:.2} {:.2} {:.2} dps, mag {:.3} {:.3} {:.3} gauss",
+            sample.time, acc.x, acc.y, acc.z, gyro.x, gyro.y, gyro.z, mag.x, mag.y, mag.z,
+        );
+    }
+}
+
+// The whole snapshot in dump_data text messages, see batch::encode_dump(),
+// straight to the transport so they don't count as batches or take part in
+// the seq. Errors are the transport's, a snapshot that can't be encoded
+// is only logged.
+fn send_dump(client: &mut dyn transport::Transport, samples: Vec<SensorDataSample>) -> Result<()> {
+    let max_len = client.max_payload_len();
+    let mut dump = telemetry::Dump {
+        samples: samples.len(),
+        frames: 0,
+        first_time: samples.first().map(|sample| sample.time),
+        last_time: samples.last().map(|sample| sample.time),
+    };
+    let encoded = batch::split(samples, max_len, batch::Encoding::Json).iter()
+        .map(|data| batch::encode_dump(data, max_len).and_then(|frame| Ok(String::from_utf8(frame)?)))
+        .collect::<Result<Vec<_>>>()
+        .and_then(|frames| {
+            dump.frames = frames.len();
+            Ok((dump.to_json()?, frames))
+        });
+    let (announcement, frames) = match encoded {
+        Ok(encoded) => encoded,
+        Err(e) => {
+            log::warn!("Not sending the ringbuffer dump, encoding failed: {}", e);
+            return Ok(());
+        },
+    };
+
+    client.send_text(&announcement)?;
+    for frame in &frames {
+        client.send_text(frame)?;
+    }
+    log::info!("Dumped {} samples in {} frames", dump.samples, frames.len());
+    Ok(())
+}
+
+// Index of the oldest buffered sample, see SAMPLES_PUSHED
+fn oldest_index<R>(buffer: &R) -> u32
+where
+    R: RingBuffer<proto::SensorDataSample>,
+{
+    SAMPLES_PUSHED.load(Ordering::Relaxed).wrapping_sub(buffer.len() as u32)
+}
+
+// How far index is past the oldest buffered sample, negative once the
+// sampling thread overwrote it
+fn offset_from_oldest(index: u32, oldest: u32) -> i32 {
+    index.wrapping_sub(oldest) as i32
+}
+
+// Copies up to limit buffered samples, oldest first, starting at index or
+// with the oldest one. Returns the index of the first sample copied.
+fn samples_from<R>(data_buffer: &Mutex<R>, index: Option<u32>, limit: usize) -> (u32, Vec<SensorDataSample>)
+where
+    R: RingBuffer<proto::SensorDataSample>,
+{
+    let buffer = lock_ringbuffer(data_buffer);
+    let oldest = oldest_index(&*buffer);
+    let skip = index.map_or(0, |index| offset_from_oldest(index, oldest).max(0) as u32);
+    let samples = buffer.iter().skip(skip as usize).take(limit).cloned().collect();
+    (oldest.wrapping_add(skip), samples)
+}
+
+// Drops what the send queue wrote from the ringbuffer
+fn discard_sent<R>(send_queue: &mut queue::SendQueue, data_buffer: &Mutex<R>)
+where
+    R: RingBuffer<proto::SensorDataSample>,
+{
+    if let Some(index) = send_queue.take_sent_through() {
+        discard_through(data_buffer, index);
+    }
+}
+
+// Removes the samples up to and including index from the front of the
+// buffer. Going by index rather than count stays correct even if the
+// sampling thread overwrote some of the oldest samples in the meantime.
+fn discard_through<R>(data_buffer: &Mutex<R>, index: u32)
+where
+    R: RingBuffer<proto::SensorDataSample>,
+{
+    let mut buffer = lock_ringbuffer(data_buffer);
+    let count = offset_from_oldest(index, oldest_index(&*buffer)).saturating_add(1).max(0) as usize;
+    for _ in 0..count.min(buffer.len()) {
+        buffer.dequeue();
+    }
+}
+
+fn reconnect(client: &mut dyn transport::Transport, device_id: &str, watchdog: &watchdog::Watchdog) -> Result<()> {
+
+    let mut backoff = reconnect_backoff();
+    for attempt in 1..=WS_RECONNECT_ATTEMPTS {
+        watchdog.feed();
+        log::info!("Reconnecting (attempt {}/{})", attempt, WS_RECONNECT_ATTEMPTS);
+
+        match client.reconnect().and_then(|_| send_hello(client, device_id)) {
+            Ok(()) => {
+                log::info!("Reconnected");
+                telemetry::record_reconnect();
+                spill::retry_unacked();
+                return Ok(());
+            },
+            Err(e) if is_permanent(&e) => return Err(e),
+            Err(e) => log::warn!("Reconnect attempt failed: {}", e),
+        }
+
+        if attempt < WS_RECONNECT_ATTEMPTS {
+            let delay = backoff.next_delay();
+            log::info!("Next reconnect attempt in {:?}", delay);
+            watchdog.unwatched(|| std::thread::sleep(delay));
+        }
+    }
+
+    bail!("Reconnect failed after {} attempts", WS_RECONNECT_ATTEMPTS)
+}
+
+// How batches are encoded, from the configuration validate() checked
+fn batch_format() -> batch::Format {
+    batch::Format {
+        encoding: batch::Encoding::parse(CONFIG.sample_format).expect("Invalid sample format"),
+        checksum: CONFIG.batch_checksum,
+    }
+}
+
+// Blink patterns as (LED on, duration in ms) steps, repeated
+fn blink_pattern(state: status::State) -> &'static [(bool, u64)] {
+    match state {
+        // WiFi down, also on purpose between bursts
+        status::State::Booting | status::State::ConnectingWifi | status::State::Storing => &[(false, 200)],
+        // WebSocket down, fast blink
+        status::State::WifiUp => &[(true, 100), (false, 100)],
+        status::State::Streaming => &[(true, 200)],
+        // Connected but no samples, short blips
+        status::State::Stalled => &[(true, 100), (false, 900)],
+        // Very slow blink, idle on purpose
+        status::State::Maintenance => &[(true, 1000), (false, 1000)],
+        // Mostly on, waiting for someone to configure WiFi
+        status::State::Provisioning => &[(true, 900), (false, 100)],
+        // Double blink
+        status::State::SensorError => &[(true, 100), (false, 100), (true, 100), (false, 700)],
+        // Triple blink
+        status::State::CredentialsError => &[(true, 100), (false, 100), (true, 100), (false, 100), (true, 100), (false, 700)],
+        // Slow blink
+        status::State::Error => &[(true, 500), (false, 500)],
+        // Fast SOS
+        status::State::Panic => &[
+            (true, 100), (false, 100), (true, 100), (false, 100), (true, 100), (false, 300),
+            (true, 300), (false, 100), (true, 300), (false, 100), (true, 300), (false, 300),
+            (true, 100), (false, 100), (true, 100), (false, 100), (true, 100), (false, 700),
+        ],
+    }
+}
+
+fn status_indicator<P>(mut led: PinDriver<P, Output>) -> Result<()>
+where
+    P: OutputPin {
+
+    loop {
+
+        // Very fast blinking while an update is being flashed. A low battery
+        // replaces the solid light of the healthy state with short blips.
+        let pattern: &[(bool, u64)] = match (ota::in_progress(), status::get()) {
+            (true, _) => &[(true, 50), (false, 50)],
+            (false, status::State::Streaming) if status::low_battery() => &[(true, 50), (false, 1950)],
+            (false, state) => blink_pattern(state),
+        };
+
+        for &(on, ms) in pattern {
+            _ = led.set_level(on.into());
+            std::thread::sleep(Duration::from_millis(ms));
+        }
+    }
+}
+
+fn battery_monitor(mut battery: battery::Battery) -> Result<()> {
+    loop {
+        match battery.read_mv() {
+            Ok(battery_mv) => {
+                telemetry::set_battery_mv(Some(battery_mv));
+                let low = CONFIG.battery_low_mv > 0 && battery_mv < CONFIG.battery_low_mv;
+                if low && !status::low_battery() {
+                    log::warn!("Battery low ({} mV)", battery_mv);
+                }
+                status::set_low_battery(low);
+            },
+            Err(e) => {
+                log::warn!("Failed to read battery voltage: {}", e);
+                telemetry::set_battery_mv(None);
+            },
+        }
+
+        std::thread::sleep(BATTERY_INTERVAL);
+    }
+}
+
+// Colors at full brightness, scaled down by brightness. At 0 the pixel
+// only gets switched off once, in case it still shows something from before
+// a reset.
+fn status_light_thread(mut light: neopixel::Neopixel, brightness: u8) -> Result<()> {
+    const MAX: u8 = u8::MAX;
+
+    let mut shown = None;
+    loop {
+        let color = if ota::in_progress() {
+            neopixel::Rgb(MAX, 0, MAX).scaled(brightness)
+        } else {
+            match status::get() {
+                status::State::Booting => neopixel::Rgb(0, 0, MAX).scaled(brightness),
+                status::State::ConnectingWifi => neopixel::Rgb(MAX, MAX, 0).scaled(brightness),
+                status::State::WifiUp => neopixel::Rgb(0, MAX, 0).scaled(brightness),
+                status::State::Streaming if CONFIG.neopixel_link_quality => match wifi::link_quality() {
+                    Some(wifi::LinkQuality::Excellent) | None => neopixel::Rgb(0, MAX, 0),
+                    Some(wifi::LinkQuality::Good) => neopixel::Rgb(MAX / 3, MAX, 0),
+                    Some(wifi::LinkQuality::Fair) => neopixel::Rgb(MAX / 3 * 2, MAX, 0),
+                    Some(wifi::LinkQuality::Poor) => neopixel::Rgb(MAX, MAX, 0),
+                }.scaled(brightness),
+                status::State::Streaming => neopixel::Rgb(0, MAX, MAX).scaled(brightness),
+                status::State::Stalled => neopixel::Rgb(MAX, 0, MAX / 2).scaled(brightness),
+                status::State::Maintenance => neopixel::Rgb(0, MAX / 3, MAX).scaled(brightness),
+                // Dark to save power
+                status::State::Storing => neopixel::Rgb::OFF,
+                status::State::Provisioning => neopixel::Rgb(MAX, MAX, MAX).scaled(brightness),
+                status::State::SensorError | status::State::Error => neopixel::Rgb(MAX, 0, 0).scaled(brightness),
+                status::State::CredentialsError => neopixel::Rgb(MAX, MAX / 4, 0).scaled(brightness),
+                // Full brightness is the point here, unless it's to stay dark
+                status::State::Panic if brightness == 0 => neopixel::Rgb::OFF,
+                status::State::Panic => neopixel::Rgb(MAX, 0, 0),
+            }
+        };
+
+        if shown != Some(color) {
+            light.set(color)?;
+            shown = Some(color);
+        }
+
+        std::thread::sleep(Duration::from_millis(100));
+    }
+}
+
+fn sensor_sampling_thread<I, R>(
+    mut sensor: imu::Lsm9ds1<I>,
+    mode: sensor::SamplingMode,
+    mut calibration: sensor::Calibration,
+    overflow: OverflowPolicy,
+    store: Option<settings::SettingsStore>,
+    buffer_mutex: Arc<Mutex<R>>,
+) -> Result<()>
+where
+    I: lsm9ds1::interface::Interface,
+    I::Error: std::fmt::Debug,
+    R: RingBuffer<SensorDataSample>,
+{
+    let sensors = enabled_sensors();
+    if CONFIG.gyro_auto_zero && sensors.gyro {
+        log::info!("Calibrating gyroscope, keep the board still");
+        match calibrate::gyro_bias(&mut sensor, CONFIG.gyro_zero_samples, GYRO_ZERO_INTERVAL, CONFIG.gyro_zero_max_stddev_dps)? {
+            Some(bias) => {
+                log::info!("Gyroscope offset is {:?} dps", bias);
+                calibration.gyro.offset = bias;
+            },
+            None => log::info!("Keeping gyroscope offset {:?}", calibration.gyro.offset),
+        }
+    }
+
+    let mag_calibrator = calibrate::MagCalibrator::new(store, Duration::from_secs(CONFIG.mag_calibrate_on_boot_s.into()));
+    if let Some(offset) = mag_calibrator.stored_offset() {
+        log::info!("Using stored magnetometer offset {:?} gauss", offset);
+        calibration.mag.offset = offset;
+    }
+
+    // Keeps the samples' fields of disabled sensors at zero
+    if !sensors.accel {
+        calibration.accel = sensor::AxisCalibration::IDENTITY;
+    }
+    if !sensors.gyro {
+        calibration.gyro = sensor::AxisCalibration::IDENTITY;
+    }
+    if !sensors.mag {
+        calibration.mag = sensor::AxisCalibration::IDENTITY;
+    }
+
+    match mode {
+        sensor::SamplingMode::Polling => sample_polling(sensor, calibration, overflow, mag_calibrator, buffer_mutex),
+        sensor::SamplingMode::Fifo => sample_fifo(sensor, calibration, overflow, mag_calibrator, buffer_mutex),
+    }
+}
+
+// Works with any IMU, the FIFO below is specific to the LSM9DS1
+fn sample_polling<S, R>(mut sensor: S, mut calibration: sensor::Calibration, overflow: OverflowPolicy, mut mag_calibrator: calibrate::MagCalibrator, buffer_mutex: Arc<Mutex<R>>) -> Result<()>
+where
+    S: ImuSensor,
+    R: RingBuffer<SensorDataSample>,
+{
+    let timer = EspTimerService::new().expect("Failed to initialize timer service");
+    let start_time = telemetry::init_time_origin(timer.now());
+    let mut interval = Duration::from_millis(CONFIG.sample_interval_ms.into());
+    let mut rate = None;
+    let mut current_interval = interval;
+    let mut next_sample_time = timer.now();
+    let sensors = enabled_sensors();
+    let mut readings = Readings::new(sensors);
+    let mut filters = filters();
+    let mut events = event_detector();
+    let mut deadband = deadband();
+    let mut integrator = integrator();
+    let clip_limits = sensor::ClipLimits::new(CONFIG.accel_range_g, CONFIG.gyro_range_dps, CONFIG.mag_range_gauss);
+    let mut mag_countdown = 0;
+    let mut rate_monitor = RateMonitor::new();
+    let mut jitter_monitor = JitterMonitor::new();
+
+    let mut data_ready = match CONFIG.imu_drdy_gpio {
+        gpio if gpio < 0 => None,
+        gpio => {
+            let data_ready = sensor::DataReady::new(gpio)?;
+            sensor.enable_data_ready_interrupt()?;
+            log::info!("Sampling on data ready interrupt from GPIO{}", gpio);
+            telemetry::set_sample_rate_hz(sensor.odr_hz()?);
+            Some(data_ready)
+        },
+    };
+
+    let mut throttle = match data_ready {
+        None => {
+            telemetry::set_sample_rate_hz(1000.0 / CONFIG.sample_interval_ms as f32);
+            check_odr(sensors);
+            CONFIG.adaptive_sampling.then(|| Throttle::new(interval))
+        },
+        Some(_) => {
+            if CONFIG.adaptive_sampling {
+                log::warn!("adaptive_sampling doesn't apply with imu_drdy_gpio, sampling at the sensor's ODR");
+            }
+            None
+        },
+    };
+
+    let mut last_stack_check = None;
+    let watchdog = watchdog::Watchdog::subscribe()?;
+    loop {
+        watchdog.feed();
+        record_sampling_stack(&mut last_stack_check);
+
+        if shutdown::requested() {
+            return Ok(());
+        }
+
+        if ota::in_progress() || status::maintenance() {
+            std::thread::sleep(OTA_SAMPLING_PAUSE);
+            rate_monitor.reset();
+            jitter_monitor.reset();
+            continue;
+        }
+        rate_monitor.update();
+
+        let requested = rate_override();
+        if requested != rate {
+            rate = requested;
+            jitter_monitor.reset();
+            if let Some(hz) = rate {
+                match &data_ready {
+                    Some(_) => match sensor.set_odr_hz(hz) {
+                        Ok(odr) => {
+                            log::info!("Sensor ODR set to {} Hz", odr);
+                            telemetry::set_sample_rate_hz(odr);
+                        },
+                        Err(e) => log::error!("Failed to set sensor ODR: {}", e),
+                    },
+                    None => {
+                        interval = Duration::from_secs_f32(1.0 / hz);
+                        throttle = CONFIG.adaptive_sampling.then(|| Throttle::new(interval));
+                        log::info!("Sampling every {:?}", interval);
+                        telemetry::set_sample_rate_hz(hz);
+                    },
+                }
+            }
+        }
+
+        // With an interrupt, the sample is timestamped when it fired
+        let drdy_time = match &data_ready {
+            Some(data_ready) => match data_ready.wait(DRDY_TIMEOUT) {
+                Some(time) => Some(time),
+                None => {
+                    log::warn!("No data ready interrupt within {:?}", DRDY_TIMEOUT);
+                    continue;
+                },
+            },
+            None => None,
+        };
+
+        let acc = sensors.accel.then(|| sensor.read_accel());
+        // Optionally timestamp as soon as the primary sensor's transaction
+        // returns, instead of after all three reads
+        let acc_time = CONFIG.timestamp_on_accel_read.then(|| timer.now());
+        let gyro = sensors.gyro.then(|| sensor.read_gyro());
+        let mag_due = mag_countdown == 0;
+        mag_countdown = if mag_due { CONFIG.mag_read_divisor.max(1) - 1 } else { mag_countdown - 1 };
+        let mag = (sensors.mag && mag_due).then(|| sensor.read_mag());
+        if RAW_DIAGNOSTICS.load(Ordering::Relaxed) {
+            record_raw(&mut sensor, sensor::Sensors { mag: sensors.mag && mag_due, ..sensors }, (&acc, &gyro, &mag));
+        }
+        read_temperature(&mut sensor);
+
+        if let Some(mag) = &mag {
+            calibrate_mag(&mut mag_calibrator, mag, &mut calibration);
+        }
+        readings.update_mag(mag);
+        if let Some((acc, gyro, mag)) = readings.update(acc, gyro) {
+            let time = clock::since_origin(drdy_time.or(acc_time).unwrap_or_else(|| timer.now()), start_time);
+            status::record_sample();
+            jitter_monitor.record(time);
+            detect_clipping(&clip_limits, acc, gyro, mag);
+            // Calibrated before filtering, the high-pass would otherwise turn the
+            // offsets into a constant error
+            let (acc, gyro, mag) = calibration.apply((acc, gyro, mag));
+            let (acc, gyro, mag) = filters.apply(time, (acc, gyro, mag));
+            detect_event(&mut events, time, acc);
+            integrate_gyro(&mut integrator, time, gyro);
+            if deadband.as_mut().map_or(true, |deadband| deadband.keep(time, acc, gyro, mag)) {
+                push_sample(&buffer_mutex, make_sample(time, acc, gyro, mag), overflow);
+            } else {
+                telemetry::record_suppressed_sample();
+            }
+            rate_monitor.record(1);
+        }
+
+        if let Some(data_ready) = &mut data_ready {
+            data_ready.rearm()?;
+            continue;
+        }
+
+        let next_interval = match &mut throttle {
+            Some(throttle) => throttle.update(&buffer_mutex),
+            None => interval,
+        };
+        // Stepping to another interval isn't jitter
+        if next_interval != current_interval {
+            current_interval = next_interval;
+            jitter_monitor.reset();
+        }
+
+        // Sleep until the next sample is due, so the time spent reading
+        // doesn't add to the interval. If we fell behind, don't try to catch
+        // up with a burst of samples.
+        next_sample_time += next_interval;
+        let now = timer.now();
+        if next_sample_time > now {
+            std::thread::sleep(next_sample_time - now);
+        } else {
+            next_sample_time = now;
+        }
+    }
+}
+
+fn sample_fifo<I, R>(mut sensor: imu::Lsm9ds1<I>, mut calibration: sensor::Calibration, overflow: OverflowPolicy, mut mag_calibrator: calibrate::MagCalibrator, buffer_mutex: Arc<Mutex<R>>) -> Result<()>
+where
+    I: lsm9ds1::interface::Interface,
+    I::Error: std::fmt::Debug,
+    R: RingBuffer<SensorDataSample>,
+{
+
+    let timer = EspTimerService::new().expect("Failed to initialize timer service");
+    let start_time = telemetry::init_time_origin(timer.now());
+
+    // Samples come out of the FIFO at exactly the sensor's ODR, so they are
+    // timestamped from that instead of by when we got around to reading them
+    let odr = sensor.odr_hz()?;
+    let mut period = Duration::from_secs_f32(1.0 / odr);
+    // Drain at half full to leave headroom for scheduling jitter
+    let mut drain_interval = period * u32::from(sensor::FIFO_DEPTH / 2);
+    let mut rate = None;
+    sensor.registers().enable_fifo()?;
+    log::info!("Sampling from FIFO at {} Hz", odr);
+    telemetry::set_sample_rate_hz(odr);
+
+    let mut next_time = None;
+    let sensors = enabled_sensors();
+    let mut readings = Readings::new(sensors);
+    let mut filters = filters();
+    let mut events = event_detector();
+    let mut deadband = deadband();
+    let mut integrator = integrator();
+    let clip_limits = sensor::ClipLimits::new(CONFIG.accel_range_g, CONFIG.gyro_range_dps, CONFIG.mag_range_gauss);
+    let mut rate_monitor = RateMonitor::new();
+    let mut last_stack_check = None;
+    let watchdog = watchdog::Watchdog::subscribe()?;
+    loop {
+        watchdog.feed();
+        record_sampling_stack(&mut last_stack_check);
+
+        if shutdown::requested() {
+            return Ok(());
+        }
+
+        // The FIFO overruns meanwhile, which resets the time base afterwards
+        if ota::in_progress() || status::maintenance() {
+            std::thread::sleep(OTA_SAMPLING_PAUSE);
+            rate_monitor.reset();
+            continue;
+        }
+        rate_monitor.update();
+
+        let requested = rate_override();
+        if requested != rate {
+            rate = requested;
+            if let Some(hz) = rate {
+                match sensor.set_odr_hz(hz) {
+                    Ok(odr) => {
+                        log::info!("Sampling from FIFO at {} Hz", odr);
+                        telemetry::set_sample_rate_hz(odr);
+                        period = Duration::from_secs_f32(1.0 / odr);
+                        drain_interval = period * u32::from(sensor::FIFO_DEPTH / 2);
+                        // What's queued was taken at the old rate
+                        next_time = None;
+                    },
+                    Err(e) => log::error!("Failed to set sensor ODR: {}", e),
+                }
+            }
+        }
+
+        let status = sensor.registers().fifo_status()?;
+        if status.overrun {
+            log::warn!("Sensor FIFO overran, samples were lost");
+            next_time = None;
+        }
+
+        // The magnetometer isn't part of the FIFO, one reading per batch
+        let mag = sensors.mag.then(|| sensor.read_mag());
+        if let Some(mag) = &mag {
+            calibrate_mag(&mut mag_calibrator, mag, &mut calibration);
+        }
+        readings.update_mag(mag);
+        read_temperature(&mut sensor);
+
+        // Without a continuous time base, assume the newest sample was taken
+        // just now. Within one, the sensor's ODR paces time rather than the
+        // ESP32's timer, so drift correction only applies here.
+        let mut time = next_time.unwrap_or_else(|| {
+            clock::since_origin(timer.now(), start_time).saturating_sub(period * u32::from(status.level.saturating_sub(1)))
+        });
+
+        for _ in 0..status.level {
+            // Reading the gyroscope pops the pair from the FIFO, the
+            // accelerometer's half is skipped if it's disabled
+            let gyro = Some(sensor.read_gyro());
+            let acc = sensors.accel.then(|| sensor.read_accel());
+
+            if let Some((acc, gyro, mag)) = readings.update(acc, gyro) {
+                status::record_sample();
+                detect_clipping(&clip_limits, acc, gyro, mag);
+                // Calibrated before filtering, the high-pass would otherwise turn the
+                // offsets into a constant error
+                let (acc, gyro, mag) = calibration.apply((acc, gyro, mag));
+                let (acc, gyro, mag) = filters.apply(time, (acc, gyro, mag));
+                detect_event(&mut events, time, acc);
+                integrate_gyro(&mut integrator, time, gyro);
+                if deadband.as_mut().map_or(true, |deadband| deadband.keep(time, acc, gyro, mag)) {
+                    push_sample(&buffer_mutex, make_sample(time, acc, gyro, mag), overflow);
+                } else {
+                    telemetry::record_suppressed_sample();
+                }
+                rate_monitor.record(1);
+            }
+            time += period;
+        }
+
+        if status.level > 0 {
+            next_time = Some(time);
+        }
+
+        std::thread::sleep(drain_interval);
+    }
+}
+
+// Walking the stack for the mark costs more than a sample is worth, it's
+// reported with the status anyway
+fn record_sampling_stack(last_check: &mut Option<Instant>) {
+    let interval = Duration::from_millis(CONFIG.status_interval_ms.into());
+    if matches!(last_check, Some(last) if last.elapsed() < interval) {
+        return;
+    }
+    *last_check = Some(Instant::now());
+    telemetry::record_stack_high_water(telemetry::Task::Sampling);
+}
+
+// Right after the driver's reads, so both describe the same conversion
+// unless the sensor already went on to the next one at a high ODR. The
+// scaled values are the driver's, before calibration and filtering.
+fn record_raw(
+    sensor: &mut impl ImuSensor,
+    sensors: sensor::Sensors,
+    (acc, gyro, mag): (&Option<Result<Triple>>, &Option<Result<Triple>>, &Option<Result<Triple>>),
+) {
+    let raw = match sensor.read_raw(sensors) {
+        Ok(raw) => raw,
+        Err(e) => {
+            log::warn!("Failed to read the sensor's output registers: {}", e);
+            return;
+        },
+    };
+
+    let axes = |raw: Option<[i16; 3]>, scaled: &Option<Result<Triple>>| {
+        let &(x, y, z) = scaled.as_ref()?.as_ref().ok()?;
+        Some(telemetry::RawAxes { raw: raw?, scaled: [x, y, z] })
+    };
+    telemetry::record_raw_sample(telemetry::RawSample {
+        accel: axes(raw.accel, acc),
+        gyro: axes(raw.gyro, gyro),
+        mag: axes(raw.mag, mag),
+    });
+}
+
+fn filters() -> filter::Filters {
+    filter::Filters {
+        acc: filter::MovingAverage::new(CONFIG.accel_filter_window as usize),
+        gyro: filter::MovingAverage::new(CONFIG.gyro_filter_window as usize),
+        mag: filter::MovingAverage::new(CONFIG.mag_filter_window as usize),
+        acc_highpass: (CONFIG.accel_highpass_mhz > 0)
+            .then(|| filter::HighPass::new(CONFIG.accel_highpass_mhz as f32 / 1000.0)),
+    }
+}
+
+fn integrator() -> Option<filter::Integrator> {
+    CONFIG.gyro_integrate.then(filter::Integrator::default)
+}
+
+fn integrate_gyro(integrator: &mut Option<filter::Integrator>, time: Duration, gyro: Triple) {
+    if let Some(integrator) = integrator {
+        if ZERO_ANGLE_REQUESTED.swap(false, Ordering::Relaxed) {
+            integrator.zero();
+            log::info!("Integrated angle zeroed");
+        }
+        telemetry::set_gyro_angle(Some(integrator.apply(time, gyro)));
+    }
+}
+
+fn deadband() -> Option<deadband::Deadband> {
+    CONFIG.deadband.then(|| deadband::Deadband::new(
+        CONFIG.accel_deadband_mg as f32 / 1000.0,
+        CONFIG.gyro_deadband_mdps as f32 / 1000.0,
+        CONFIG.mag_deadband_mgauss as f32 / 1000.0,
+    ))
+}
+
+fn event_detector() -> Option<filter::EventDetector> {
+    (CONFIG.accel_event_threshold_mg > 0)
+        .then(|| filter::EventDetector::new(CONFIG.accel_event_threshold_mg as f32 / 1000.0))
+}
+
+fn detect_event(detector: &mut Option<filter::EventDetector>, time: Duration, acc: Triple) {
+    if let Some(detector) = detector {
+        if detector.update(acc) {
+            log::info!("Event detected at {:.3} s", time.as_secs_f32());
+            telemetry::record_event();
+        }
+    }
+}
+
+// Polling on a timer runs independently of the rate the dies convert at,
+// which only shows in the data. Repeated readings are the likelier mistake,
+// aliasing sets in once the ODR is well above the poll rate.
+fn check_odr(sensors: sensor::Sensors) {
+    let poll_hz = 1000.0 / CONFIG.sample_interval_ms as f32;
+
+    if sensors.accel || sensors.gyro {
+        if CONFIG.ag_odr_hz < poll_hz {
+            log::warn!("Polling at {} Hz, above ag_odr_hz {} Hz: readings repeat", poll_hz, CONFIG.ag_odr_hz);
+        } else if CONFIG.ag_odr_hz >= ODR_ALIASING_FACTOR * poll_hz {
+            log::warn!("ag_odr_hz {} Hz is far above the poll rate of {} Hz, the signal may alias", CONFIG.ag_odr_hz, poll_hz);
+        }
+    }
+
+    // Common with the defaults and harmless, mag_read_divisor avoids it
+    let mag_poll_hz = poll_hz / CONFIG.mag_read_divisor.max(1) as f32;
+    if sensors.mag && CONFIG.mag_odr_hz < mag_poll_hz {
+        log::info!("Reading the magnetometer at {} Hz, above mag_odr_hz {} Hz: readings repeat", mag_poll_hz, CONFIG.mag_odr_hz);
+    }
+}
+
+// Last good reading of each sensor. A transient read failure on one sensor
+// reuses its previous value instead of throwing away the others' readings.
+struct Readings {
+    acc: Option<Triple>,
+    gyro: Option<Triple>,
+    mag: Option<Triple>,
+}
+
+impl Readings {
+    // Disabled sensors read zero from the start
+    fn new(sensors: sensor::Sensors) -> Self {
+        let disabled = |enabled: bool| (!enabled).then_some((0.0, 0.0, 0.0));
+        Self {
+            acc: disabled(sensors.accel),
+            gyro: disabled(sensors.gyro),
+            mag: disabled(sensors.mag),
+        }
+    }
+
+    // Returns the readings to build a sample from, once every sensor has
+    // been read successfully at least once. None is for a sensor that wasn't
+    // read.
+    fn update<E1, E2>(&mut self, acc: Option<Result<Triple, E1>>, gyro: Option<Result<Triple, E2>>) -> Option<(Triple, Triple, Triple)> {
+        Self::keep_latest(&mut self.acc, acc, "accelerometer");
+        Self::keep_latest(&mut self.gyro, gyro, "gyroscope");
+
+        Some((self.acc?, self.gyro?, self.mag?))
+    }
+
+    fn update_mag<E>(&mut self, mag: Option<Result<Triple, E>>) {
+        Self::keep_latest(&mut self.mag, mag, "magnetometer");
+    }
+
+    fn keep_latest<E>(last: &mut Option<Triple>, reading: Option<Result<Triple, E>>, name: &str) {
+        match reading {
+            None => (),
+            Some(Ok(value)) => *last = Some(value),
+            Some(Err(_)) => log::debug!("Failed to read {}, reusing previous value", name),
+        }
+    }
+}
+
+// Achieved sample rate over a sliding window of RATE_WINDOW_PERIODS periods
+// of RATE_PERIOD. It's logged every DIAGNOSTICS_INTERVAL and compared to the
+// target in telemetry::sample_rate_hz(), measuring starts over whenever
+// that changes.
+struct RateMonitor {
+    target: Option<f32>,
+    // Sample counts and lengths of the completed periods
+    periods: VecDeque<(u32, Duration)>,
+    count: u32,
+    period_start: Instant,
+    last_log: Instant,
+    behind: bool,
+}
+
+impl RateMonitor {
+    fn new() -> Self {
+        Self {
+            target: None,
+            periods: VecDeque::with_capacity(RATE_WINDOW_PERIODS),
+            count: 0,
+            period_start: Instant::now(),
+            last_log: Instant::now(),
+            behind: false,
+        }
+    }
+
+    // After a pause, the window would mix in samples from before
+    fn reset(&mut self) {
+        self.periods.clear();
+        self.count = 0;
+        self.period_start = Instant::now();
+    }
+
+    fn record(&mut self, samples: u32) {
+        self.count += samples;
+    }
+
+    // Called on every iteration of the sampling loop
+    fn update(&mut self) {
+        let target = telemetry::sample_rate_hz();
+        if target != self.target {
+            self.target = target;
+            self.reset();
+        }
+
+        let elapsed = self.period_start.elapsed();
+        if elapsed < RATE_PERIOD {
+            return;
+        }
+        if self.periods.len() == RATE_WINDOW_PERIODS {
+            self.periods.pop_front();
+        }
+        self.periods.push_back((self.count, elapsed));
+        self.count = 0;
+        self.period_start = Instant::now();
+
+        // Judged on full windows only, the FIFO delivers in bursts
+        if self.periods.len() < RATE_WINDOW_PERIODS {
+            return;
+        }
+        let samples = self.periods.iter().map(|(count, _)| count).sum::<u32>();
+        let duration = self.periods.iter().map(|(_, duration)| *duration).sum::<Duration>();
+        let rate = samples as f32 / duration.as_secs_f32();
+        telemetry::set_achieved_sample_rate_hz(Some(rate));
+
+        let Some(target) = target else {
+            return;
+        };
+        let behind = rate < target * (1.0 - CONFIG.sample_rate_tolerance_pct as f32 / 100.0);
+        if behind && !self.behind {
+            log::warn!("Sampling is falling behind: {:.1} Hz achieved, {:.1} Hz configured", rate, target);
+        } else if !behind && self.behind {
+            log::info!("Sampling caught up: {:.1} Hz achieved, {:.1} Hz configured", rate, target);
+        } else if self.last_log.elapsed() >= DIAGNOSTICS_INTERVAL {
+            log::info!("Sampling at {:.1} Hz, {:.1} Hz configured", rate, target);
+        } else {
+            return;
+        }
+        self.behind = behind;
+        self.last_log = Instant::now();
+    }
+}
+
+// Spread of the intervals between consecutive sample timestamps, over
+// windows of jitter_window_samples intervals. Sleeping to the next sample
+// and waiting for the ringbuffer add to it, which smears the spectrum the
+// collector computes. Measuring only: a standard deviation above
+// jitter_warn_us is warned about, the fix is FIFO or interrupt driven
+// sampling.
+struct JitterMonitor {
+    last_time: Option<Duration>,
+    count: u32,
+    min_us: u64,
+    max_us: u64,
+    // Integers, the ESP32 has no double precision FPU
+    sum_us: u64,
+    sum_squares_us: u64,
+    last_log: Instant,
+    high: bool,
+}
+
+impl JitterMonitor {
+    fn new() -> Self {
+        Self {
+            last_time: None,
+            count: 0,
+            min_us: u64::MAX,
+            max_us: 0,
+            sum_us: 0,
+            sum_squares_us: 0,
+            last_log: Instant::now(),
+            high: false,
+        }
+    }
+
+    // After a pause or a change of rate, the gap would count as jitter
+    fn reset(&mut self) {
+        self.last_time = None;
+        self.start_window();
+    }
+
+    fn start_window(&mut self) {
+        self.count = 0;
+        self.min_us = u64::MAX;
+        self.max_us = 0;
+        self.sum_us = 0;
+        self.sum_squares_us = 0;
+    }
+
+    fn record(&mut self, time: Duration) {
+        let Some(last_time) = self.last_time.replace(time) else {
+            return;
+        };
+        let delta_us = time.saturating_sub(last_time).as_micros() as u64;
+        self.count += 1;
+        self.min_us = self.min_us.min(delta_us);
+        self.max_us = self.max_us.max(delta_us);
+        self.sum_us += delta_us;
+        self.sum_squares_us += delta_us * delta_us;
+        if self.count < CONFIG.jitter_window_samples {
+            return;
+        }
+
+        let count = self.count as f64;
+        let mean = self.sum_us as f64 / count;
+        let variance = (self.sum_squares_us as f64 / count - mean * mean).max(0.0);
+        let jitter = telemetry::SampleJitter {
+            min_us: self.min_us as u32,
+            max_us: self.max_us as u32,
+            mean_us: mean as f32,
+            stddev_us: variance.sqrt() as f32,
+        };
+        telemetry::set_sample_jitter(Some(jitter));
+        self.start_window();
+
+        let high = CONFIG.jitter_warn_us > 0 && jitter.stddev_us > CONFIG.jitter_warn_us as f32;
+        if high && !self.high {
+            log::warn!("Sample intervals vary by {:.0} µs (standard deviation), consider FIFO or interrupt driven sampling: {:?}", jitter.stddev_us, jitter);
+        } else if !high && self.high {
+            log::info!("Sample intervals are steady again: {:?}", jitter);
+        } else if self.last_log.elapsed() >= DIAGNOSTICS_INTERVAL {
+            log::info!("Sample interval jitter: {:?}", jitter);
+        } else {
+            return;
+        }
+        self.high = high;
+        self.last_log = Instant::now();
+    }
+}
+
+// Trades temporal resolution for not dropping samples when the network can't
+// keep up
+struct Throttle {
+    base: Duration,
+    max: Duration,
+    current: Duration,
+}
+
+impl Throttle {
+    fn new(base: Duration) -> Self {
+        let max = Duration::from_millis(CONFIG.max_sample_interval_ms.into()).max(base);
+        Self { base, max, current: base }
+    }
+
+    // Returns the interval to the next sample
+    fn update<R>(&mut self, buffer_mutex: &Mutex<R>) -> Duration
+    where
+        R: RingBuffer<SensorDataSample>,
+    {
+        let fill_pct = {
+            let buffer = lock_ringbuffer(buffer_mutex);
+            buffer.len() * 100 / buffer.capacity()
+        };
+
+        let previous = self.current;
+        if fill_pct >= CONFIG.buffer_high_water_pct as usize {
+            self.current = (self.current * 2).min(self.max);
+        } else if fill_pct <= CONFIG.buffer_low_water_pct as usize {
+            self.current = (self.current / 2).max(self.base);
+        }
+
+        if self.current != previous {
+            log::info!("Ringbuffer {}% full, sampling every {:?}", fill_pct, self.current);
+            telemetry::set_sample_rate_hz(1.0 / self.current.as_secs_f32());
+        }
+
+        self.current
+    }
+}
+
+// Calibration runs alongside sampling on the raw readings, samples taken
+// meanwhile still go out with the previous offset
+fn calibrate_mag(mag_calibrator: &mut calibrate::MagCalibrator, mag: &Result<Triple>, calibration: &mut sensor::Calibration) {
+    if let Some(offset) = mag_calibrator.observe(mag) {
+        calibration.mag.offset = offset;
+    }
+}
+
+// The temperature isn't part of SensorDataSample (the schema lives in
+// hecate-protobuf), it's reported with the status message instead. A failed
+// read doesn't hold up the sample.
+fn read_temperature(sensor: &mut impl ImuSensor) {
+    let temperature = sensor.read_temp()
+        .inspect_err(|e| log::debug!("Failed to read temperature: {}", e))
+        .ok();
+    telemetry::set_temperature(temperature);
+}
+
+fn detect_clipping(limits: &sensor::ClipLimits, acc: Triple, gyro: Triple, mag: Triple) {
+    let axes = limits.clipped_axes(acc, gyro, mag);
+    if axes != 0 {
+        telemetry::record_clipped_sample(axes);
+    }
+}
+
+fn make_sample(time: Duration, (ax, ay, az): Triple, (gx, gy, gz): Triple, (mx, my, mz): Triple) -> SensorDataSample {
+
+    proto::SensorDataSample {
+        time: time.as_secs_f32(),
+        acceleration: proto::Acceleration{ x: ax, y: ay, z: az },
+        magnetometer: proto::MagnetometerData { x: mx, y: my, z: mz },
+        gyroscope: proto::GyroscopeData { x: gx, y: gy, z: gz },
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum OverflowPolicy {
+    OverwriteOldest,
+    DropNewest,
+}
+
+impl OverflowPolicy {
+    fn parse(name: &str) -> Result<Self> {
+        match name {
+            "overwrite_oldest" => Ok(OverflowPolicy::OverwriteOldest),
+            "drop_newest" => Ok(OverflowPolicy::DropNewest),
+            other => bail!("Invalid overflow policy \"{}\" (expected overwrite_oldest or drop_newest)", other),
+        }
+    }
+}
+
+fn push_sample<R>(buffer_mutex: &Mutex<R>, sample: SensorDataSample, overflow: OverflowPolicy)
+where
+    R: RingBuffer<SensorDataSample>,
+{
+    let evicted = {
+        let mut buffer = lock_ringbuffer(buffer_mutex);
+        if !buffer.is_full() {
+            buffer.push(sample);
+            SAMPLES_PUSHED.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+
+        match overflow {
+            OverflowPolicy::OverwriteOldest => {
+                let oldest = buffer.dequeue();
+                buffer.push(sample);
+                SAMPLES_PUSHED.fetch_add(1, Ordering::Relaxed);
+                oldest
+            },
+            OverflowPolicy::DropNewest => Some(sample),
+        }
+    };
+
+    // Whichever sample has to go is moved to flash instead if that's
+    // enabled, after unlocking so the networking thread doesn't wait for
+    // the write
+    let spilled = evicted.is_some_and(|sample| spill::store(&sample));
+    if !spilled {
+        telemetry::record_dropped_sample();
+    }
+}
+
+// The ringbuffer only holds samples, there's no invariant a panic while
+// holding the lock could have broken. Carrying on beats losing all data
+// flow for good.
+fn lock_ringbuffer<R>(buffer_mutex: &Mutex<R>) -> MutexGuard<'_, R> {
+    buffer_mutex.lock().unwrap_or_else(|e| {
+        if !RINGBUFFER_POISON_LOGGED.swap(true, Ordering::Relaxed) {
+            log::warn!("Ringbuffer mutex was poisoned, recovering");
+        }
+        e.into_inner()
+    })
+}
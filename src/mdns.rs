@@ -0,0 +1,20 @@
+use esp_idf_svc::{mdns::EspMdns, sys::EspError};
+use std::net::Ipv4Addr;
+use std::time::Duration;
+
+const QUERY_TIMEOUT: Duration = Duration::from_secs(3);
+
+// Regular DNS can't resolve these, they're answered by the host itself over
+// multicast
+pub fn is_local(host: &str) -> bool {
+    host.ends_with(".local")
+}
+
+pub fn resolve(host: &str) -> Result<Ipv4Addr, EspError> {
+    let mdns = EspMdns::take()?;
+    let name = host.strip_suffix(".local").unwrap_or(host);
+    let address = mdns.query_a(name, QUERY_TIMEOUT)?;
+
+    log::info!("Resolved {} to {} via mDNS", host, address);
+    Ok(address)
+}
@@ -0,0 +1,145 @@
+use anyhow::{bail, Result};
+use esp_idf_svc::mqtt::client::{EspMqttClient, EventPayload, MqttClientConfiguration, QoS};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::transport::{Message, Transport};
+
+// Sized so a full default batch of samples fits without fragmenting
+const BUFFER_SIZE: usize = 4096;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Publishes sensor data to `<topic>`, text messages (identification and
+// status) to `<topic>/status` and takes commands from `<topic>/command`
+pub struct MqttTransport {
+    client: EspMqttClient<'static>,
+    data_topic: String,
+    status_topic: String,
+    command_topic: String,
+    connected: Arc<AtomicBool>,
+    // Set on every (re)connect, the broker forgets our subscription when the
+    // session ends
+    needs_subscribe: Arc<AtomicBool>,
+    received: Arc<Mutex<Vec<Message>>>,
+}
+
+impl MqttTransport {
+    pub fn connect(url: &str, client_id: &str, topic: &str) -> Result<Self> {
+
+        let connected = Arc::new(AtomicBool::new(false));
+        let needs_subscribe = Arc::new(AtomicBool::new(false));
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let command_topic = format!("{}/command", topic);
+
+        let config = MqttClientConfiguration {
+            client_id: Some(client_id),
+            buffer_size: BUFFER_SIZE,
+            out_buffer_size: BUFFER_SIZE,
+            ..Default::default()
+        };
+
+        let client = {
+            let connected = connected.clone();
+            let needs_subscribe = needs_subscribe.clone();
+            let received = received.clone();
+            let command_topic = command_topic.clone();
+
+            // Runs on the MQTT client's own task, which also takes care of
+            // reconnecting to the broker
+            EspMqttClient::new_cb(url, &config, move |event| match event.payload() {
+                EventPayload::Connected(_) => {
+                    connected.store(true, Ordering::Relaxed);
+                    needs_subscribe.store(true, Ordering::Relaxed);
+                },
+                EventPayload::Disconnected => connected.store(false, Ordering::Relaxed),
+                EventPayload::Received { topic: Some(topic), data, .. } if topic == command_topic => {
+                    let message = match std::str::from_utf8(data) {
+                        Ok(text) => Message::Text(text.to_string()),
+                        Err(_) => Message::Binary(data.to_vec()),
+                    };
+                    if let Ok(mut received) = received.lock() {
+                        received.push(message);
+                    }
+                },
+                EventPayload::Error(e) => log::warn!("MQTT error: {}", e),
+                _ => {},
+            })?
+        };
+
+        let mut transport = Self {
+            client,
+            data_topic: topic.to_string(),
+            status_topic: format!("{}/status", topic),
+            command_topic,
+            connected,
+            needs_subscribe,
+            received,
+        };
+        transport.wait_connected()?;
+
+        Ok(transport)
+    }
+
+    fn wait_connected(&mut self) -> Result<()> {
+        let start = Instant::now();
+        while !self.connected.load(Ordering::Relaxed) {
+            if start.elapsed() > CONNECT_TIMEOUT {
+                bail!("MQTT broker not connected after {:?}", CONNECT_TIMEOUT);
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+
+        Ok(())
+    }
+
+    fn publish(&mut self, topic: &str, payload: &[u8]) -> Result<()> {
+        if !self.is_connected() {
+            bail!("MQTT broker not connected");
+        }
+
+        self.client.publish(topic, QoS::AtMostOnce, false, payload)?;
+        Ok(())
+    }
+}
+
+impl Transport for MqttTransport {
+    fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::Relaxed)
+    }
+
+    // The client reconnects on its own, this only waits for it
+    fn reconnect(&mut self) -> Result<()> {
+        self.wait_connected()
+    }
+
+    fn max_payload_len(&self) -> usize {
+        BUFFER_SIZE
+    }
+
+    fn send_text(&mut self, text: &str) -> Result<()> {
+        let topic = self.status_topic.clone();
+        self.publish(&topic, text.as_bytes())
+    }
+
+    fn send_binary(&mut self, buf: &[u8]) -> Result<()> {
+        let topic = self.data_topic.clone();
+        self.publish(&topic, buf)
+    }
+
+    // Payloads have no type, JSON batches go to the data topic as they are
+    fn send_batch(&mut self, message: &[u8], _text: bool) -> Result<()> {
+        self.send_binary(message)
+    }
+
+    fn poll(&mut self) -> Result<Vec<Message>> {
+        if self.needs_subscribe.swap(false, Ordering::Relaxed) {
+            self.client.subscribe(&self.command_topic, QoS::AtLeastOnce)?;
+        }
+
+        let mut received = self.received.lock()
+            .or_else(|e| bail!("MQTT receive queue mutex is poisoned: {e}"))?;
+        Ok(std::mem::take(&mut *received))
+    }
+}
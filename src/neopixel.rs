@@ -0,0 +1,64 @@
+use anyhow::Result;
+use esp_idf_svc::hal::{
+    gpio::OutputPin,
+    peripheral::Peripheral,
+    rmt::{config::TransmitConfig, FixedLengthSignal, PinState, Pulse, RmtChannel, TxRmtDriver},
+};
+use std::time::Duration;
+
+// WS2812 bit timings
+const T0H: Duration = Duration::from_nanos(350);
+const T0L: Duration = Duration::from_nanos(800);
+const T1H: Duration = Duration::from_nanos(700);
+const T1L: Duration = Duration::from_nanos(600);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Rgb(pub u8, pub u8, pub u8);
+
+impl Rgb {
+    pub const OFF: Self = Rgb(0, 0, 0);
+
+    // Every channel times brightness / 255
+    pub fn scaled(self, brightness: u8) -> Self {
+        let scale = |channel: u8| (u16::from(channel) * u16::from(brightness) / 255) as u8;
+        Rgb(scale(self.0), scale(self.1), scale(self.2))
+    }
+}
+
+// Single WS2812 pixel, driven through the RMT peripheral
+pub struct Neopixel<'d> {
+    tx: TxRmtDriver<'d>,
+}
+
+impl<'d> Neopixel<'d> {
+    pub fn new<C: RmtChannel>(
+        channel: impl Peripheral<P = C> + 'd,
+        pin: impl Peripheral<P = impl OutputPin> + 'd,
+    ) -> Result<Self> {
+        let config = TransmitConfig::new().clock_divider(1);
+        Ok(Self { tx: TxRmtDriver::new(channel, pin, &config)? })
+    }
+
+    pub fn set(&mut self, Rgb(r, g, b): Rgb) -> Result<()> {
+        let ticks_hz = self.tx.counter_clock()?;
+        let zero = (
+            Pulse::new_with_duration(ticks_hz, PinState::High, &T0H)?,
+            Pulse::new_with_duration(ticks_hz, PinState::Low, &T0L)?,
+        );
+        let one = (
+            Pulse::new_with_duration(ticks_hz, PinState::High, &T1H)?,
+            Pulse::new_with_duration(ticks_hz, PinState::Low, &T1L)?,
+        );
+
+        // The pixel expects green, red, blue, most significant bit first
+        let grb = u32::from_be_bytes([0, g, r, b]);
+        let mut signal = FixedLengthSignal::<24>::new();
+        for i in 0..24 {
+            let bit = grb & (1 << (23 - i)) != 0;
+            signal.set(i, if bit { &one } else { &zero })?;
+        }
+
+        self.tx.start_blocking(&signal)?;
+        Ok(())
+    }
+}
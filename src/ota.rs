@@ -0,0 +1,126 @@
+use anyhow::{bail, Result};
+use esp_idf_svc::{
+    hal::reset,
+    http::{
+        client::{Configuration, EspHttpConnection},
+        Method,
+    },
+    ota::{EspOta, EspOtaUpdate},
+    sys::esp_crt_bundle_attach,
+};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use crate::shutdown;
+
+static IN_PROGRESS: AtomicBool = AtomicBool::new(false);
+
+// How long the other threads get to stop before rebooting into the update
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(2);
+
+// Any text frame can ask for an update, and the collector connection may be
+// plain ws://. So an update needs the configured token, and the image has to
+// come over HTTPS from one of the allowed prefixes, where the server
+// certificate is checked against the bundle.
+pub fn check_request(url: &str, token: &str, expected_token: &str, url_prefixes: &'static str) -> Result<()> {
+    if expected_token.is_empty() {
+        bail!("OTA updates are disabled, ota_token isn't set");
+    }
+    if !tokens_match(token, expected_token) {
+        bail!("Wrong OTA token");
+    }
+    if !parse_url_prefixes(url_prefixes)?.iter().any(|prefix| url.starts_with(prefix)) {
+        bail!("{} is not below one of the ota_url_prefixes", url);
+    }
+    Ok(())
+}
+
+// Comma separated, each one https:// with at least a host and ending in '/'
+// so "https://example.com" doesn't also allow "https://example.com.evil"
+pub fn parse_url_prefixes(list: &'static str) -> Result<Vec<&'static str>> {
+    let prefixes: Vec<_> = list.split(',').map(str::trim).filter(|prefix| !prefix.is_empty()).collect();
+    if prefixes.is_empty() {
+        bail!("ota_url_prefixes is empty, OTA updates need at least one");
+    }
+    for prefix in &prefixes {
+        let host = prefix.strip_prefix("https://").unwrap_or("");
+        if host.is_empty() || host.starts_with('/') || !prefix.ends_with('/') {
+            bail!("Invalid OTA URL prefix \"{}\" (expected https://host/path/)", prefix);
+        }
+    }
+    Ok(prefixes)
+}
+
+// Takes as long whatever the first wrong byte is
+fn tokens_match(token: &str, expected: &str) -> bool {
+    token.len() == expected.len()
+        && token.bytes().zip(expected.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+// Sampling pauses and the indicator blinks while this is set
+pub fn in_progress() -> bool {
+    IN_PROGRESS.load(Ordering::Relaxed)
+}
+
+// With rollback enabled, the bootloader falls back to the previous image
+// unless a freshly updated one confirms it came up properly
+pub fn mark_running_slot_valid() -> Result<()> {
+    EspOta::new()?.mark_running_slot_valid()?;
+    Ok(())
+}
+
+// Downloads the image at url into the inactive OTA slot and reboots into it.
+// Only returns on failure, the running firmware stays in place then.
+pub fn update(url: &str) -> Result<()> {
+    log::info!("Starting OTA update from {}", url);
+
+    IN_PROGRESS.store(true, Ordering::Relaxed);
+    let result = download(url);
+    IN_PROGRESS.store(false, Ordering::Relaxed);
+    result?;
+
+    log::info!("OTA update complete, rebooting");
+    shutdown::request(SHUTDOWN_TIMEOUT);
+    reset::restart();
+}
+
+fn download(url: &str) -> Result<()> {
+    let mut connection = EspHttpConnection::new(&Configuration {
+        crt_bundle_attach: Some(esp_crt_bundle_attach),
+        ..Default::default()
+    })?;
+    connection.initiate_request(Method::Get, url, &[])?;
+    connection.initiate_response()?;
+    if connection.status() != 200 {
+        bail!("Firmware download failed with HTTP status {}", connection.status());
+    }
+
+    let mut ota = EspOta::new()?;
+    let mut update = ota.initiate_update()?;
+
+    // An incomplete image must never be marked bootable
+    match copy_image(&mut connection, &mut update) {
+        Ok(len) => {
+            log::info!("Downloaded {} bytes", len);
+            update.complete()?;
+            Ok(())
+        },
+        Err(e) => {
+            _ = update.abort();
+            Err(e)
+        },
+    }
+}
+
+fn copy_image(connection: &mut EspHttpConnection, update: &mut EspOtaUpdate) -> Result<usize> {
+    let mut buf = [0u8; 1024];
+    let mut total = 0;
+    loop {
+        let len = connection.read(&mut buf)?;
+        if len == 0 {
+            return Ok(total);
+        }
+        update.write(&buf[..len])?;
+        total += len;
+    }
+}
@@ -0,0 +1,107 @@
+use anyhow::Result;
+use esp_idf_svc::hal::gpio::{AnyOutputPin, Output, PinDriver};
+use esp_idf_svc::sys::{
+    esp, esp_deep_sleep_start, esp_pm_config_t, esp_pm_configure, esp_sleep_enable_ext0_wakeup,
+    esp_sleep_enable_gpio_wakeup, esp_sleep_enable_wifi_wakeup, esp_sleep_get_wakeup_cause,
+    esp_sleep_source_t_ESP_SLEEP_WAKEUP_EXT0, gpio_deep_sleep_hold_en, gpio_hold_dis, gpio_hold_en,
+    gpio_int_type_t_GPIO_INTR_HIGH_LEVEL, gpio_wakeup_enable,
+};
+use std::time::Duration;
+
+const MAX_CPU_FREQ_MHZ: i32 = 240;
+const MIN_CPU_FREQ_MHZ: i32 = 80;
+
+// Long enough off for the supplied parts to reset
+const POWER_OFF_TIME: Duration = Duration::from_millis(100);
+
+// The switched supply of the QWIIC connector and the NeoPixel, which share
+// one regulator. Dropping it switches the supply off, so the parts don't
+// keep drawing power once the firmware lets go of the pin.
+pub struct PowerRail {
+    pin: PinDriver<'static, AnyOutputPin, Output>,
+    // How long the parts usually take to come up
+    power_up_delay: Duration,
+}
+
+impl PowerRail {
+    pub fn new(pin: PinDriver<'static, AnyOutputPin, Output>, power_up_delay: Duration) -> Self {
+        Self { pin, power_up_delay }
+    }
+
+    pub fn power_up_delay(&self) -> Duration {
+        self.power_up_delay
+    }
+
+    // Returns once the parts had the usual time to come up, slower ones
+    // may need longer
+    pub fn power_up(&mut self) -> Result<()> {
+        self.pin.set_high()?;
+        std::thread::sleep(self.power_up_delay);
+        Ok(())
+    }
+
+    pub fn power_down(&mut self) -> Result<()> {
+        self.pin.set_low()?;
+        Ok(())
+    }
+
+    pub fn power_cycle(&mut self) -> Result<()> {
+        self.power_down()?;
+        std::thread::sleep(POWER_OFF_TIME);
+        self.power_up()
+    }
+}
+
+impl Drop for PowerRail {
+    fn drop(&mut self) {
+        _ = self.pin.set_low();
+    }
+}
+
+// Lets the idle task put the chip into light sleep whenever every thread is
+// blocked. The IMU data ready line wakes it for the next sample, WiFi wakes
+// it for beacons and incoming traffic.
+pub fn enable_light_sleep(wake_gpio: i32) -> Result<()> {
+    esp!(unsafe { gpio_wakeup_enable(wake_gpio, gpio_int_type_t_GPIO_INTR_HIGH_LEVEL) })?;
+    esp!(unsafe { esp_sleep_enable_gpio_wakeup() })?;
+    esp!(unsafe { esp_sleep_enable_wifi_wakeup() })?;
+
+    let config = esp_pm_config_t {
+        max_freq_mhz: MAX_CPU_FREQ_MHZ,
+        min_freq_mhz: MIN_CPU_FREQ_MHZ,
+        light_sleep_enable: true,
+    };
+    esp!(unsafe { esp_pm_configure(&config as *const _ as *const _) })?;
+
+    Ok(())
+}
+
+// Only these can wake the chip from deep sleep
+pub fn is_rtc_gpio(gpio: i32) -> bool {
+    matches!(gpio, 0 | 2 | 4 | 12..=15 | 25..=27 | 32..=39)
+}
+
+// Everything but the RTC powers down until wake_gpio goes high, then the
+// firmware boots from the start. The outputs in held keep their level
+// meanwhile. Only returns on failure.
+pub fn deep_sleep_until_high(wake_gpio: i32, held: &[i32]) -> Result<()> {
+    for &gpio in held {
+        esp!(unsafe { gpio_hold_en(gpio) })?;
+    }
+    unsafe { gpio_deep_sleep_hold_en() };
+    esp!(unsafe { esp_sleep_enable_ext0_wakeup(wake_gpio, 1) })?;
+
+    unsafe { esp_deep_sleep_start() }
+}
+
+// Held outputs ignore writes until released, also after waking up
+pub fn release(held: &[i32]) -> Result<()> {
+    for &gpio in held {
+        esp!(unsafe { gpio_hold_dis(gpio) })?;
+    }
+    Ok(())
+}
+
+pub fn woken_by_gpio() -> bool {
+    unsafe { esp_sleep_get_wakeup_cause() == esp_sleep_source_t_ESP_SLEEP_WAKEUP_EXT0 }
+}
@@ -0,0 +1,182 @@
+use anyhow::{anyhow, bail, Result};
+use esp_idf_svc::{
+    eventloop::EspSystemEventLoop,
+    hal::reset,
+    http::{
+        server::{Configuration as HttpConfiguration, EspHttpServer},
+        Method,
+    },
+    io::{Read, Write},
+    nvs::EspDefaultNvsPartition,
+    wifi::{AccessPointConfiguration, AuthMethod, BlockingWifi, Configuration, EspWifi},
+};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::{credentials, settings, shutdown, status};
+
+// Last resort when no configured network can be joined: the unit opens a
+// setup network of its own and serves a form at http://192.168.4.1/ where
+// an operator enters SSID and PSK. They're stored in NVS, and the unit
+// restarts to use them.
+
+// The setup page is at the root of the AP's address, where a phone's
+// browser ends up without typing a port
+pub const PORT: u16 = 80;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+// Lets the confirmation page go out before the restart
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(2);
+// A form with an SSID and a PSK, urlencoded
+const MAX_FORM_LEN: usize = 512;
+const CTRL_PORT: u16 = 32769;
+
+const FORM: &str = "<!DOCTYPE html><html><head><meta name=\"viewport\" content=\"width=device-width\">\
+<title>Hecate Sensor Setup</title></head><body><h1>WiFi Setup</h1><form method=\"post\" action=\"/\">\
+<p><label>SSID <input name=\"ssid\" maxlength=\"32\" required></label></p>\
+<p><label>PSK <input name=\"psk\" type=\"password\" minlength=\"8\" maxlength=\"64\"></label></p>\
+<p><button type=\"submit\">Save and restart</button></p></form></body></html>";
+
+const SAVED: &str = "<!DOCTYPE html><html><body><h1>Saved</h1><p>Restarting to join the network.</p></body></html>";
+
+// Runs the setup network until an operator configured WiFi, which restarts
+// the unit, or until the timeout, so a network that was only gone for a
+// while is tried again.
+pub fn run(
+    wifi_mutex: &Mutex<EspWifi>,
+    sysloop: &EspSystemEventLoop,
+    nvs: &EspDefaultNvsPartition,
+    device_id: &str,
+    psk: &str,
+    timeout: Duration,
+) -> Result<()> {
+    // Never an open network, joining it is all it takes to change WiFi
+    if psk.len() < credentials::MIN_PSK_LEN {
+        bail!("provisioning_psk has to be at least {} bytes", credentials::MIN_PSK_LEN);
+    }
+
+    let ssid = setup_ssid(device_id);
+    let mut wifi_locked = wifi_mutex.lock()
+        .map_err(|_| anyhow!("WiFi mutex is poisoned"))?;
+    let mut wifi = BlockingWifi::wrap(&mut *wifi_locked, sysloop.clone())?;
+
+    if wifi.is_started()? {
+        _ = wifi.disconnect();
+        wifi.stop()?;
+    }
+    wifi.set_configuration(&Configuration::AccessPoint(AccessPointConfiguration {
+        ssid: ssid.as_str().try_into()
+            .map_err(|_| anyhow!("Setup SSID {} is longer than {} bytes", ssid, credentials::MAX_SSID_LEN))?,
+        password: psk.try_into()
+            .map_err(|_| anyhow!("provisioning_psk is longer than {} bytes", credentials::MAX_PSK_LEN))?,
+        auth_method: AuthMethod::WPA2Personal,
+        ..Default::default()
+    }))?;
+    wifi.start()?;
+    wifi.wait_netif_up()?;
+
+    let ip = wifi.wifi().ap_netif().get_ip_info()?.ip;
+    status::set(status::State::Provisioning);
+    log::warn!("No WiFi network could be joined, started setup network {}. Open http://{}/ to configure WiFi.", ssid, ip);
+
+    let submitted = Arc::new(Mutex::new(None));
+    let server = start_server(submitted.clone())?;
+
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some((ssid, psk)) = submitted.lock().ok().and_then(|mut submitted| submitted.take()) {
+            settings::SettingsStore::new(nvs.clone())?.save_wifi(&ssid, &psk)?;
+            log::info!("WiFi set to {}, restarting", ssid);
+            std::thread::sleep(RESTART_DELAY);
+            shutdown::request(SHUTDOWN_TIMEOUT);
+            reset::restart();
+        }
+
+        if shutdown::requested() || Instant::now() >= deadline {
+            break;
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    }
+
+    log::info!("WiFi wasn't configured within {:?}, trying the known networks again", timeout);
+    drop(server);
+    wifi.stop()?;
+    Ok(())
+}
+
+// Tells units apart when several are being set up at once
+fn setup_ssid(device_id: &str) -> String {
+    let suffix = &device_id[device_id.len().saturating_sub(6)..];
+    format!("hecate-setup-{}", suffix)
+}
+
+fn start_server(submitted: Arc<Mutex<Option<(String, String)>>>) -> Result<EspHttpServer<'static>> {
+    let mut server = EspHttpServer::new(&HttpConfiguration {
+        http_port: PORT,
+        // Next to the status page's, which has the default
+        ctrl_port: CTRL_PORT,
+        ..Default::default()
+    })?;
+
+    server.fn_handler("/", Method::Get, |request| -> Result<()> {
+        let mut response = request.into_response(200, Some("OK"), &[("Content-Type", "text/html")])?;
+        response.write_all(FORM.as_bytes())?;
+        Ok(())
+    })?;
+
+    server.fn_handler("/", Method::Post, move |mut request| -> Result<()> {
+        let mut body = [0u8; MAX_FORM_LEN];
+        let mut len = 0;
+        while len < body.len() {
+            match request.read(&mut body[len..])? {
+                0 => break,
+                n => len += n,
+            }
+        }
+        let form = std::str::from_utf8(&body[..len])?;
+
+        let ssid = form_value(form, "ssid").unwrap_or_default();
+        let psk = form_value(form, "psk").unwrap_or_default();
+        // The browser checks the lengths too, but a PSK WPA2 can't use would
+        // leave the unit restarting onto a network it never joins
+        if !credentials::check_credentials(&ssid, &psk).is_empty() {
+            request.into_status_response(400)?
+                .write_all(b"SSID must be 1-32 bytes, PSK empty or 8-64")?;
+            return Ok(());
+        }
+
+        let mut response = request.into_response(200, Some("OK"), &[("Content-Type", "text/html")])?;
+        response.write_all(SAVED.as_bytes())?;
+        if let Ok(mut submitted) = submitted.lock() {
+            *submitted = Some((ssid, psk));
+        }
+        Ok(())
+    })?;
+
+    Ok(server)
+}
+
+// Value of a field in an application/x-www-form-urlencoded body
+fn form_value(form: &str, name: &str) -> Option<String> {
+    form.split('&')
+        .filter_map(|field| field.split_once('='))
+        .find(|(key, _)| *key == name)
+        .and_then(|(_, value)| url_decode(value))
+}
+
+fn url_decode(value: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut input = value.bytes();
+    while let Some(byte) = input.next() {
+        bytes.push(match byte {
+            b'+' => b' ',
+            b'%' => {
+                let hex = [input.next()?, input.next()?];
+                u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?
+            },
+            byte => byte,
+        });
+    }
+    String::from_utf8(bytes).ok()
+}
@@ -0,0 +1,154 @@
+use anyhow::{bail, Result};
+use std::collections::VecDeque;
+use crate::telemetry;
+use crate::transport::Transport;
+
+// Encoded SensorData messages waiting for the transport. Encoding puts them
+// in, flush() writes them out in order, so backpressure and resending after
+// a failed write are handled in one place. Samples stay in the ringbuffer
+// until their frame was written, see take_sent_through(), so a restart of
+// the networking thread encodes them again and a full ringbuffer spills
+// them to flash. A frame the policy drops is lost.
+
+// What try_send() does when the queue is full
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FullPolicy {
+    // Hand the frame back, send() flushes to make room
+    Block,
+    DropOldest,
+    DropNewest,
+}
+
+impl FullPolicy {
+    pub fn parse(name: &str) -> Result<Self> {
+        match name {
+            "block" => Ok(FullPolicy::Block),
+            "drop_oldest" => Ok(FullPolicy::DropOldest),
+            "drop_newest" => Ok(FullPolicy::DropNewest),
+            other => bail!("Invalid send queue policy \"{}\" (expected block, drop_oldest or drop_newest)", other),
+        }
+    }
+}
+
+pub struct Frame {
+    message: Vec<u8>,
+    // JSON, see Transport::send_batch()
+    text: bool,
+    // Set once writing the frame failed, with the seq it was sent as. It
+    // may have arrived after all, so it's announced as a resend next time.
+    failed_seq: Option<u32>,
+    // Ringbuffer index of the newest sample in it
+    last_index: u32,
+}
+
+impl Frame {
+    pub fn new(message: Vec<u8>, text: bool, last_index: u32) -> Self {
+        Self { message, text, failed_seq: None, last_index }
+    }
+}
+
+pub struct SendQueue {
+    frames: VecDeque<Frame>,
+    capacity: usize,
+    policy: FullPolicy,
+    sent_through: Option<u32>,
+}
+
+impl SendQueue {
+    pub fn new(capacity: usize, policy: FullPolicy) -> Self {
+        let capacity = capacity.max(1);
+        Self { frames: VecDeque::with_capacity(capacity), capacity, policy, sent_through: None }
+    }
+
+    // Ringbuffer index of the newest sample written since the last call, the
+    // samples up to it can go. Also after a send or flush that failed, the
+    // frames before the failed one went out.
+    pub fn take_sent_through(&mut self) -> Option<u32> {
+        self.sent_through.take()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    // Never blocks. A full queue drops a frame according to the policy, or
+    // with FullPolicy::Block hands this one back.
+    pub fn try_send(&mut self, frame: Frame) -> Result<(), Frame> {
+        if self.frames.len() >= self.capacity {
+            match self.policy {
+                FullPolicy::Block => return Err(frame),
+                FullPolicy::DropOldest => {
+                    self.frames.pop_front();
+                    telemetry::record_dropped_frame();
+                },
+                FullPolicy::DropNewest => {
+                    telemetry::record_dropped_frame();
+                    return Ok(());
+                },
+            }
+        }
+
+        self.frames.push_back(frame);
+        telemetry::set_send_queue_depth(self.frames.len());
+        Ok(())
+    }
+
+    // Like try_send(), but waits for the transport to take the oldest frame
+    // when the queue is full and the policy is to block
+    pub fn send(&mut self, mut frame: Frame, client: &mut dyn Transport, seq: &mut u32) -> Result<()> {
+        loop {
+            match self.try_send(frame) {
+                Ok(()) => return Ok(()),
+                Err(rejected) => {
+                    frame = rejected;
+                    self.send_oldest(client, seq)?;
+                },
+            }
+        }
+    }
+
+    // Writes everything queued, oldest first. A frame that fails stays at
+    // the front and goes first after reconnecting, announced by a Resend.
+    pub fn flush(&mut self, client: &mut dyn Transport, seq: &mut u32) -> Result<()> {
+        while !self.frames.is_empty() {
+            self.send_oldest(client, seq)?;
+        }
+        Ok(())
+    }
+
+    fn send_oldest(&mut self, client: &mut dyn Transport, seq: &mut u32) -> Result<()> {
+        let Some(frame) = self.frames.front_mut() else {
+            return Ok(());
+        };
+
+        let result = match frame.failed_seq {
+            Some(previous_seq) => telemetry::Resend { previous_seq, seq: *seq }.to_json()
+                .map_err(anyhow::Error::from)
+                .and_then(|json| client.send_text(&json))
+                .and_then(|()| client.send_batch(&frame.message, frame.text)),
+            None => client.send_batch(&frame.message, frame.text),
+        };
+
+        match result {
+            Ok(()) => {
+                match frame.failed_seq {
+                    Some(previous_seq) => log::info!("Resent batch {} as batch {}", previous_seq, seq),
+                    None => log::info!("Sent batch {}", seq),
+                }
+                telemetry::record_batch_sent(*seq, &frame.message);
+                *seq = seq.wrapping_add(1);
+                self.sent_through = Some(frame.last_index);
+                self.frames.pop_front();
+                telemetry::set_send_queue_depth(self.frames.len());
+                Ok(())
+            },
+            Err(e) => {
+                log::error!("Failed to send batch {}: {}", seq, e);
+                // Only the first attempt counts, that's the one the
+                // collector may have gotten
+                frame.failed_seq.get_or_insert(*seq);
+                Err(e)
+            },
+        }
+    }
+}
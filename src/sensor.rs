@@ -0,0 +1,859 @@
+use anyhow::{bail, Result};
+use embedded_hal_0_2::{
+    blocking::{i2c::{Write, WriteRead}, spi},
+    digital::v2::OutputPin,
+};
+use esp_idf_svc::{
+    hal::{
+        delay::{TickType, BLOCK},
+        gpio::{AnyInputPin, AnyOutputPin, Input, InterruptType, Output, PinDriver},
+        i2c::I2cDriver,
+        spi::{SpiDeviceDriver, SpiDriver},
+        task::notification::Notification,
+    },
+    sys::{esp_timer_get_time, EspError},
+};
+use lsm9ds1::{accel, gyro, interface::i2c::{AgAddress, MagAddress}, mag};
+use std::fmt::Display;
+use std::num::NonZeroU32;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use crate::board;
+use crate::imu::Triple;
+
+// Both the accelerometer/gyroscope and the magnetometer die expose their
+// identity in a WHO_AM_I register at the same offset
+const WHO_AM_I: u8 = 0x0F;
+const AG_WHO_AM_I_VALUE: u8 = 0x68;
+const MAG_WHO_AM_I_VALUE: u8 = 0x3D;
+
+const OUT_TEMP_L: u8 = 0x15;
+const INT1_CTRL: u8 = 0x0C;
+const CTRL_REG1_G: u8 = 0x10;
+const CTRL_REG6_XL: u8 = 0x20;
+const CTRL_REG9: u8 = 0x23;
+const FIFO_CTRL: u8 = 0x2E;
+const FIFO_SRC: u8 = 0x2F;
+const CTRL_REG3_M: u8 = 0x22;
+const INT_GEN_CFG_XL: u8 = 0x06;
+const INT_GEN_THS_X_XL: u8 = 0x07;
+const INT_GEN_DUR_XL: u8 = 0x0A;
+const CTRL_REG4: u8 = 0x1E;
+const CTRL_REG7_XL: u8 = 0x21;
+const INT_GEN_SRC_XL: u8 = 0x26;
+const CTRL_REG10: u8 = 0x24;
+const STATUS_REG: u8 = 0x27;
+const OUT_X_L_G: u8 = 0x18;
+const OUT_X_L_XL: u8 = 0x28;
+const CTRL_REG1_M: u8 = 0x20;
+const CTRL_REG2_M: u8 = 0x21;
+const STATUS_REG_M: u8 = 0x27;
+const OUT_X_L_M: u8 = 0x28;
+
+const CTRL_REG3_M_POWER_DOWN: u8 = 0b11;
+
+// Set in the register address byte of SPI reads
+const SPI_READ: u8 = 0x80;
+
+const INT1_DRDY_G: u8 = 0x02;
+const INT1_IG_XL: u8 = 0x40;
+// High event on any axis
+const INT_GEN_CFG_XL_ANY_HIGH: u8 = 0b0010_1010;
+const CTRL_REG4_LIR_XL1: u8 = 0x02;
+const CTRL_REG7_XL_HPIS1: u8 = 0x01;
+const CTRL_REG6_XL_ODR_10_HZ: u8 = 0b001 << 5;
+const CTRL_REG6_XL_ODR_MASK: u8 = 0b111 << 5;
+const CTRL_REG9_FIFO_EN: u8 = 0x02;
+const FIFO_MODE_CONTINUOUS: u8 = 0b110 << 5;
+
+// Self-test configurations: accelerometer at 119 Hz and ±2 g, gyroscope at
+// 119 Hz and ±2000 dps, magnetometer continuous at 80 Hz and ±12 gauss
+const SELF_TEST_CTRL_REG6_XL: u8 = 0b011_00_000;
+const SELF_TEST_CTRL_REG1_G: u8 = 0b011_11_000;
+const SELF_TEST_CTRL_REG1_M: u8 = 0b0_00_111_00;
+const SELF_TEST_CTRL_REG2_M: u8 = 0b10 << 5;
+const SELF_TEST_CTRL_REG3_M: u8 = 0;
+const CTRL_REG10_ST_XL: u8 = 0x01;
+const CTRL_REG10_ST_G: u8 = 0x04;
+const CTRL_REG1_M_ST: u8 = 0x01;
+const STATUS_REG_XLDA: u8 = 0x01;
+const STATUS_REG_GDA: u8 = 0x02;
+const STATUS_REG_M_ZYXDA: u8 = 0x08;
+// Sensitivities in those ranges, in g, dps and gauss per LSB
+const SELF_TEST_ACCEL_SCALE: f32 = 0.000061;
+const SELF_TEST_GYRO_SCALE: f32 = 0.07;
+const SELF_TEST_MAG_SCALE: f32 = 0.00043;
+// Allowed change of every axis' output with self-test on, the magnetometer
+// has a smaller one for Z
+const SELF_TEST_ACCEL_LIMITS: [(f32, f32); 3] = [(0.06, 1.7); 3];
+const SELF_TEST_GYRO_LIMITS: [(f32, f32); 3] = [(20.0, 250.0); 3];
+const SELF_TEST_MAG_LIMITS: [(f32, f32); 3] = [(1.0, 3.0), (1.0, 3.0), (0.1, 1.0)];
+const SELF_TEST_SAMPLES: u32 = 5;
+const SELF_TEST_SETTLE_TIME: Duration = Duration::from_millis(100);
+const SELF_TEST_SAMPLE_TIMEOUT: Duration = Duration::from_millis(100);
+
+// Number of accel/gyro sample pairs the FIFO holds
+pub const FIFO_DEPTH: u8 = 32;
+
+// Gyroscope ODR settings 1 to 6 of CTRL_REG1_G, 0 is power-down
+const GYRO_ODRS_HZ: [f32; 6] = [14.9, 59.5, 119.0, 238.0, 476.0, 952.0];
+pub const MAX_ODR_HZ: f32 = 952.0;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SamplingMode {
+    // Read the output registers on a timer
+    Polling,
+    // Let the sensor queue samples at its ODR and drain them in batches
+    Fifo,
+}
+
+impl SamplingMode {
+    pub fn parse(name: &str) -> Result<Self> {
+        match name {
+            "poll" => Ok(SamplingMode::Polling),
+            "fifo" => Ok(SamplingMode::Fifo),
+            other => bail!("Invalid sampling mode \"{}\" (expected poll or fifo)", other),
+        }
+    }
+}
+
+// Which parts of the IMU are in use
+#[derive(Clone, Copy, Debug)]
+pub struct Sensors {
+    pub accel: bool,
+    pub gyro: bool,
+    pub mag: bool,
+}
+
+// I2C bus that can be shared between the lsm9ds1 driver and direct register
+// access for features the driver doesn't cover
+#[derive(Clone)]
+pub struct SharedI2c(Arc<Mutex<I2cDriver<'static>>>);
+
+impl SharedI2c {
+    pub fn new(i2c: I2cDriver<'static>) -> Self {
+        Self(Arc::new(Mutex::new(i2c)))
+    }
+
+    fn lock(&self) -> MutexGuard<'_, I2cDriver<'static>> {
+        // A panic mid-transaction doesn't leave the driver in a state worth
+        // refusing to talk to the sensor over
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn read_register(&self, address: u8, register: u8) -> Result<u8, EspError> {
+        let mut value = [0u8];
+        self.lock().write_read(address, &[register], &mut value, BLOCK)?;
+        Ok(value[0])
+    }
+
+    fn write_register(&self, address: u8, register: u8, value: u8) -> Result<(), EspError> {
+        self.lock().write(address, &[register, value], BLOCK)
+    }
+}
+
+impl Write for SharedI2c {
+    type Error = EspError;
+
+    fn write(&mut self, address: u8, bytes: &[u8]) -> Result<(), Self::Error> {
+        self.lock().write(address, bytes, BLOCK)
+    }
+}
+
+impl WriteRead for SharedI2c {
+    type Error = EspError;
+
+    fn write_read(&mut self, address: u8, bytes: &[u8], buffer: &mut [u8]) -> Result<(), Self::Error> {
+        self.lock().write_read(address, bytes, buffer, BLOCK)
+    }
+}
+
+// SPI bus to the sensor. The chip selects are separate ChipSelect pins, the
+// lsm9ds1 driver drives them itself.
+#[derive(Clone)]
+pub struct SharedSpi(Arc<Mutex<SpiDeviceDriver<'static, SpiDriver<'static>>>>);
+
+impl SharedSpi {
+    pub fn new(spi: SpiDeviceDriver<'static, SpiDriver<'static>>) -> Self {
+        Self(Arc::new(Mutex::new(spi)))
+    }
+
+    fn lock(&self) -> MutexGuard<'_, SpiDeviceDriver<'static, SpiDriver<'static>>> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl spi::Transfer<u8> for SharedSpi {
+    type Error = EspError;
+
+    fn transfer<'w>(&mut self, words: &'w mut [u8]) -> Result<&'w [u8], Self::Error> {
+        self.lock().transfer_in_place(words)?;
+        Ok(words)
+    }
+}
+
+impl spi::Write<u8> for SharedSpi {
+    type Error = EspError;
+
+    fn write(&mut self, words: &[u8]) -> Result<(), Self::Error> {
+        self.lock().write(words)
+    }
+}
+
+// Chip select of one die, active low. Shared between the lsm9ds1 driver and
+// Device.
+#[derive(Clone)]
+pub struct ChipSelect(Arc<Mutex<PinDriver<'static, AnyOutputPin, Output>>>);
+
+impl ChipSelect {
+    pub fn new(gpio: i32) -> Result<Self> {
+        let mut pin = PinDriver::output(board::output_pin(gpio)?)?;
+        pin.set_high()?;
+        Ok(Self(Arc::new(Mutex::new(pin))))
+    }
+
+    fn set_selected(&self, selected: bool) -> Result<(), EspError> {
+        let mut pin = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        match selected {
+            true => pin.set_low(),
+            false => pin.set_high(),
+        }
+    }
+}
+
+impl OutputPin for ChipSelect {
+    type Error = EspError;
+
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        self.set_selected(true)
+    }
+
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        self.set_selected(false)
+    }
+}
+
+// Whichever bus the sensor is wired to
+#[derive(Clone)]
+pub enum Bus {
+    I2c(SharedI2c),
+    Spi {
+        spi: SharedSpi,
+        ag_cs: ChipSelect,
+        mag_cs: ChipSelect,
+    },
+}
+
+// One die of the sensor, for direct register access
+#[derive(Clone)]
+pub enum Device {
+    I2c { bus: SharedI2c, address: u8 },
+    Spi { spi: SharedSpi, cs: ChipSelect },
+}
+
+impl Device {
+    pub fn accel_gyro(bus: &SharedI2c, address: &AgAddress) -> Self {
+        Device::I2c { bus: bus.clone(), address: ag_address_value(address) }
+    }
+
+    pub fn magnetometer(bus: &SharedI2c, address: &MagAddress) -> Self {
+        Device::I2c { bus: bus.clone(), address: mag_address_value(address) }
+    }
+
+    // Multi-byte reads only auto-increment on the accel/gyro die, the
+    // magnetometer would need another address bit over SPI
+    fn read(&self, register: u8, buf: &mut [u8]) -> Result<(), EspError> {
+        match self {
+            Device::I2c { bus, address } => bus.lock().write_read(*address, &[register], buf, BLOCK),
+            Device::Spi { spi, cs } => {
+                let mut spi = spi.lock();
+                cs.set_selected(true)?;
+                buf.fill(0);
+                let result = spi.write(&[register | SPI_READ])
+                    .and_then(|()| spi.transfer_in_place(buf));
+                cs.set_selected(false)?;
+                result
+            },
+        }
+    }
+
+    fn read_register(&self, register: u8) -> Result<u8, EspError> {
+        let mut value = [0u8];
+        self.read(register, &mut value)?;
+        Ok(value[0])
+    }
+
+    fn write_register(&self, register: u8, value: u8) -> Result<(), EspError> {
+        match self {
+            Device::I2c { bus, address } => bus.write_register(*address, register, value),
+            Device::Spi { spi, cs } => {
+                let mut spi = spi.lock();
+                cs.set_selected(true)?;
+                let result = spi.write(&[register, value]);
+                cs.set_selected(false)?;
+                result
+            },
+        }
+    }
+}
+
+impl Display for Device {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Device::I2c { address, .. } => write!(f, "at {:#04x}", address),
+            Device::Spi { .. } => write!(f, "on SPI"),
+        }
+    }
+}
+
+pub struct FifoStatus {
+    pub level: u8,
+    pub overrun: bool,
+}
+
+// Direct register access to the accelerometer/gyroscope die, and to the
+// magnetometer's output where that's needed
+pub struct Registers {
+    ag: Device,
+    mag: Option<Device>,
+}
+
+impl Registers {
+    pub fn new(ag: Device) -> Self {
+        Self { ag, mag: None }
+    }
+
+    pub fn with_magnetometer(self, mag: Device) -> Self {
+        Self { mag: Some(mag), ..self }
+    }
+
+    // Output registers as the dies hold them, before the driver scales them
+    pub fn read_raw_accel(&self) -> Result<[i16; 3]> {
+        let mut raw = [0u8; 6];
+        self.ag.read(OUT_X_L_XL, &mut raw)?;
+        Ok(axes(raw))
+    }
+
+    pub fn read_raw_gyro(&self) -> Result<[i16; 3]> {
+        let mut raw = [0u8; 6];
+        self.ag.read(OUT_X_L_G, &mut raw)?;
+        Ok(axes(raw))
+    }
+
+    pub fn read_raw_mag(&self) -> Result<[i16; 3]> {
+        let Some(mag) = &self.mag else {
+            bail!("No magnetometer registers")
+        };
+        Ok(axes(read_output(mag, OUT_X_L_M)?))
+    }
+
+    // Output data rate the gyroscope (and with it the FIFO) runs at
+    pub fn gyro_odr_hz(&self) -> Result<f32> {
+        let odr = self.ag.read_register(CTRL_REG1_G)? >> 5;
+        match odr {
+            1..=6 => Ok(GYRO_ODRS_HZ[usize::from(odr) - 1]),
+            _ => bail!("Gyroscope is powered down"),
+        }
+    }
+
+    // Picks the slowest ODR that is at least hz and returns it. The scale
+    // and bandwidth settings in the same register are kept.
+    pub fn set_gyro_odr_hz(&self, hz: f32) -> Result<f32> {
+        let Some(index) = GYRO_ODRS_HZ.iter().position(|&odr| odr >= hz) else {
+            bail!("{} Hz is above the gyroscope's maximum ODR of {} Hz", hz, MAX_ODR_HZ)
+        };
+        let ctrl_reg1_g = self.ag.read_register(CTRL_REG1_G)?;
+        let odr = (index as u8 + 1) << 5;
+        self.ag.write_register(CTRL_REG1_G, (ctrl_reg1_g & 0x1F) | odr)?;
+        Ok(GYRO_ODRS_HZ[index])
+    }
+
+    // Continuous mode: once full, the oldest samples are overwritten
+    pub fn enable_fifo(&self) -> Result<()> {
+        let ctrl_reg9 = self.ag.read_register(CTRL_REG9)?;
+        self.ag.write_register(CTRL_REG9, ctrl_reg9 | CTRL_REG9_FIFO_EN)?;
+        self.ag.write_register(FIFO_CTRL, FIFO_MODE_CONTINUOUS)?;
+        Ok(())
+    }
+
+    // Die temperature in °C: 16 LSB/°C, zero at 25 °C
+    pub fn read_temperature(&self) -> Result<f32> {
+        let mut raw = [0u8; 2];
+        self.ag.read(OUT_TEMP_L, &mut raw)?;
+        Ok(f32::from(i16::from_le_bytes(raw)) / 16.0 + 25.0)
+    }
+
+    // Route gyroscope data ready to INT1_A/G. Accel and gyro run at the same
+    // ODR, so this covers both.
+    pub fn enable_data_ready_interrupt(&self) -> Result<()> {
+        self.ag.write_register(INT1_CTRL, INT1_DRDY_G)?;
+        Ok(())
+    }
+
+    // Runs the accelerometer alone at 10 Hz and latches INT1_A/G high once
+    // any axis changes by more than threshold_g. The interrupt sees the
+    // high-pass filtered data, so gravity doesn't count. One threshold step
+    // is range_g / 256.
+    pub fn enable_motion_wake(&self, threshold_g: f32, range_g: u8) -> Result<()> {
+        let threshold = (threshold_g * 256.0 / f32::from(range_g)).round().clamp(1.0, 255.0) as u8;
+
+        self.ag.write_register(CTRL_REG1_G, 0)?;
+        let ctrl_reg6_xl = self.ag.read_register(CTRL_REG6_XL)?;
+        self.ag.write_register(CTRL_REG6_XL, (ctrl_reg6_xl & !CTRL_REG6_XL_ODR_MASK) | CTRL_REG6_XL_ODR_10_HZ)?;
+        let ctrl_reg7_xl = self.ag.read_register(CTRL_REG7_XL)?;
+        self.ag.write_register(CTRL_REG7_XL, ctrl_reg7_xl | CTRL_REG7_XL_HPIS1)?;
+
+        for axis in 0..3 {
+            self.ag.write_register(INT_GEN_THS_X_XL + axis, threshold)?;
+        }
+        self.ag.write_register(INT_GEN_DUR_XL, 0)?;
+        self.ag.write_register(INT_GEN_CFG_XL, INT_GEN_CFG_XL_ANY_HIGH)?;
+        let ctrl_reg4 = self.ag.read_register(CTRL_REG4)?;
+        self.ag.write_register(CTRL_REG4, ctrl_reg4 | CTRL_REG4_LIR_XL1)?;
+        self.ag.write_register(INT1_CTRL, INT1_IG_XL)?;
+
+        // Clears an event latched before, which would wake right away
+        self.ag.read_register(INT_GEN_SRC_XL)?;
+        Ok(())
+    }
+
+    pub fn fifo_status(&self) -> Result<FifoStatus> {
+        let fifo_src = self.ag.read_register(FIFO_SRC)?;
+        Ok(FifoStatus {
+            level: fifo_src & 0x3F,
+            overrun: fifo_src & 0x40 != 0,
+        })
+    }
+}
+
+// The accel/gyro INT1 pin wired to a GPIO. DRDY stays high until the output
+// registers are read, so this triggers on the level: a sample missed while
+// busy fires again right away instead of stalling on a missing edge.
+pub struct DataReady {
+    pin: PinDriver<'static, AnyInputPin, Input>,
+    notification: Notification,
+}
+
+impl DataReady {
+    // Has to be created on the thread that waits, the notification targets
+    // the current task
+    pub fn new(gpio: i32) -> Result<Self> {
+        let mut pin = PinDriver::input(board::input_pin(gpio)?)?;
+        pin.set_interrupt_type(InterruptType::HighLevel)?;
+
+        // The notification value carries the low 32 bits of the timer when
+        // the interrupt fired, so the timestamp isn't delayed by scheduling
+        let notification = Notification::new();
+        let notifier = notification.notifier();
+        unsafe {
+            pin.subscribe(move || {
+                let fired = esp_timer_get_time() as u32;
+                notifier.notify_and_yield(NonZeroU32::new(fired).unwrap_or(NonZeroU32::MIN));
+            })?;
+        }
+        pin.enable_interrupt()?;
+
+        Ok(Self { pin, notification })
+    }
+
+    // Blocks until the sensor signals new data and returns the timer value
+    // at that moment, or None after the timeout
+    pub fn wait(&self, timeout: Duration) -> Option<Duration> {
+        let fired = self.notification.wait(TickType::from(timeout).into())?;
+        let now = unsafe { esp_timer_get_time() } as u64;
+        let elapsed = (now as u32).wrapping_sub(fired.get());
+        Some(Duration::from_micros(now - u64::from(elapsed)))
+    }
+
+    // The interrupt disables itself when it fires. Re-arm after the output
+    // registers were read, the level is still high before that.
+    pub fn rearm(&mut self) -> Result<()> {
+        self.pin.enable_interrupt()?;
+        Ok(())
+    }
+}
+
+fn ag_address_value(address: &AgAddress) -> u8 {
+    match address {
+        AgAddress::_1 => 0x6A,
+        AgAddress::_2 => 0x6B,
+    }
+}
+
+fn mag_address_value(address: &MagAddress) -> u8 {
+    match address {
+        MagAddress::_1 => 0x1C,
+        MagAddress::_2 => 0x1E,
+    }
+}
+
+fn who_am_i(bus: &SharedI2c, address: u8) -> Option<u8> {
+    bus.read_register(address, WHO_AM_I).ok()
+}
+
+// Parses an address setting ("1", "2" or "auto") into the candidates to probe,
+// in the order they should be tried
+fn candidates<A>(setting: &str, first: A, second: A) -> Result<Vec<A>> {
+    match setting {
+        "1" => Ok(vec![first]),
+        "2" => Ok(vec![second]),
+        "auto" => Ok(vec![second, first]),
+        other => bail!("Invalid sensor address setting \"{}\" (expected 1, 2 or auto)", other),
+    }
+}
+
+// For Config::validate(), so a typo doesn't end up as a sensor that can't
+// be found
+pub fn check_address_setting(setting: &str) -> Result<()> {
+    candidates(setting, (), ()).map(drop)
+}
+
+// Whether the accelerometer/gyroscope die answers WHO_AM_I yet, at either
+// address on I2C. Doesn't say which sensor it is, verify_identity() does.
+pub fn responds(bus: &Bus) -> bool {
+    match bus {
+        Bus::I2c(bus) => [AgAddress::_1, AgAddress::_2].iter()
+            .any(|address| who_am_i(bus, ag_address_value(address)) == Some(AG_WHO_AM_I_VALUE)),
+        Bus::Spi { spi, ag_cs, .. } => {
+            let ag = Device::Spi { spi: spi.clone(), cs: ag_cs.clone() };
+            ag.read_register(WHO_AM_I).is_ok_and(|value| value == AG_WHO_AM_I_VALUE)
+        },
+    }
+}
+
+// A configured address is taken as is, verify_identity() checks it. With
+// "auto", the first address answering with the expected WHO_AM_I wins.
+pub fn detect_addresses(bus: &SharedI2c, ag_setting: &str, mag_setting: &str) -> Result<(AgAddress, MagAddress)> {
+
+    let ag_address = pick(candidates(ag_setting, AgAddress::_1, AgAddress::_2)?,
+        |a| who_am_i(bus, ag_address_value(a)) == Some(AG_WHO_AM_I_VALUE));
+    let mag_address = pick(candidates(mag_setting, MagAddress::_1, MagAddress::_2)?,
+        |a| who_am_i(bus, mag_address_value(a)) == Some(MAG_WHO_AM_I_VALUE));
+
+    match (ag_address, mag_address) {
+        (Some(ag), Some(mag)) => {
+            log::info!("Using sensor at AG address {:#04x}, magnetometer address {:#04x}",
+                ag_address_value(&ag), mag_address_value(&mag));
+            Ok((ag, mag))
+        },
+        // Boards with only the accelerometer/gyroscope die populated. The
+        // driver wants a magnetometer address either way, mag_present()
+        // tells that nothing answers there.
+        (Some(ag), None) => {
+            log::info!("Using sensor at AG address {:#04x}, no magnetometer answered WHO_AM_I", ag_address_value(&ag));
+            Ok((ag, MagAddress::_2))
+        },
+        (None, _) => bail!("No accelerometer/gyroscope answered WHO_AM_I at either address"),
+    }
+}
+
+fn pick<A>(mut candidates: Vec<A>, answers: impl Fn(&A) -> bool) -> Option<A> {
+    if candidates.len() == 1 {
+        return candidates.pop();
+    }
+    candidates.into_iter().find(answers)
+}
+
+// Makes sure it's really an LSM9DS1 at this address, a different QWIIC
+// device or an address clash would otherwise only show up as garbage data
+pub fn verify_identity(ag: &Device) -> Result<()> {
+    check_who_am_i(ag, "Accelerometer/gyroscope", AG_WHO_AM_I_VALUE)
+}
+
+// Not every board has the magnetometer die, the others stream without it.
+// Something else answering at its address counts as no magnetometer too.
+pub fn mag_present(mag: &Device) -> bool {
+    match check_who_am_i(mag, "Magnetometer", MAG_WHO_AM_I_VALUE) {
+        Ok(()) => true,
+        Err(e) => {
+            log::warn!("{}", e);
+            false
+        },
+    }
+}
+
+fn check_who_am_i(device: &Device, name: &str, expected: u8) -> Result<()> {
+    match device.read_register(WHO_AM_I) {
+        Ok(value) if value == expected => Ok(()),
+        Ok(value) => bail!("{} {} is not an LSM9DS1: WHO_AM_I is {:#04x}, expected {:#04x}", name, device, value, expected),
+        Err(e) => bail!("{} {} did not answer WHO_AM_I: {}", name, device, e),
+    }
+}
+
+pub fn accel_scale(range_g: u8) -> Result<accel::Scale> {
+    match range_g {
+        2 => Ok(accel::Scale::_2G),
+        4 => Ok(accel::Scale::_4G),
+        8 => Ok(accel::Scale::_8G),
+        16 => Ok(accel::Scale::_16G),
+        other => bail!("Invalid accelerometer range ±{} g (expected 2, 4, 8 or 16)", other),
+    }
+}
+
+pub fn gyro_scale(range_dps: u16) -> Result<gyro::Scale> {
+    match range_dps {
+        245 => Ok(gyro::Scale::_245DPS),
+        500 => Ok(gyro::Scale::_500DPS),
+        2000 => Ok(gyro::Scale::_2000DPS),
+        other => bail!("Invalid gyroscope range ±{} dps (expected 245, 500 or 2000)", other),
+    }
+}
+
+pub fn mag_scale(range_gauss: u8) -> Result<mag::Scale> {
+    match range_gauss {
+        4 => Ok(mag::Scale::_4G),
+        8 => Ok(mag::Scale::_8G),
+        12 => Ok(mag::Scale::_12G),
+        16 => Ok(mag::Scale::_16G),
+        other => bail!("Invalid magnetometer range ±{} gauss (expected 4, 8, 12 or 16)", other),
+    }
+}
+
+// Share of the full scale at which an axis counts as clipped. On the smaller
+// scales the output registers top out just short of the nominal range.
+const CLIP_FRACTION: f32 = 0.99;
+
+// Where the configured full scale ranges put the readings of a saturated
+// axis, in g, dps and gauss
+#[derive(Clone, Copy, Debug)]
+pub struct ClipLimits {
+    accel: f32,
+    gyro: f32,
+    mag: f32,
+}
+
+impl ClipLimits {
+    pub fn new(accel_range_g: u8, gyro_range_dps: u16, mag_range_gauss: u8) -> Self {
+        Self {
+            accel: f32::from(accel_range_g) * CLIP_FRACTION,
+            gyro: f32::from(gyro_range_dps) * CLIP_FRACTION,
+            mag: f32::from(mag_range_gauss) * CLIP_FRACTION,
+        }
+    }
+
+    // One bit per clipped axis: x, y and z of the accelerometer in bits
+    // 0-2, the gyroscope in 3-5 and the magnetometer in 6-8. Takes the
+    // readings before calibration and filtering, as the sensor output them.
+    pub fn clipped_axes(&self, acc: Triple, gyro: Triple, mag: Triple) -> u32 {
+        [(acc, self.accel), (gyro, self.gyro), (mag, self.mag)]
+            .into_iter()
+            .flat_map(|((x, y, z), limit)| [x, y, z].map(|value| value.abs() >= limit))
+            .enumerate()
+            .fold(0, |axes, (bit, clipped)| axes | (u32::from(clipped) << bit))
+    }
+}
+
+// The dies keep their configuration over a reset of the ESP32, so sensors
+// that were enabled before are switched off explicitly. With the gyroscope
+// on, the accelerometer runs along with it whatever CTRL_REG6_XL says. mag is
+// None without a magnetometer.
+pub fn power_down_unused(ag: &Device, mag: Option<&Device>, sensors: Sensors) -> Result<()> {
+    if !sensors.gyro {
+        ag.write_register(CTRL_REG1_G, 0)?;
+    }
+    if !sensors.accel {
+        ag.write_register(CTRL_REG6_XL, 0)?;
+    }
+    if let (false, Some(mag)) = (sensors.mag, mag) {
+        mag.write_register(CTRL_REG3_M, CTRL_REG3_M_POWER_DOWN)?;
+    }
+    Ok(())
+}
+
+// Runs the built-in self-test of each enabled sensor: the output with the
+// test stimulus on has to differ from the one without by the amount the
+// datasheet specifies. Catches damaged dies that still answer WHO_AM_I. The
+// configuration is restored afterwards, before begin_*() sets it up for
+// streaming. The board should lie still for the half second this takes.
+pub fn self_test(ag: &Device, mag: Option<&Device>, sensors: Sensors) -> Result<()> {
+    let saved_ag = [CTRL_REG1_G, CTRL_REG6_XL, CTRL_REG10]
+        .map(|register| ag.read_register(register).map(|value| (register, value)));
+    let saved_mag = mag.map(|mag| (mag, [CTRL_REG1_M, CTRL_REG2_M, CTRL_REG3_M]
+        .map(|register| mag.read_register(register).map(|value| (register, value)))));
+
+    let result = run_self_tests(ag, mag, sensors);
+
+    for (device, saved) in std::iter::once((ag, saved_ag)).chain(saved_mag) {
+        for (register, value) in saved.into_iter().flatten() {
+            device.write_register(register, value)?;
+        }
+    }
+    result
+}
+
+fn run_self_tests(ag: &Device, mag: Option<&Device>, sensors: Sensors) -> Result<()> {
+    if sensors.accel {
+        // Without the gyroscope, the accelerometer runs at its own ODR
+        ag.write_register(CTRL_REG1_G, 0)?;
+        ag.write_register(CTRL_REG6_XL, SELF_TEST_CTRL_REG6_XL)?;
+        let output = |st| {
+            ag.write_register(CTRL_REG10, if st { CTRL_REG10_ST_XL } else { 0 })?;
+            average_output(ag, STATUS_REG, STATUS_REG_XLDA, OUT_X_L_XL, SELF_TEST_ACCEL_SCALE)
+        };
+        check_self_test("Accelerometer", "g", output(false)?, output(true)?, SELF_TEST_ACCEL_LIMITS)?;
+    }
+
+    if sensors.gyro {
+        ag.write_register(CTRL_REG1_G, SELF_TEST_CTRL_REG1_G)?;
+        let output = |st| {
+            ag.write_register(CTRL_REG10, if st { CTRL_REG10_ST_G } else { 0 })?;
+            average_output(ag, STATUS_REG, STATUS_REG_GDA, OUT_X_L_G, SELF_TEST_GYRO_SCALE)
+        };
+        check_self_test("Gyroscope", "dps", output(false)?, output(true)?, SELF_TEST_GYRO_LIMITS)?;
+    }
+    ag.write_register(CTRL_REG10, 0)?;
+
+    if let (true, Some(mag)) = (sensors.mag, mag) {
+        mag.write_register(CTRL_REG2_M, SELF_TEST_CTRL_REG2_M)?;
+        mag.write_register(CTRL_REG3_M, SELF_TEST_CTRL_REG3_M)?;
+        let output = |st| {
+            mag.write_register(CTRL_REG1_M, SELF_TEST_CTRL_REG1_M | if st { CTRL_REG1_M_ST } else { 0 })?;
+            average_output(mag, STATUS_REG_M, STATUS_REG_M_ZYXDA, OUT_X_L_M, SELF_TEST_MAG_SCALE)
+        };
+        check_self_test("Magnetometer", "gauss", output(false)?, output(true)?, SELF_TEST_MAG_LIMITS)?;
+    }
+
+    Ok(())
+}
+
+// X, Y and Z output registers from out_x_l on. Bytes are read one at a time,
+// the magnetometer doesn't auto-increment over SPI.
+fn read_output(device: &Device, out_x_l: u8) -> Result<[u8; 6], EspError> {
+    let mut raw = [0u8; 6];
+    for (offset, byte) in raw.iter_mut().enumerate() {
+        *byte = device.read_register(out_x_l + offset as u8)?;
+    }
+    Ok(raw)
+}
+
+fn axes(raw: [u8; 6]) -> [i16; 3] {
+    [0, 1, 2].map(|axis| i16::from_le_bytes([raw[2 * axis], raw[2 * axis + 1]]))
+}
+
+// Average of SELF_TEST_SAMPLES samples once the output settled, the first
+// one after the wait is dropped
+fn average_output(device: &Device, status_reg: u8, ready: u8, out_x_l: u8, scale: f32) -> Result<[f32; 3]> {
+    std::thread::sleep(SELF_TEST_SETTLE_TIME);
+
+    let mut sum = [0.0; 3];
+    for sample in 0..=SELF_TEST_SAMPLES {
+        let start = std::time::Instant::now();
+        while device.read_register(status_reg)? & ready == 0 {
+            if start.elapsed() >= SELF_TEST_SAMPLE_TIMEOUT {
+                bail!("No data from the sensor {} during self-test", device);
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+
+        let raw = axes(read_output(device, out_x_l)?);
+        if sample == 0 {
+            continue;
+        }
+        for (sum, raw) in sum.iter_mut().zip(raw) {
+            *sum += f32::from(raw) * scale;
+        }
+    }
+
+    Ok(sum.map(|sum| sum / SELF_TEST_SAMPLES as f32))
+}
+
+fn check_self_test(name: &str, unit: &str, off: [f32; 3], on: [f32; 3], limits: [(f32, f32); 3]) -> Result<()> {
+    let change = [0, 1, 2].map(|axis| (on[axis] - off[axis]).abs());
+    log::info!("{} self-test output change: {:.3}, {:.3}, {:.3} {}", name, change[0], change[1], change[2], unit);
+
+    for ((axis, change), (min, max)) in ["X", "Y", "Z"].into_iter().zip(change).zip(limits) {
+        if !(min..=max).contains(&change) {
+            bail!("{} failed self-test: {} changed by {:.3} {}, expected {}-{}", name, axis, change, unit, min, max);
+        }
+    }
+    Ok(())
+}
+
+// With the gyroscope on, the accelerometer runs at its rate whatever its own
+// ODR says, so rates only one of them supports need the gyroscope on or off
+pub fn ag_odr(hz: f32, gyro_on: bool) -> Result<(accel::ODR, gyro::ODR)> {
+    match hz {
+        _ if hz == 10.0 && !gyro_on => Ok((accel::ODR::_10Hz, gyro::ODR::PowerDown)),
+        _ if hz == 14.9 && gyro_on => Ok((accel::ODR::_119Hz, gyro::ODR::_14_9Hz)),
+        _ if hz == 50.0 && !gyro_on => Ok((accel::ODR::_50Hz, gyro::ODR::PowerDown)),
+        _ if hz == 59.5 && gyro_on => Ok((accel::ODR::_119Hz, gyro::ODR::_59_5Hz)),
+        _ if hz == 119.0 => Ok((accel::ODR::_119Hz, gyro::ODR::_119Hz)),
+        _ if hz == 238.0 => Ok((accel::ODR::_238Hz, gyro::ODR::_238Hz)),
+        _ if hz == 476.0 => Ok((accel::ODR::_476Hz, gyro::ODR::_476Hz)),
+        _ if hz == 952.0 => Ok((accel::ODR::_952Hz, gyro::ODR::_952Hz)),
+        _ if gyro_on => bail!("Invalid accelerometer/gyroscope ODR {} Hz (expected 14.9, 59.5, 119, 238, 476 or 952)", hz),
+        _ => bail!("Invalid accelerometer ODR {} Hz without the gyroscope (expected 10, 50, 119, 238, 476 or 952)", hz),
+    }
+}
+
+pub fn mag_odr(hz: f32) -> Result<mag::ODR> {
+    match hz {
+        _ if hz == 0.625 => Ok(mag::ODR::_0_625Hz),
+        _ if hz == 1.25 => Ok(mag::ODR::_1_25Hz),
+        _ if hz == 2.5 => Ok(mag::ODR::_2_5Hz),
+        _ if hz == 5.0 => Ok(mag::ODR::_5Hz),
+        _ if hz == 10.0 => Ok(mag::ODR::_10Hz),
+        _ if hz == 20.0 => Ok(mag::ODR::_20Hz),
+        _ if hz == 40.0 => Ok(mag::ODR::_40Hz),
+        _ if hz == 80.0 => Ok(mag::ODR::_80Hz),
+        _ => bail!("Invalid magnetometer ODR {} Hz (expected 0.625, 1.25, 2.5, 5, 10, 20, 40 or 80)", hz),
+    }
+}
+
+// Per-axis correction applied to raw readings as (raw - offset) * scale
+#[derive(Clone, Copy, Debug)]
+pub struct AxisCalibration {
+    pub offset: [f32; 3],
+    pub scale: [f32; 3],
+}
+
+impl AxisCalibration {
+    pub const IDENTITY: Self = Self { offset: [0.0; 3], scale: [1.0; 3] };
+
+    // Parses comma-separated x,y,z triples, an empty string keeps the identity
+    pub fn parse(offset: &str, scale: &str) -> Result<Self> {
+        Ok(Self {
+            offset: parse_triple(offset, Self::IDENTITY.offset)?,
+            scale: parse_triple(scale, Self::IDENTITY.scale)?,
+        })
+    }
+
+    #[inline]
+    pub fn apply(&self, (x, y, z): (f32, f32, f32)) -> (f32, f32, f32) {
+        (
+            (x - self.offset[0]) * self.scale[0],
+            (y - self.offset[1]) * self.scale[1],
+            (z - self.offset[2]) * self.scale[2],
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Calibration {
+    pub accel: AxisCalibration,
+    pub gyro: AxisCalibration,
+    pub mag: AxisCalibration,
+}
+
+impl Calibration {
+    pub fn apply(&self, (acc, gyro, mag): (Triple, Triple, Triple)) -> (Triple, Triple, Triple) {
+        (self.accel.apply(acc), self.gyro.apply(gyro), self.mag.apply(mag))
+    }
+}
+
+fn parse_triple(value: &str, default: [f32; 3]) -> Result<[f32; 3]> {
+    if value.trim().is_empty() {
+        return Ok(default);
+    }
+
+    let values = value.split(',')
+        .map(|v| v.trim().parse::<f32>())
+        .collect::<Result<Vec<_>, _>>()
+        .or_else(|_| bail!("Invalid number in \"{}\"", value))?;
+
+    match values[..] {
+        [x, y, z] => Ok([x, y, z]),
+        _ => bail!("Expected three comma-separated values, got \"{}\"", value),
+    }
+}
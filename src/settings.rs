@@ -0,0 +1,145 @@
+use anyhow::Result;
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+
+use crate::CONFIG;
+
+const NAMESPACE: &str = "hecate";
+
+// Longest string value we read back, NVS keys themselves are limited to 15
+// characters
+const MAX_VALUE_LEN: usize = 128;
+
+pub const WIFI_SSID: &str = "wifi_ssid";
+pub const WIFI_PSK: &str = "wifi_psk";
+pub const WS_HOST: &str = "ws_host";
+pub const WS_PORT: &str = "ws_port";
+pub const WS_ENDPOINT: &str = "ws_endpoint";
+pub const WS_TOKEN: &str = "ws_token";
+pub const NEOPIXEL_BRIGHTNESS: &str = "px_brightness";
+// Written by the magnetometer calibration rather than by hand
+pub const MAG_OFFSET: &str = "mag_offset";
+// Counters kept across reboots, see main::restore_counters()
+pub const BOOT_COUNT: &str = "boot_count";
+pub const BATCH_TOTAL: &str = "batch_total";
+// Set by the maintenance command rather than by hand
+pub const MAINTENANCE: &str = "maintenance";
+
+// Settings that can be changed in the field without a rebuild. Every value
+// that isn't stored in NVS falls back to the compiled-in CONFIG.
+#[derive(Clone, Debug)]
+pub struct Settings {
+    pub wifi_ssid: String,
+    pub wifi_psk: String,
+    pub ws_host: String,
+    pub ws_port: u16,
+    pub ws_endpoint: String,
+    // Sent as a bearer token with the WebSocket upgrade, empty for none
+    pub ws_token: String,
+    // 0 turns the NeoPixel off, see CONFIG.neopixel_brightness
+    pub neopixel_brightness: u8,
+    // Don't resume sampling after a reboot, see status::set_maintenance()
+    pub maintenance: bool,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            wifi_ssid: CONFIG.wifi_ssid.into(),
+            wifi_psk: CONFIG.wifi_psk.into(),
+            ws_host: CONFIG.ws_host.into(),
+            ws_port: CONFIG.ws_port,
+            ws_endpoint: CONFIG.ws_endpoint.into(),
+            ws_token: CONFIG.ws_token.into(),
+            neopixel_brightness: CONFIG.neopixel_brightness,
+            maintenance: false,
+        }
+    }
+}
+
+pub struct SettingsStore {
+    nvs: EspNvs<NvsDefault>,
+}
+
+impl SettingsStore {
+    pub fn new(partition: EspDefaultNvsPartition) -> Result<Self> {
+        Ok(Self { nvs: EspNvs::new(partition, NAMESPACE, true)? })
+    }
+
+    pub fn load(&self) -> Result<Settings> {
+        let defaults = Settings::default();
+
+        Ok(Settings {
+            wifi_ssid: self.get_str(WIFI_SSID)?.unwrap_or(defaults.wifi_ssid),
+            wifi_psk: self.get_str(WIFI_PSK)?.unwrap_or(defaults.wifi_psk),
+            ws_host: self.get_str(WS_HOST)?.unwrap_or(defaults.ws_host),
+            ws_port: self.nvs.get_u16(WS_PORT)?.unwrap_or(defaults.ws_port),
+            ws_endpoint: self.get_str(WS_ENDPOINT)?.unwrap_or(defaults.ws_endpoint),
+            ws_token: self.get_str(WS_TOKEN)?.unwrap_or(defaults.ws_token),
+            neopixel_brightness: self.nvs.get_u8(NEOPIXEL_BRIGHTNESS)?.unwrap_or(defaults.neopixel_brightness),
+            maintenance: self.nvs.get_u8(MAINTENANCE)?.map_or(defaults.maintenance, |value| value != 0),
+        })
+    }
+
+    pub fn save(&mut self, settings: &Settings) -> Result<()> {
+        self.set_str(WIFI_SSID, &settings.wifi_ssid)?;
+        self.set_str(WIFI_PSK, &settings.wifi_psk)?;
+        self.set_str(WS_HOST, &settings.ws_host)?;
+        self.nvs.set_u16(WS_PORT, settings.ws_port)?;
+        self.set_str(WS_ENDPOINT, &settings.ws_endpoint)?;
+        self.set_str(WS_TOKEN, &settings.ws_token)?;
+        self.nvs.set_u8(NEOPIXEL_BRIGHTNESS, settings.neopixel_brightness)?;
+        self.save_maintenance(settings.maintenance)
+    }
+
+    pub fn save_maintenance(&mut self, enabled: bool) -> Result<()> {
+        self.nvs.set_u8(MAINTENANCE, enabled.into())?;
+        Ok(())
+    }
+
+    // Written by the setup page, the rest of the settings stays as it is
+    pub fn save_wifi(&mut self, ssid: &str, psk: &str) -> Result<()> {
+        self.set_str(WIFI_SSID, ssid)?;
+        self.set_str(WIFI_PSK, psk)?;
+        Ok(())
+    }
+
+    // Removing a key makes it fall back to the compiled default again
+    pub fn remove(&mut self, key: &str) -> Result<()> {
+        self.nvs.remove(key)?;
+        Ok(())
+    }
+
+    // Hard-iron offset as x, y, z f32s, takes precedence over
+    // CONFIG.mag_offset
+    pub fn load_mag_offset(&self) -> Result<Option<[f32; 3]>> {
+        let mut buf = [0u8; 12];
+        Ok(self.nvs.get_raw(MAG_OFFSET, &mut buf)?
+            .and_then(|raw| <[u8; 12]>::try_from(raw).ok())
+            .map(|raw| [0, 1, 2].map(|axis| f32::from_le_bytes(raw[axis * 4..axis * 4 + 4].try_into().unwrap()))))
+    }
+
+    pub fn save_mag_offset(&mut self, offset: [f32; 3]) -> Result<()> {
+        let raw = offset.map(f32::to_le_bytes).concat();
+        self.nvs.set_raw(MAG_OFFSET, &raw)?;
+        Ok(())
+    }
+
+    pub fn load_u32(&self, key: &str) -> Result<Option<u32>> {
+        Ok(self.nvs.get_u32(key)?)
+    }
+
+    pub fn save_u32(&mut self, key: &str, value: u32) -> Result<()> {
+        self.nvs.set_u32(key, value)?;
+        Ok(())
+    }
+
+    fn get_str(&self, key: &str) -> Result<Option<String>> {
+        let mut buf = [0u8; MAX_VALUE_LEN];
+        Ok(self.nvs.get_str(key, &mut buf)?.map(String::from))
+    }
+
+    fn set_str(&mut self, key: &str, value: &str) -> Result<()> {
+        self.nvs.set_str(key, value)?;
+        Ok(())
+    }
+}
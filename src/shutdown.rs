@@ -0,0 +1,55 @@
+use std::cell::Cell;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::time::{Duration, Instant};
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+// Threads holding a Running guard
+static RUNNING: AtomicU32 = AtomicU32::new(0);
+
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+thread_local! {
+    static REGISTERED: Cell<bool> = const { Cell::new(false) };
+}
+
+// Held by a worker thread for as long as it touches hardware or the network
+pub struct Running(());
+
+pub fn register() -> Running {
+    RUNNING.fetch_add(1, Ordering::SeqCst);
+    REGISTERED.with(|registered| registered.set(true));
+    Running(())
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        REGISTERED.with(|registered| registered.set(false));
+        RUNNING.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// Checked at the top of every worker loop, which returns once it's set
+pub fn requested() -> bool {
+    REQUESTED.load(Ordering::Relaxed)
+}
+
+// Asks the worker threads to stop and waits for them up to timeout, so a
+// restart doesn't cut off an I2C transaction or a frame being sent. Called
+// from a worker thread, that one isn't waited for. Returns whether all
+// threads stopped in time, there's no way to force the others.
+pub fn request(timeout: Duration) -> bool {
+    REQUESTED.store(true, Ordering::Relaxed);
+
+    let own = u32::from(REGISTERED.with(Cell::get));
+    let deadline = Instant::now() + timeout;
+    while RUNNING.load(Ordering::SeqCst) > own {
+        if Instant::now() >= deadline {
+            log::warn!("{} threads still running after {:?}", RUNNING.load(Ordering::SeqCst) - own, timeout);
+            return false;
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    }
+
+    true
+}
@@ -0,0 +1,249 @@
+use anyhow::{bail, Result};
+use esp_idf_svc::sys::{esp, esp_vfs_spiffs_conf_t, esp_vfs_spiffs_register};
+use hecate_protobuf::{Message, SensorDataSample};
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, Read, Write};
+use std::sync::{Mutex, OnceLock};
+
+// Samples that didn't fit into the ringbuffer while the collector was
+// unreachable, kept on flash until they can be sent. They're stored as
+// length-delimited SensorDataSamples in numbered segment files, so replayed
+// data can be freed a segment at a time. The file names also carry the boot
+// count, sample times only mean something together with it.
+//
+// SPIFFS spreads writes over the whole partition, and every sample is only
+// written and erased once. The flash (rated for ~100k erase cycles per
+// sector) should take years of daily outages, but permanent spilling, e.g.
+// because the collector is gone for good, wears it steadily.
+
+const MOUNT_POINT: &str = "/spiffs";
+const PARTITION_LABEL: &[u8] = b"storage\0";
+const SEGMENT_LEN: u64 = 16 * 1024;
+
+static SPILL: OnceLock<Mutex<Spill>> = OnceLock::new();
+
+struct Spill {
+    max_segments: u32,
+    boot_count: u32,
+    // Segments left over from before a reboot and the boot they're from
+    earlier_boots: BTreeMap<u32, u32>,
+    // Oldest segment on flash and the one currently being written. Equal
+    // when there's at most one segment.
+    first: u32,
+    current: u32,
+    writer: Option<(BufWriter<File>, u64)>,
+    // The first segment, once replayed, while the collector hasn't
+    // acknowledged it yet. Nothing else is replayed meanwhile.
+    awaiting_ack: Option<u32>,
+}
+
+pub struct Segment {
+    index: u32,
+    // The boot the samples were recorded in, their times count from its start
+    pub boot_count: u32,
+    pub samples: Vec<SensorDataSample>,
+}
+
+impl Segment {
+    // What the collector acknowledges it by
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+}
+
+fn segment_path(index: u32, boot_count: u32) -> String {
+    format!("{}/spill-{:08}-{:08}.pb", MOUNT_POINT, index, boot_count)
+}
+
+// The index and boot count
+fn parse_segment_name(name: &str) -> Option<(u32, u32)> {
+    let (index, boot_count) = name.strip_prefix("spill-")?.strip_suffix(".pb")?.split_once('-')?;
+    Some((index.parse().ok()?, boot_count.parse().ok()?))
+}
+
+// Mounts the storage partition and picks up segments left over from before
+// a reboot. Without this, store() always returns false.
+pub fn init(max_bytes: u32, boot_count: u32) -> Result<()> {
+    let config = esp_vfs_spiffs_conf_t {
+        base_path: b"/spiffs\0".as_ptr() as *const _,
+        partition_label: PARTITION_LABEL.as_ptr() as *const _,
+        max_files: 4,
+        format_if_mount_failed: true,
+    };
+    esp!(unsafe { esp_vfs_spiffs_register(&config) })?;
+
+    let earlier_boots = fs::read_dir(MOUNT_POINT)?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| parse_segment_name(&entry.file_name().to_string_lossy()))
+        .collect::<BTreeMap<_, _>>();
+    let first = earlier_boots.keys().next().copied().unwrap_or(0);
+    // Never append to a segment from before the reboot, it might end in a
+    // partially written sample
+    let current = earlier_boots.keys().next_back().map_or(0, |last| last + 1);
+
+    let max_segments = (u64::from(max_bytes) / SEGMENT_LEN).max(2) as u32;
+    if !earlier_boots.is_empty() {
+        log::info!("Found {} spilled segments on flash", earlier_boots.len());
+    }
+
+    let spill = Spill { max_segments, boot_count, earlier_boots, first, current, writer: None, awaiting_ack: None };
+    if SPILL.set(Mutex::new(spill)).is_err() {
+        bail!("Spill storage is already initialized");
+    }
+
+    Ok(())
+}
+
+fn spill() -> Option<std::sync::MutexGuard<'static, Spill>> {
+    Some(SPILL.get()?.lock().unwrap_or_else(|e| e.into_inner()))
+}
+
+// Returns whether the sample was stored
+pub fn store(sample: &SensorDataSample) -> bool {
+    let Some(mut spill) = spill() else {
+        return false;
+    };
+
+    spill.store(sample)
+        .inspect_err(|e| log::warn!("Failed to spill sample to flash: {}", e))
+        .is_ok()
+}
+
+// Also false while a replayed segment waits for its acknowledgement
+pub fn has_pending() -> bool {
+    spill().is_some_and(|spill| spill.awaiting_ack.is_none() && (spill.first != spill.current || spill.writer.is_some()))
+}
+
+// The oldest segment's samples. It stays on flash until remove() confirms
+// it was sent.
+pub fn oldest() -> Option<Segment> {
+    let mut spill = spill()?;
+    if spill.first == spill.current {
+        // Only the segment being written is left, finish it so it can be
+        // replayed
+        spill.writer.as_ref()?;
+        spill.rotate();
+    }
+
+    let index = spill.first;
+    let boot_count = spill.boot_count_of(index);
+    match read_segment(&segment_path(index, boot_count)) {
+        Ok(samples) => Some(Segment { index, boot_count, samples }),
+        Err(e) => {
+            log::warn!("Dropping unreadable spill segment {}: {}", index, e);
+            spill.remove_first();
+            None
+        },
+    }
+}
+
+pub fn remove(segment: Segment) {
+    if let Some(mut spill) = spill() {
+        if spill.first == segment.index {
+            spill.remove_first();
+        }
+    }
+}
+
+// Keeps a replayed segment on flash until ack() is called for it
+pub fn await_ack(segment: Segment) {
+    if let Some(mut spill) = spill() {
+        spill.awaiting_ack = Some(segment.index);
+    }
+}
+
+pub fn awaiting_ack() -> bool {
+    spill().is_some_and(|spill| spill.awaiting_ack.is_some())
+}
+
+// Returns whether index was the segment waiting for acknowledgement, which
+// is deleted then
+pub fn ack(index: u32) -> bool {
+    let Some(mut spill) = spill() else {
+        return false;
+    };
+    if spill.awaiting_ack != Some(index) {
+        return false;
+    }
+
+    spill.awaiting_ack = None;
+    spill.remove_first();
+    true
+}
+
+// For a new session: whatever wasn't acknowledged in the last one is
+// replayed again
+pub fn retry_unacked() {
+    if let Some(mut spill) = spill() {
+        if let Some(index) = spill.awaiting_ack.take() {
+            log::info!("Spill segment {} wasn't acknowledged, replaying it again", index);
+        }
+    }
+}
+
+impl Spill {
+    fn store(&mut self, sample: &SensorDataSample) -> Result<()> {
+        if self.writer.as_ref().is_some_and(|(_, len)| *len >= SEGMENT_LEN) {
+            self.rotate();
+        }
+
+        // Bounded by dropping the oldest segment, recent data is worth more
+        if self.current - self.first >= self.max_segments {
+            log::warn!("Spill storage full, dropping oldest segment {}", self.first);
+            self.remove_first();
+        }
+
+        if self.writer.is_none() {
+            let file = OpenOptions::new().create(true).append(true).open(segment_path(self.current, self.boot_count))?;
+            self.writer = Some((BufWriter::new(file), 0));
+        }
+
+        let (writer, len) = self.writer.as_mut().unwrap();
+        let buf = sample.encode_length_delimited_to_vec();
+        writer.write_all(&buf)?;
+        *len += buf.len() as u64;
+
+        Ok(())
+    }
+
+    fn rotate(&mut self) {
+        if let Some((mut writer, _)) = self.writer.take() {
+            _ = writer.flush()
+                .inspect_err(|e| log::warn!("Failed to flush spill segment {}: {}", self.current, e));
+            self.current += 1;
+        }
+    }
+
+    fn boot_count_of(&self, index: u32) -> u32 {
+        self.earlier_boots.get(&index).copied().unwrap_or(self.boot_count)
+    }
+
+    fn remove_first(&mut self) {
+        if self.awaiting_ack == Some(self.first) {
+            self.awaiting_ack = None;
+        }
+        _ = fs::remove_file(segment_path(self.first, self.boot_count_of(self.first)));
+        self.earlier_boots.remove(&self.first);
+        if self.first < self.current {
+            self.first += 1;
+        }
+    }
+}
+
+fn read_segment(path: &str) -> Result<Vec<SensorDataSample>> {
+    let mut buf = Vec::new();
+    File::open(path)?.read_to_end(&mut buf)?;
+
+    let mut samples = Vec::new();
+    let mut remaining = buf.as_slice();
+    while !remaining.is_empty() {
+        match SensorDataSample::decode_length_delimited(&mut remaining) {
+            Ok(sample) => samples.push(sample),
+            // A sample cut off by a reset, everything before it is fine
+            Err(_) => break,
+        }
+    }
+
+    Ok(samples)
+}
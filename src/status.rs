@@ -0,0 +1,148 @@
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU8, Ordering};
+use std::time::Duration;
+
+use crate::telemetry;
+
+// Overall device state, set by the threads as they go and shown by the
+// status indicators
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(u8)]
+pub enum State {
+    Booting,
+    ConnectingWifi,
+    WifiUp,
+    Streaming,
+    // Streaming, but the sampling thread hasn't produced a sample for
+    // STALL_TIMEOUT. Never set, get() derives it.
+    Stalled,
+    // Streaming with sampling paused by the maintenance command, derived
+    // like Stalled
+    Maintenance,
+    // Store and forward between bursts, with the radio off
+    Storing,
+    // Serving the WiFi setup network, see provision.rs
+    Provisioning,
+    SensorError,
+    // A WiFi SSID or PSK that can't be used, see credentials::check_credentials()
+    CredentialsError,
+    Error,
+    // Shown for the few seconds between a panic and the restart
+    Panic,
+}
+
+impl State {
+    pub fn name(self) -> &'static str {
+        match self {
+            State::Booting => "booting",
+            State::ConnectingWifi => "connecting_wifi",
+            State::WifiUp => "wifi_up",
+            State::Streaming => "streaming",
+            State::Stalled => "stalled",
+            State::Maintenance => "maintenance",
+            State::Storing => "storing",
+            State::Provisioning => "provisioning",
+            State::SensorError => "sensor_error",
+            State::CredentialsError => "credentials_error",
+            State::Error => "error",
+            State::Panic => "panic",
+        }
+    }
+}
+
+const NO_FAULT: u8 = u8::MAX;
+
+// Longer than a sample takes at the lowest rate the rate command accepts,
+// validate() keeps the configured intervals below it too
+pub const STALL_TIMEOUT: Duration = Duration::from_secs(3);
+
+static STATE: AtomicU8 = AtomicU8::new(State::Booting as u8);
+
+// Sticky, the failures reported here don't recover on their own. It takes
+// precedence over the regular state.
+static FAULT: AtomicU8 = AtomicU8::new(NO_FAULT);
+
+// Uptime in ms when the last sample was taken, 0 before the first. Wraps
+// after 49 days, which only matters for a sampling thread that stalled
+// exactly then.
+static LAST_SAMPLE_MS: AtomicU32 = AtomicU32::new(0);
+
+// Called by the sampling thread for every sample it takes, shown or not
+pub fn record_sample() {
+    LAST_SAMPLE_MS.store(telemetry::uptime().as_millis() as u32, Ordering::Relaxed);
+}
+
+fn samples_flowing() -> bool {
+    let last = LAST_SAMPLE_MS.load(Ordering::Relaxed);
+    let now = telemetry::uptime().as_millis() as u32;
+    last != 0 && now.wrapping_sub(last) < STALL_TIMEOUT.as_millis() as u32
+}
+
+// Shown on top of the regular state rather than replacing it
+static LOW_BATTERY: AtomicBool = AtomicBool::new(false);
+
+pub fn set_low_battery(low: bool) {
+    LOW_BATTERY.store(low, Ordering::Relaxed);
+}
+
+pub fn low_battery() -> bool {
+    LOW_BATTERY.load(Ordering::Relaxed)
+}
+
+// Sampling is paused while set, see State::Maintenance
+static MAINTENANCE: AtomicBool = AtomicBool::new(false);
+
+pub fn set_maintenance(enabled: bool) {
+    MAINTENANCE.store(enabled, Ordering::Relaxed);
+}
+
+pub fn maintenance() -> bool {
+    MAINTENANCE.load(Ordering::Relaxed)
+}
+
+pub fn set(state: State) {
+    STATE.store(state as u8, Ordering::Relaxed);
+}
+
+pub fn fail(fault: State) {
+    FAULT.store(fault as u8, Ordering::Relaxed);
+}
+
+// For when whatever failed was restarted. Other faults stay in place.
+pub fn clear_fault(fault: State) {
+    _ = FAULT.compare_exchange(fault as u8, NO_FAULT, Ordering::Relaxed, Ordering::Relaxed);
+}
+
+// The most severe problem there is: a fault, then WiFi, the collector
+// connection and the samples, in that order. Streaming means all of them
+// are fine.
+pub fn get() -> State {
+    match FAULT.load(Ordering::Relaxed) {
+        NO_FAULT => match from_u8(STATE.load(Ordering::Relaxed)) {
+            State::Streaming if maintenance() => State::Maintenance,
+            State::Streaming if !samples_flowing() => State::Stalled,
+            state => state,
+        },
+        fault => from_u8(fault),
+    }
+}
+
+// Whether the networking thread got as far as the collector, whatever the
+// samples do
+pub fn collector_connected(state: State) -> bool {
+    matches!(state, State::Streaming | State::Stalled | State::Maintenance)
+}
+
+fn from_u8(value: u8) -> State {
+    match value {
+        v if v == State::Booting as u8 => State::Booting,
+        v if v == State::ConnectingWifi as u8 => State::ConnectingWifi,
+        v if v == State::WifiUp as u8 => State::WifiUp,
+        v if v == State::Streaming as u8 => State::Streaming,
+        v if v == State::Storing as u8 => State::Storing,
+        v if v == State::Provisioning as u8 => State::Provisioning,
+        v if v == State::SensorError as u8 => State::SensorError,
+        v if v == State::CredentialsError as u8 => State::CredentialsError,
+        v if v == State::Panic as u8 => State::Panic,
+        _ => State::Error,
+    }
+}
@@ -0,0 +1,78 @@
+use anyhow::Result;
+use esp_idf_svc::{
+    http::{
+        server::{Configuration, EspHttpServer},
+        Method,
+    },
+    io::Write,
+};
+use serde::Serialize;
+
+use crate::{status, telemetry, wifi};
+
+// Read-only status at http://<device-ip>/status, for checking on a unit in
+// the field without the collector. It answers as long as the returned
+// server is kept alive.
+
+#[derive(Debug, Serialize)]
+struct StatusPage<'a> {
+    firmware_version: &'a str,
+    device_id: &'a str,
+    state: &'static str,
+    wifi_connected: bool,
+    collector_connected: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rssi: Option<i8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rssi_smoothed: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    link_quality: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ip: Option<String>,
+    uptime_s: u32,
+    free_heap: u32,
+    dropped_samples: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sample_rate_hz: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    achieved_sample_rate_hz: Option<f32>,
+}
+
+fn status_json(firmware_version: &str, device_id: &str) -> serde_json::Result<String> {
+    let state = status::get();
+    let page = StatusPage {
+        firmware_version,
+        device_id,
+        state: state.name(),
+        wifi_connected: state == status::State::WifiUp || status::collector_connected(state),
+        collector_connected: status::collector_connected(state),
+        rssi: wifi::rssi(),
+        rssi_smoothed: wifi::smoothed_rssi(),
+        link_quality: wifi::link_quality().map(wifi::LinkQuality::name),
+        ip: wifi::ipv4_address().map(|ip| ip.to_string()),
+        uptime_s: telemetry::uptime().as_secs() as u32,
+        free_heap: telemetry::free_heap(),
+        dropped_samples: telemetry::dropped_samples(),
+        sample_rate_hz: telemetry::sample_rate_hz(),
+        achieved_sample_rate_hz: telemetry::achieved_sample_rate_hz(),
+    };
+    serde_json::to_string(&page)
+}
+
+pub fn start(port: u16, firmware_version: &'static str, device_id: &str) -> Result<EspHttpServer<'static>> {
+    let mut server = EspHttpServer::new(&Configuration {
+        http_port: port,
+        ..Default::default()
+    })?;
+
+    let device_id = device_id.to_string();
+    server.fn_handler("/status", Method::Get, move |request| -> Result<()> {
+        let json = status_json(firmware_version, &device_id)?;
+        let mut response = request.into_response(200, Some("OK"), &[("Content-Type", "application/json")])?;
+        response.write_all(json.as_bytes())?;
+        Ok(())
+    })?;
+
+    log::info!("Status page at port {}, /status", port);
+    Ok(server)
+}
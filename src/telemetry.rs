@@ -0,0 +1,675 @@
+use esp_idf_svc::sys::{
+    esp_get_free_heap_size, esp_get_minimum_free_heap_size, esp_timer_get_time,
+    uxTaskGetStackHighWaterMark,
+};
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::ptr;
+use std::sync::{Mutex, OnceLock};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
+
+use crate::batch::SessionDigest;
+use crate::transport::ReadBuffer;
+
+// Latest sensor die temperature as f32 bits, NaN until the first reading
+static TEMPERATURE: AtomicU32 = AtomicU32::new(0x7FC0_0000);
+
+pub fn set_temperature(temperature: Option<f32>) {
+    TEMPERATURE.store(temperature.unwrap_or(f32::NAN).to_bits(), Ordering::Relaxed);
+}
+
+pub fn temperature() -> Option<f32> {
+    let temperature = f32::from_bits(TEMPERATURE.load(Ordering::Relaxed));
+    (!temperature.is_nan()).then_some(temperature)
+}
+
+// Rate the sampling thread produces samples at as f32 bits, NaN until it
+// started
+static SAMPLE_RATE: AtomicU32 = AtomicU32::new(0x7FC0_0000);
+
+pub fn set_sample_rate_hz(rate: f32) {
+    SAMPLE_RATE.store(rate.to_bits(), Ordering::Relaxed);
+}
+
+pub fn sample_rate_hz() -> Option<f32> {
+    let rate = f32::from_bits(SAMPLE_RATE.load(Ordering::Relaxed));
+    (!rate.is_nan()).then_some(rate)
+}
+
+// Rate samples were actually produced at recently as f32 bits, NaN until
+// it was first measured. Falls short of the target when reading the sensor
+// takes longer than the interval or the thread doesn't get scheduled.
+static ACHIEVED_SAMPLE_RATE: AtomicU32 = AtomicU32::new(0x7FC0_0000);
+
+pub fn set_achieved_sample_rate_hz(rate: Option<f32>) {
+    ACHIEVED_SAMPLE_RATE.store(rate.unwrap_or(f32::NAN).to_bits(), Ordering::Relaxed);
+}
+
+pub fn achieved_sample_rate_hz() -> Option<f32> {
+    let rate = f32::from_bits(ACHIEVED_SAMPLE_RATE.load(Ordering::Relaxed));
+    (!rate.is_nan()).then_some(rate)
+}
+
+// Spread of the intervals between consecutive sample timestamps over the
+// last window, polling only. With a FIFO the timestamps are paced by the
+// sensor's ODR, so there's nothing to measure.
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct SampleJitter {
+    pub min_us: u32,
+    pub max_us: u32,
+    pub mean_us: f32,
+    pub stddev_us: f32,
+}
+
+static SAMPLE_JITTER: Mutex<Option<SampleJitter>> = Mutex::new(None);
+
+pub fn set_sample_jitter(jitter: Option<SampleJitter>) {
+    if let Ok(mut sample_jitter) = SAMPLE_JITTER.lock() {
+        *sample_jitter = jitter;
+    }
+}
+
+pub fn sample_jitter() -> Option<SampleJitter> {
+    SAMPLE_JITTER.lock().ok().and_then(|sample_jitter| *sample_jitter)
+}
+
+// Latest integrated gyroscope angle per axis in degrees, only with
+// gyro_integrate
+static GYRO_ANGLE: Mutex<Option<(f32, f32, f32)>> = Mutex::new(None);
+
+pub fn set_gyro_angle(angle: Option<(f32, f32, f32)>) {
+    if let Ok(mut gyro_angle) = GYRO_ANGLE.lock() {
+        *gyro_angle = angle;
+    }
+}
+
+pub fn gyro_angle() -> Option<(f32, f32, f32)> {
+    GYRO_ANGLE.lock().ok().and_then(|gyro_angle| *gyro_angle)
+}
+
+// Latest battery voltage in mV, 0 until the first reading
+static BATTERY_MV: AtomicU32 = AtomicU32::new(0);
+
+pub fn set_battery_mv(battery_mv: Option<u32>) {
+    BATTERY_MV.store(battery_mv.unwrap_or(0), Ordering::Relaxed);
+}
+
+pub fn battery_mv() -> Option<u32> {
+    match BATTERY_MV.load(Ordering::Relaxed) {
+        0 => None,
+        battery_mv => Some(battery_mv),
+    }
+}
+
+// Samples lost to a full ringbuffer before they were sent, overwritten or
+// never stored depending on the overflow policy. There are no 64-bit
+// atomics on this target, at 100 Hz a u32 doesn't wrap for over a year.
+// The stats command can reset it.
+static DROPPED_SAMPLES: AtomicU32 = AtomicU32::new(0);
+
+pub fn record_dropped_sample() {
+    DROPPED_SAMPLES.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn dropped_samples() -> u32 {
+    DROPPED_SAMPLES.load(Ordering::Relaxed)
+}
+
+// Batches dropped because they couldn't be encoded into a frame, live or
+// replayed from flash. The stats command can reset it.
+static SKIPPED_BATCHES: AtomicU32 = AtomicU32::new(0);
+
+// Returns the new total, for the log line
+pub fn record_skipped_batch() -> u32 {
+    SKIPPED_BATCHES.fetch_add(1, Ordering::Relaxed) + 1
+}
+
+pub fn skipped_batches() -> u32 {
+    SKIPPED_BATCHES.load(Ordering::Relaxed)
+}
+
+// Successful reconnects to the collector and batches it took, since boot or
+// the last reset by the stats command
+static RECONNECTS: AtomicU32 = AtomicU32::new(0);
+static BATCHES_SENT: AtomicU32 = AtomicU32::new(0);
+
+pub fn record_reconnect() {
+    RECONNECTS.fetch_add(1, Ordering::Relaxed);
+}
+
+// Never reset, for rates over an interval. The byte count wraps.
+static FRAMES_TOTAL: AtomicU32 = AtomicU32::new(0);
+static FRAME_BYTES_TOTAL: AtomicU32 = AtomicU32::new(0);
+
+// Batches sent over the life of the device, continued from NVS at boot.
+// Unlike seq it never starts over, so together with BOOT_COUNT it tells the
+// collector about reboots and batches lost to them.
+static BATCH_TOTAL: AtomicU32 = AtomicU32::new(0);
+static BOOT_COUNT: AtomicU32 = AtomicU32::new(0);
+
+pub fn restore_counters(boot_count: u32, batch_total: u32) {
+    BOOT_COUNT.store(boot_count, Ordering::Relaxed);
+    BATCH_TOTAL.store(batch_total, Ordering::Relaxed);
+}
+
+pub fn boot_count() -> u32 {
+    BOOT_COUNT.load(Ordering::Relaxed)
+}
+
+pub fn batch_total() -> u32 {
+    BATCH_TOTAL.load(Ordering::Relaxed)
+}
+
+// Batch seq went out as frame
+pub fn record_batch_sent(seq: u32, frame: &[u8]) {
+    BATCHES_SENT.fetch_add(1, Ordering::Relaxed);
+    BATCH_TOTAL.fetch_add(1, Ordering::Relaxed);
+    FRAMES_TOTAL.fetch_add(1, Ordering::Relaxed);
+    FRAME_BYTES_TOTAL.fetch_add(frame.len() as u32, Ordering::Relaxed);
+    if let Ok(mut digest) = SESSION_DIGEST.lock() {
+        if let Some(digest) = digest.as_mut() {
+            digest.add(seq, frame);
+        }
+    }
+}
+
+// Digest of the batches sent since the last hello, only kept with
+// session_digest
+static SESSION_DIGEST: Mutex<Option<SessionDigest>> = Mutex::new(None);
+
+pub fn start_session_digest(first_seq: u32) {
+    if let Ok(mut digest) = SESSION_DIGEST.lock() {
+        *digest = Some(SessionDigest::new(first_seq));
+    }
+}
+
+pub fn take_session_digest() -> Option<SessionDigest> {
+    SESSION_DIGEST.lock().ok().and_then(|mut digest| digest.take())
+}
+
+// Data frames and their bytes sent since boot
+pub fn frame_totals() -> (u32, u32) {
+    (FRAMES_TOTAL.load(Ordering::Relaxed), FRAME_BYTES_TOTAL.load(Ordering::Relaxed))
+}
+
+// Samples the deadband left out since boot
+static SUPPRESSED_SAMPLES: AtomicU32 = AtomicU32::new(0);
+
+pub fn record_suppressed_sample() {
+    SUPPRESSED_SAMPLES.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn suppressed_samples() -> u32 {
+    SUPPRESSED_SAMPLES.load(Ordering::Relaxed)
+}
+
+// Events detected in the filtered acceleration since boot
+static EVENTS: AtomicU32 = AtomicU32::new(0);
+
+pub fn record_event() {
+    EVENTS.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn events() -> u32 {
+    EVENTS.load(Ordering::Relaxed)
+}
+
+// Samples with an axis at the end of its full scale range since boot, and
+// the axes that were since the last status message (see
+// sensor::ClipLimits::clipped_axes())
+static CLIPPED_SAMPLES: AtomicU32 = AtomicU32::new(0);
+static CLIPPED_AXES: AtomicU32 = AtomicU32::new(0);
+
+pub fn record_clipped_sample(axes: u32) {
+    CLIPPED_SAMPLES.fetch_add(1, Ordering::Relaxed);
+    CLIPPED_AXES.fetch_or(axes, Ordering::Relaxed);
+}
+
+pub fn clipped_samples() -> u32 {
+    CLIPPED_SAMPLES.load(Ordering::Relaxed)
+}
+
+pub fn take_clipped_axes() -> u32 {
+    CLIPPED_AXES.swap(0, Ordering::Relaxed)
+}
+
+// Encoded messages waiting for the transport, and those the send queue
+// policy dropped since boot
+static SEND_QUEUE_DEPTH: AtomicU32 = AtomicU32::new(0);
+static DROPPED_FRAMES: AtomicU32 = AtomicU32::new(0);
+
+pub fn set_send_queue_depth(depth: usize) {
+    SEND_QUEUE_DEPTH.store(depth as u32, Ordering::Relaxed);
+}
+
+pub fn send_queue_depth() -> u32 {
+    SEND_QUEUE_DEPTH.load(Ordering::Relaxed)
+}
+
+pub fn record_dropped_frame() {
+    DROPPED_FRAMES.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn dropped_frames() -> u32 {
+    DROPPED_FRAMES.load(Ordering::Relaxed)
+}
+
+// The transport's receive buffer as of the last status message
+static READ_BUFFER: Mutex<Option<ReadBuffer>> = Mutex::new(None);
+
+pub fn set_read_buffer(read_buffer: Option<ReadBuffer>) {
+    if let Ok(mut current) = READ_BUFFER.lock() {
+        *current = read_buffer;
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Task {
+    Networking,
+    Sampling,
+}
+
+// Least free stack space each task had so far in bytes, 0 until it reported
+static STACK_HIGH_WATER: [AtomicU32; 2] = [AtomicU32::new(0), AtomicU32::new(0)];
+
+// Called by the task itself. FreeRTOS tracks the mark for us, this only
+// walks the part of the stack that was never used.
+pub fn record_stack_high_water(task: Task) {
+    let free = unsafe { uxTaskGetStackHighWaterMark(ptr::null_mut()) };
+    STACK_HIGH_WATER[task as usize].store(free, Ordering::Relaxed);
+}
+
+#[derive(Debug, Serialize)]
+pub struct Diagnostics {
+    pub free_heap: u32,
+    // Lowest free heap since boot
+    pub min_free_heap: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub networking_stack_free: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sampling_stack_free: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub achieved_sample_rate_hz: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sample_jitter: Option<SampleJitter>,
+    pub send_queue_depth: u32,
+    pub dropped_frames: u32,
+    pub skipped_batches: u32,
+    pub suppressed_samples: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub read_buffer: Option<ReadBuffer>,
+}
+
+pub fn free_heap() -> u32 {
+    unsafe { esp_get_free_heap_size() }
+}
+
+pub fn uptime() -> Duration {
+    Duration::from_micros(unsafe { esp_timer_get_time() } as u64)
+}
+
+pub fn diagnostics() -> Diagnostics {
+    let stack_free = |task: Task| match STACK_HIGH_WATER[task as usize].load(Ordering::Relaxed) {
+        0 => None,
+        free => Some(free),
+    };
+
+    Diagnostics {
+        free_heap: free_heap(),
+        min_free_heap: unsafe { esp_get_minimum_free_heap_size() },
+        networking_stack_free: stack_free(Task::Networking),
+        sampling_stack_free: stack_free(Task::Sampling),
+        achieved_sample_rate_hz: achieved_sample_rate_hz(),
+        sample_jitter: sample_jitter(),
+        send_queue_depth: send_queue_depth(),
+        dropped_frames: dropped_frames(),
+        skipped_batches: skipped_batches(),
+        suppressed_samples: suppressed_samples(),
+        read_buffer: READ_BUFFER.lock().ok().and_then(|read_buffer| *read_buffer),
+    }
+}
+
+// Timer value (time since boot) that sample timestamps count from
+static TIME_ORIGIN: OnceLock<Duration> = OnceLock::new();
+
+// Returns the origin in effect. Only the first call sets it, so a restarted
+// sampling thread keeps counting from where the first one started.
+pub fn init_time_origin(since_boot: Duration) -> Duration {
+    *TIME_ORIGIN.get_or_init(|| since_boot)
+}
+
+// UTC time of the sample time origin in microseconds since the epoch. Adding
+// a sample's time gives its absolute timestamp, drift corrected by
+// clock::since_origin(). None until the clock was synced.
+pub fn time_origin_utc() -> Option<u64> {
+    crate::clock::utc_of(*TIME_ORIGIN.get()?)
+}
+
+// Status sent to the collector as a JSON text frame, next to the binary
+// sensor data. Values that couldn't be read are left out. It goes out on a
+// fixed interval even when there's no sensor data, so it doubles as a
+// heartbeat.
+#[derive(Debug, Default, Serialize)]
+pub struct Status<'a> {
+    pub device_id: &'a str,
+    pub uptime_s: u32,
+    pub free_heap: u32,
+    // Number of the next SensorData batch, counts up across connections and
+    // reboots like batch_total
+    pub seq: u32,
+    // Since the device was first flashed, see batch_total()
+    pub batch_total: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rssi: Option<i8>,
+    // Moving average of rssi and its class, see wifi::LinkQuality
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rssi_smoothed: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub link_quality: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub battery_mv: Option<u32>,
+    pub dropped_samples: u32,
+    pub skipped_batches: u32,
+    // Sampling paused by the maintenance command
+    pub maintenance: bool,
+    // SensorDataSample has no room for a flag, so clipping is reported here:
+    // the samples since boot and a bitfield of the axes since the last
+    // status message, see sensor::ClipLimits::clipped_axes()
+    pub clipped_samples: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub clipped_axes: Option<u32>,
+    // Only with accel_event_threshold_mg
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub events: Option<u32>,
+    // Only with gyro_integrate, x, y and z since boot or the last zero_angle
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gyro_angle_deg: Option<(f32, f32, f32)>,
+    // Sample times stay relative to the origin, SensorDataSample only has
+    // room for an f32
+    pub time_synced: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub time_origin_us: Option<u64>,
+    // How fast the timer runs against NTP, already corrected for in the
+    // sample times
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub clock_drift_ppm: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub diagnostics: Option<Diagnostics>,
+}
+
+impl Status<'_> {
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        to_tagged_json("status", self)
+    }
+}
+
+// First message of every session, tells the collector who is sending and how
+// to interpret the numbers in SensorDataSample
+#[derive(Debug, Serialize)]
+pub struct Hello<'a> {
+    pub firmware_version: &'a str,
+    pub device_id: &'a str,
+    pub sampling_mode: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sample_rate_hz: Option<f32>,
+    pub accel_range_g: u8,
+    pub gyro_range_dps: u16,
+    pub mag_range_gauss: u8,
+    // Binary messages start with a length and CRC32, see batch::Format::encode()
+    pub batch_checksum: bool,
+    // "protobuf" for binary SensorData messages, "json" for text ones
+    pub sample_format: &'a str,
+    // Boots since the device was first flashed, this one included
+    pub boot_count: u32,
+    // Number of the session's first batch, it skips ahead after a reboot
+    pub seq: u32,
+}
+
+impl Hello<'_> {
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        to_tagged_json("hello", self)
+    }
+}
+
+// Precedes a batch that is sent again after its first send failed: the
+// next binary message is batch seq and repeats batch previous_seq of the
+// previous session, which may have arrived after all
+#[derive(Debug, Serialize)]
+pub struct Resend {
+    pub previous_seq: u32,
+    pub seq: u32,
+}
+
+impl Resend {
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        to_tagged_json("resend", self)
+    }
+}
+
+// Sent after replaying a segment of spilled samples. With spill_require_ack
+// {"cmd":"spill_ack","segment":...} deletes it from flash.
+#[derive(Debug, Serialize)]
+pub struct Replayed {
+    pub segment: u32,
+    // The boot the samples are from, their times count from its start
+    pub boot_count: u32,
+    pub samples: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub first_time: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_time: Option<f32>,
+}
+
+impl Replayed {
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        to_tagged_json("replayed", self)
+    }
+}
+
+// Reply to the flush command, sent once the samples that were buffered
+// when it arrived went out
+#[derive(Debug, Serialize)]
+pub struct Flushed {
+    pub samples: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_time: Option<f32>,
+}
+
+impl Flushed {
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        to_tagged_json("flushed", self)
+    }
+}
+
+// Sent before the dump_data messages of a ringbuffer dump, the next frames
+// messages are a copy for debugging and not part of the stream
+#[derive(Debug, Serialize)]
+pub struct Dump {
+    pub samples: usize,
+    pub frames: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub first_time: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_time: Option<f32>,
+}
+
+impl Dump {
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        to_tagged_json("dump", self)
+    }
+}
+
+// Marker of the linktest command, for the collector to send back as it is
+#[derive(Debug, Serialize)]
+pub struct Linktest {
+    pub nonce: u32,
+}
+
+impl Linktest {
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        to_tagged_json("linktest", self)
+    }
+}
+
+// Whether the marker came back, and how long that took
+#[derive(Debug, Serialize)]
+pub struct LinktestResult {
+    pub nonce: u32,
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rtt_ms: Option<u32>,
+}
+
+impl LinktestResult {
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        to_tagged_json("linktest_result", self)
+    }
+}
+
+// A sensor's output registers next to what the driver scaled them to
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct RawAxes {
+    pub raw: [i16; 3],
+    pub scaled: [f32; 3],
+}
+
+// Sent for every sample while raw output is on, with each sensor's latest
+// reading. The ranges they're scaled for are in the hello.
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+pub struct RawSample {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub accel: Option<RawAxes>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gyro: Option<RawAxes>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mag: Option<RawAxes>,
+}
+
+impl RawSample {
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        to_tagged_json("raw_sample", self)
+    }
+}
+
+// Raw samples waiting for the networking thread, the oldest are dropped
+// beyond this while the connection is down
+const MAX_RAW_SAMPLES: usize = 128;
+
+struct RawSamples {
+    latest: RawSample,
+    queued: VecDeque<RawSample>,
+}
+
+static RAW_SAMPLES: Mutex<RawSamples> = Mutex::new(RawSamples {
+    latest: RawSample { accel: None, gyro: None, mag: None },
+    queued: VecDeque::new(),
+});
+
+// Sensors that weren't read this time keep their previous reading
+pub fn record_raw_sample(sample: RawSample) {
+    if let Ok(mut samples) = RAW_SAMPLES.lock() {
+        let latest = &mut samples.latest;
+        latest.accel = sample.accel.or(latest.accel);
+        latest.gyro = sample.gyro.or(latest.gyro);
+        latest.mag = sample.mag.or(latest.mag);
+        let latest = *latest;
+        if samples.queued.len() >= MAX_RAW_SAMPLES {
+            samples.queued.pop_front();
+        }
+        samples.queued.push_back(latest);
+    }
+}
+
+// Everything recorded since the last call, oldest first
+pub fn take_raw_samples() -> Vec<RawSample> {
+    RAW_SAMPLES.lock().map(|mut samples| samples.queued.drain(..).collect()).unwrap_or_default()
+}
+
+// Last message of a session with session_digest, right before the
+// connection is closed. See batch::SessionDigest for how crc32 is computed.
+#[derive(Debug, Serialize)]
+pub struct SessionEnd {
+    pub first_seq: u32,
+    pub batches: u32,
+    pub crc32: u32,
+}
+
+impl SessionEnd {
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        to_tagged_json("session_end", self)
+    }
+}
+
+// Reply to the version command
+#[derive(Debug, Serialize)]
+pub struct Version<'a> {
+    pub firmware_version: &'a str,
+}
+
+impl Version<'_> {
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        to_tagged_json("version", self)
+    }
+}
+
+// Reply to the stats command
+#[derive(Debug, Serialize)]
+pub struct Stats {
+    pub dropped_samples: u32,
+    pub skipped_batches: u32,
+    pub reconnects: u32,
+    pub batches_sent: u32,
+    pub uptime_s: u32,
+}
+
+impl Stats {
+    // With reset, the counters start over from zero. Taking and zeroing
+    // each one in one go loses nothing counted in between.
+    pub fn take(reset: bool) -> Self {
+        let take = |counter: &AtomicU32| match reset {
+            true => counter.swap(0, Ordering::Relaxed),
+            false => counter.load(Ordering::Relaxed),
+        };
+        Self {
+            dropped_samples: take(&DROPPED_SAMPLES),
+            skipped_batches: take(&SKIPPED_BATCHES),
+            reconnects: take(&RECONNECTS),
+            batches_sent: take(&BATCHES_SENT),
+            uptime_s: uptime().as_secs() as u32,
+        }
+    }
+
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        to_tagged_json("stats", self)
+    }
+}
+
+// Sent when a command couldn't be carried out
+#[derive(Debug, Serialize)]
+pub struct CommandError<'a> {
+    pub cmd: &'a str,
+    pub error: String,
+}
+
+impl CommandError<'_> {
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        to_tagged_json("error", self)
+    }
+}
+
+// Text frames carry different kinds of messages, the "type" field tells
+// them apart
+fn to_tagged_json<T: Serialize>(kind: &'static str, message: &T) -> serde_json::Result<String> {
+    #[derive(Serialize)]
+    struct Tagged<'a, T> {
+        #[serde(rename = "type")]
+        kind: &'static str,
+        #[serde(flatten)]
+        message: &'a T,
+    }
+
+    serde_json::to_string(&Tagged { kind, message })
+}
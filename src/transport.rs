@@ -0,0 +1,110 @@
+use anyhow::Result;
+use serde::Serialize;
+use std::time::Duration;
+
+use crate::ws::WebSocketClient;
+use embedded_websocket as ews;
+
+// Incoming messages look the same on every transport
+pub use crate::ws::WsMessage as Message;
+
+// Receive buffer of a transport that decodes frames itself
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct ReadBuffer {
+    // Received but not decoded yet
+    pub buffered: usize,
+    pub size: usize,
+    // At least one whole frame is among the buffered bytes
+    pub complete_frame: bool,
+}
+
+// What the networking thread needs from a connection to the collector.
+// Establishing the connection is transport specific and happens before a
+// transport is handed over, afterwards it only ever gets re-established.
+// Only the networking thread uses it, taking turns between a non-blocking
+// poll() and the sends, so implementations don't need to be thread safe.
+pub trait Transport {
+    fn is_connected(&self) -> bool;
+
+    fn reconnect(&mut self) -> Result<()>;
+
+    // Largest binary message that can be sent in one piece
+    fn max_payload_len(&self) -> usize;
+
+    // Minimum time between two data batches, for transports where every
+    // send is expensive
+    fn send_interval(&self) -> Duration {
+        Duration::ZERO
+    }
+
+    fn send_text(&mut self, text: &str) -> Result<()>;
+
+    fn send_binary(&mut self, buf: &[u8]) -> Result<()>;
+
+    // An encoded batch, JSON if text is set (see batch::Format::is_text()).
+    // Transports that send text elsewhere than data put it with the binary
+    // batches.
+    fn send_batch(&mut self, message: &[u8], text: bool) -> Result<()> {
+        match text {
+            true => self.send_text(std::str::from_utf8(message)?),
+            false => self.send_binary(message),
+        }
+    }
+
+    // Messages received since the last call, without blocking
+    fn poll(&mut self) -> Result<Vec<Message>>;
+
+    // False for transports whose poll() never returns anything, nothing
+    // that waits for an answer from the collector should wait on them
+    fn receives(&self) -> bool {
+        true
+    }
+
+    fn read_buffer(&self) -> Option<ReadBuffer> {
+        None
+    }
+
+    // Ends the session in an orderly way before shutting down or turning
+    // the radio off
+    fn close(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl<const BUFSIZE: usize> Transport for WebSocketClient<'_, BUFSIZE> {
+    fn is_connected(&self) -> bool {
+        WebSocketClient::is_connected(self)
+    }
+
+    fn reconnect(&mut self) -> Result<()> {
+        Ok(WebSocketClient::reconnect(self)?)
+    }
+
+    fn max_payload_len(&self) -> usize {
+        WebSocketClient::max_payload_len(self)
+    }
+
+    fn send_text(&mut self, text: &str) -> Result<()> {
+        Ok(WebSocketClient::send_text(self, text)?)
+    }
+
+    fn send_binary(&mut self, buf: &[u8]) -> Result<()> {
+        Ok(WebSocketClient::send_binary(self, buf)?)
+    }
+
+    fn poll(&mut self) -> Result<Vec<Message>> {
+        Ok(WebSocketClient::poll(self)?)
+    }
+
+    fn read_buffer(&self) -> Option<ReadBuffer> {
+        Some(ReadBuffer {
+            buffered: self.read_cursor(),
+            size: self.buffer_size(),
+            complete_frame: self.has_complete_frame(),
+        })
+    }
+
+    fn close(&mut self) -> Result<()> {
+        Ok(WebSocketClient::close(self, Some(ews::WebSocketCloseStatusCode::GoingAway))?)
+    }
+}
@@ -0,0 +1,97 @@
+use anyhow::{anyhow, Result};
+use std::io::ErrorKind;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+
+use crate::telemetry;
+use crate::transport::{Message, Transport};
+
+// Fits an Ethernet frame of 1500 bytes with the IPv6 and UDP headers, so a
+// datagram is never fragmented on the LAN. Batches are sized to this.
+const MAX_DATAGRAM_LEN: usize = 1452;
+
+// Sends every message as one datagram to host:port, for a LAN where latency
+// matters more than the odd lost batch. Nothing is acknowledged or resent,
+// and there's no connection to lose. It's send-only: a datagram carries no
+// proof of where it came from, so nothing received is taken as a command.
+pub struct UdpTransport {
+    socket: UdpSocket,
+    peer: SocketAddr,
+}
+
+impl UdpTransport {
+    pub fn connect(host: &str, port: u16) -> Result<Self> {
+        let peer = (host, port).to_socket_addrs()?
+            .next()
+            .ok_or_else(|| anyhow!("{} has no address", host))?;
+        let local: SocketAddr = match peer {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+
+        let socket = UdpSocket::bind(local)?;
+        // Fixes the destination for send()
+        socket.connect(peer)?;
+        socket.set_nonblocking(true)?;
+        Ok(Self { socket, peer })
+    }
+
+    // An oversized datagram would be fragmented or dropped on the way, so
+    // it's dropped here with a warning instead. So is one the stack has no
+    // room for right now, like the network would on congestion.
+    fn send(&mut self, buf: &[u8]) -> Result<bool> {
+        if buf.len() > MAX_DATAGRAM_LEN {
+            log::warn!("Dropping a {} byte message, datagrams to {} are limited to {} bytes", buf.len(), self.peer, MAX_DATAGRAM_LEN);
+            return Ok(false);
+        }
+        match self.socket.send(buf) {
+            Ok(_) => Ok(true),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                log::debug!("Dropping a {} byte message, the send buffer is full", buf.len());
+                Ok(false)
+            },
+            // The collector's port being closed may show up on a later
+            // send, which doesn't matter without a connection
+            Err(e) if e.kind() == ErrorKind::ConnectionRefused => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+impl Transport for UdpTransport {
+    fn is_connected(&self) -> bool {
+        true
+    }
+
+    fn reconnect(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn max_payload_len(&self) -> usize {
+        MAX_DATAGRAM_LEN
+    }
+
+    fn send_text(&mut self, text: &str) -> Result<()> {
+        self.send(text.as_bytes())?;
+        Ok(())
+    }
+
+    fn send_binary(&mut self, buf: &[u8]) -> Result<()> {
+        self.send(buf)?;
+        Ok(())
+    }
+
+    fn send_batch(&mut self, message: &[u8], _text: bool) -> Result<()> {
+        if !self.send(message)? {
+            telemetry::record_dropped_frame();
+        }
+        Ok(())
+    }
+
+    fn poll(&mut self) -> Result<Vec<Message>> {
+        Ok(Vec::new())
+    }
+
+    fn receives(&self) -> bool {
+        false
+    }
+}
@@ -0,0 +1,53 @@
+use anyhow::Result;
+use esp_idf_svc::sys::{
+    esp, esp_task_wdt_add, esp_task_wdt_config_t, esp_task_wdt_delete, esp_task_wdt_init,
+    esp_task_wdt_reconfigure, esp_task_wdt_reset,
+};
+use std::ptr;
+use std::time::Duration;
+
+// Makes the task watchdog reset the board when a subscribed thread hasn't
+// checked in within the timeout. Only our own threads are watched, not the
+// idle tasks.
+pub fn configure(timeout: Duration) -> Result<()> {
+    let config = esp_task_wdt_config_t {
+        timeout_ms: timeout.as_millis() as u32,
+        idle_core_mask: 0,
+        trigger_panic: true,
+    };
+
+    // The watchdog is normally already running from ESP-IDF's startup code
+    esp!(unsafe { esp_task_wdt_reconfigure(&config) })
+        .or_else(|_| esp!(unsafe { esp_task_wdt_init(&config) }))?;
+
+    Ok(())
+}
+
+// Watchdog subscription of the current thread, dropping it unsubscribes
+pub struct Watchdog(());
+
+impl Watchdog {
+    pub fn subscribe() -> Result<Self> {
+        esp!(unsafe { esp_task_wdt_add(ptr::null_mut()) })?;
+        Ok(Self(()))
+    }
+
+    pub fn feed(&self) {
+        unsafe { esp_task_wdt_reset() };
+    }
+
+    // For operations that legitimately block longer than the timeout and
+    // have their own limits, like connecting to WiFi or flashing an update
+    pub fn unwatched<T>(&self, f: impl FnOnce() -> T) -> T {
+        unsafe { esp_task_wdt_delete(ptr::null_mut()) };
+        let result = f();
+        unsafe { esp_task_wdt_add(ptr::null_mut()) };
+        result
+    }
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        unsafe { esp_task_wdt_delete(ptr::null_mut()) };
+    }
+}
@@ -1,67 +1,668 @@
 use esp_idf_svc::{
-    wifi::{AuthMethod, BlockingWifi, ClientConfiguration, Configuration, EspWifi},
-    eventloop::EspSystemEventLoop,
+    wifi::{AccessPointInfo, AuthMethod, BlockingWifi, ClientConfiguration, Configuration, EspWifi, WifiEvent},
+    eventloop::{EspSubscription, EspSystemEventLoop, System},
+    ipv4,
+    netif::{EspNetif, NetifConfiguration},
+    sys::{
+        esp, esp_ip6_addr_t, esp_netif_create_ip6_linklocal, esp_netif_get_all_ip6,
+        esp_netif_get_handle_from_ifkey, esp_netif_get_ip_info, esp_netif_ip_info_t, esp_netif_t, esp_wifi_get_country, esp_wifi_set_country_code,
+        esp_wifi_sta_get_ap_info, esp_wifi_sta_wpa2_ent_clear_ca_cert, esp_wifi_sta_wpa2_ent_disable,
+        esp_wifi_sta_wpa2_ent_enable, esp_wifi_sta_wpa2_ent_set_ca_cert, esp_wifi_sta_wpa2_ent_set_identity,
+        esp_wifi_sta_wpa2_ent_set_password, esp_wifi_sta_wpa2_ent_set_username, wifi_ap_record_t, wifi_country_t,
+        EspError, CONFIG_LWIP_IPV6_NUM_ADDRESSES,
+    },
 };
 use log;
-use anyhow::{bail, Result, Ok};
-use std::sync::{Arc, Mutex};
+use anyhow::{bail, Result};
+use std::error::Error;
+use std::ffi::CString;
+use std::fmt::Display;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::ops::RangeInclusive;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::time::{Duration, Instant};
 
-pub fn connect(
+use crate::backoff::Backoff;
+use crate::credentials::CredentialError;
+
+const CONNECT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+// Weight of the newest reading in the smoothed RSSI. With a reading a second
+// a step change is mostly through after ten seconds.
+const RSSI_SMOOTHING: f32 = 0.2;
+
+// f32 bits, NaN while not associated
+static SMOOTHED_RSSI: AtomicU32 = AtomicU32::new(0x7FC0_0000);
+
+// SSID and channel of the last AP we got onto, so reconnecting to it can
+// skip the scan. Lost with a reboot, which then scans like before.
+static LAST_AP: Mutex<Option<(String, u8)>> = Mutex::new(None);
+
+#[derive(Clone, Copy)]
+pub struct Credentials<'a> {
+    pub ssid: &'a str,
+    pub psk: &'a str,
+    pub auth_method: AuthMethod,
+    // 802.1X instead of the PSK
+    pub enterprise: Option<Enterprise>,
+}
+
+// WPA2-Enterprise login, PEAP or TTLS with MSCHAPv2 inside, whichever the
+// network offers
+#[derive(Clone, Copy, Debug)]
+pub struct Enterprise {
+    // Sent in the clear before the tunnel is up, often "anonymous@<realm>"
+    pub identity: &'static str,
+    pub username: &'static str,
+    pub password: &'static str,
+    // PEM of the CA that signed the RADIUS server's certificate, empty to
+    // accept any server
+    pub ca_cert: &'static str,
+}
+
+// Limit of the supplicant for identity, username and password
+pub const MAX_EAP_FIELD_LEN: usize = 128;
+
+// The supplicant keeps a pointer to the CA certificate rather than a copy,
+// and mbedTLS wants PEM with a terminating NUL. There's only the one from
+// the configuration, so it's made once and kept.
+static EAP_CA_CERT: OnceLock<Vec<u8>> = OnceLock::new();
+
+impl Enterprise {
+    // None without a username, the network uses its PSK then
+    pub fn new(identity: &'static str, username: &'static str, password: &'static str, ca_cert: &'static str) -> Option<Self> {
+        (!username.is_empty()).then_some(Self { identity, username, password, ca_cert })
+    }
+
+    // What the supplicant would reject
+    pub fn check(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        for (name, value) in [("identity", self.identity), ("username", self.username), ("password", self.password)] {
+            if value.len() >= MAX_EAP_FIELD_LEN {
+                problems.push(format!("WiFi EAP {} is longer than {} bytes", name, MAX_EAP_FIELD_LEN - 1));
+            }
+        }
+        if self.password.is_empty() {
+            problems.push("WiFi EAP password is empty".to_string());
+        }
+        if !self.ca_cert.is_empty() && !self.ca_cert.contains("-----BEGIN CERTIFICATE-----") {
+            problems.push("WiFi EAP CA certificate is not PEM".to_string());
+        }
+        problems
+    }
+
+    // Hands the login to the supplicant and switches the station to 802.1X,
+    // after the ClientConfiguration is set
+    fn enable(&self) -> std::result::Result<(), WifiError> {
+        let identity = if self.identity.is_empty() { self.username } else { self.identity };
+        esp!(unsafe { esp_wifi_sta_wpa2_ent_set_identity(identity.as_ptr(), identity.len() as i32) })?;
+        esp!(unsafe { esp_wifi_sta_wpa2_ent_set_username(self.username.as_ptr(), self.username.len() as i32) })?;
+        esp!(unsafe { esp_wifi_sta_wpa2_ent_set_password(self.password.as_ptr(), self.password.len() as i32) })?;
+
+        match self.ca_cert {
+            "" => {
+                log::warn!("No CA certificate for WiFi EAP, the RADIUS server isn't verified");
+                unsafe { esp_wifi_sta_wpa2_ent_clear_ca_cert() };
+            },
+            pem => {
+                let cert = EAP_CA_CERT.get_or_init(|| [pem.as_bytes(), b"\0"].concat());
+                esp!(unsafe { esp_wifi_sta_wpa2_ent_set_ca_cert(cert.as_ptr(), cert.len() as i32) })?;
+            },
+        }
+
+        esp!(unsafe { esp_wifi_sta_wpa2_ent_enable() })?;
+        Ok(())
+    }
+}
+
+pub fn parse_auth_method(name: &str) -> AuthMethod {
+    match name {
+        "wpa2" => AuthMethod::WPA2Personal,
+        "wpa3" => AuthMethod::WPA3Personal,
+        "wpa2wpa3" => AuthMethod::WPA2WPA3Personal,
+        "open" => AuthMethod::None,
+        other => {
+            log::warn!("Unknown WiFi auth method \"{}\", falling back to WPA2", other);
+            AuthMethod::WPA2Personal
+        },
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct StaticIp {
+    pub ip: Ipv4Addr,
+    pub gateway: Ipv4Addr,
+    pub netmask: Ipv4Addr,
+}
+
+impl StaticIp {
+    // An empty address means DHCP. The netmask defaults to a /24.
+    pub fn parse(ip: &str, gateway: &str, netmask: &str) -> Result<Option<Self>> {
+        if ip.is_empty() {
+            return Ok(None);
+        }
+
+        let netmask = if netmask.is_empty() { "255.255.255.0" } else { netmask };
+        let static_ip = StaticIp {
+            ip: ip.parse().or_else(|_| bail!("Invalid static IP address {}", ip))?,
+            gateway: gateway.parse().or_else(|_| bail!("Invalid gateway address {}", gateway))?,
+            netmask: netmask.parse().or_else(|_| bail!("Invalid netmask {}", netmask))?,
+        };
+
+        let mask = u32::from(static_ip.netmask);
+        if mask.leading_ones() != mask.count_ones() {
+            bail!("Netmask {} is not contiguous", netmask);
+        }
+
+        Ok(Some(static_ip))
+    }
+
+    fn netif_configuration(&self) -> NetifConfiguration {
+        NetifConfiguration {
+            ip_configuration: ipv4::Configuration::Client(ipv4::ClientConfiguration::Fixed(ipv4::ClientSettings {
+                ip: self.ip,
+                subnet: ipv4::Subnet {
+                    gateway: self.gateway,
+                    mask: ipv4::Mask(u32::from(self.netmask).count_ones() as u8),
+                },
+                dns: Some(self.gateway),
+                secondary_dns: None,
+            })),
+            ..NetifConfiguration::wifi_default_client()
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum WifiError {
+    // See credentials::check_credentials()
+    Credentials(CredentialError),
+    MutexPoisoned,
+    Driver(EspError),
+    Scan(EspError),
+    // None of the networks showed up in the scan and joining them blind
+    // didn't work either
+    NotFound,
+    // The AP was seen in the scan but didn't let us in, most likely a wrong
+    // PSK. The driver doesn't tell us the actual reason here.
+    Auth(String),
+    Timeout,
+}
+
+impl WifiError {
+    // Configuration errors stay the same no matter how often we try
+    pub fn is_retryable(&self) -> bool {
+        !matches!(
+            self,
+            WifiError::Credentials(_) | WifiError::MutexPoisoned
+        )
+    }
+}
+
+impl Display for WifiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WifiError::Credentials(e) => write!(f, "{}", e),
+            WifiError::MutexPoisoned => write!(f, "Wifi mutex is poisoned"),
+            WifiError::Driver(e) => write!(f, "WiFi driver error: {}", e),
+            WifiError::Scan(e) => write!(f, "WiFi scan failed: {}", e),
+            WifiError::NotFound => write!(f, "None of the networks could be found"),
+            WifiError::Auth(ssid) => write!(f, "{} rejected the connection", ssid),
+            WifiError::Timeout => write!(f, "Timed out"),
+        }
+    }
+}
+
+impl Error for WifiError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            WifiError::Driver(e) | WifiError::Scan(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<CredentialError> for WifiError {
+    fn from(e: CredentialError) -> Self {
+        WifiError::Credentials(e)
+    }
+}
+
+impl From<EspError> for WifiError {
+    fn from(e: EspError) -> Self {
+        WifiError::Driver(e)
+    }
+}
+
+#[derive(Debug)]
+pub struct RetriesExhausted {
+    pub attempts: u32,
+    pub last_error: WifiError,
+}
+
+impl Display for RetriesExhausted {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "WiFi connection failed after {} attempts: {}", self.attempts, self.last_error)
+    }
+}
+
+impl Error for RetriesExhausted {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.last_error)
+    }
+}
+
+// Returns a flag that is raised whenever the station loses its AP. The flag
+// only stays updated as long as the returned subscription is kept alive.
+pub fn subscribe_disconnects(sysloop: &EspSystemEventLoop) -> Result<(EspSubscription<'static, System>, Arc<AtomicBool>)> {
+
+    let disconnected = Arc::new(AtomicBool::new(false));
+    let flag = disconnected.clone();
+    let subscription = sysloop.subscribe::<WifiEvent, _>(move |event| {
+        if matches!(event, WifiEvent::StaDisconnected { .. }) {
+            flag.store(true, Ordering::Relaxed);
+        }
+    })?;
+
+    Ok((subscription, disconnected))
+}
+
+// Signal strength of the AP the station is currently associated with. This
+// asks the driver every time, so it follows the station when it roams.
+pub fn rssi() -> Option<i8> {
+    let mut ap_info = wifi_ap_record_t::default();
+    esp!(unsafe { esp_wifi_sta_get_ap_info(&mut ap_info) }).ok()?;
+    Some(ap_info.rssi)
+}
+
+// Coarse classes of the smoothed RSSI, with the usual thresholds for
+// 2.4 GHz WiFi
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LinkQuality {
+    Excellent,
+    Good,
+    Fair,
+    Poor,
+}
+
+impl LinkQuality {
+    pub fn from_rssi(rssi: f32) -> Self {
+        match rssi {
+            rssi if rssi >= -55.0 => LinkQuality::Excellent,
+            rssi if rssi >= -67.0 => LinkQuality::Good,
+            rssi if rssi >= -75.0 => LinkQuality::Fair,
+            _ => LinkQuality::Poor,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            LinkQuality::Excellent => "excellent",
+            LinkQuality::Good => "good",
+            LinkQuality::Fair => "fair",
+            LinkQuality::Poor => "poor",
+        }
+    }
+}
+
+// Feeds one reading into the moving average, to be called at a steady rate
+// while connected. The average starts over after a reading failed, a new AP
+// has nothing to do with the old one.
+pub fn sample_rssi() {
+    let previous = smoothed_rssi();
+    let smoothed = match (rssi(), previous) {
+        (None, _) => None,
+        (Some(rssi), None) => Some(rssi as f32),
+        (Some(rssi), Some(previous)) => Some(previous + RSSI_SMOOTHING * (rssi as f32 - previous)),
+    };
+    SMOOTHED_RSSI.store(smoothed.unwrap_or(f32::NAN).to_bits(), Ordering::Relaxed);
+
+    if let Some(smoothed) = smoothed {
+        let quality = LinkQuality::from_rssi(smoothed);
+        if previous.map(LinkQuality::from_rssi) != Some(quality) {
+            log::info!("Link quality {} ({:.0} dBm)", quality.name(), smoothed);
+        }
+    }
+}
+
+pub fn smoothed_rssi() -> Option<f32> {
+    let rssi = f32::from_bits(SMOOTHED_RSSI.load(Ordering::Relaxed));
+    (!rssi.is_nan()).then_some(rssi)
+}
+
+pub fn link_quality() -> Option<LinkQuality> {
+    smoothed_rssi().map(LinkQuality::from_rssi)
+}
+
+// All IPv6 addresses of the station's netif, link-local ones included
+fn ipv6_addresses(netif: *mut esp_netif_t) -> Vec<Ipv6Addr> {
+    if netif.is_null() {
+        return Vec::new();
+    }
+
+    let mut addresses = [esp_ip6_addr_t::default(); CONFIG_LWIP_IPV6_NUM_ADDRESSES as usize];
+    let count = unsafe { esp_netif_get_all_ip6(netif, addresses.as_mut_ptr()) };
+    addresses[..count.clamp(0, addresses.len() as i32) as usize].iter()
+        .map(|address| {
+            // The words are in network byte order already
+            let mut octets = [0u8; 16];
+            for (chunk, word) in octets.chunks_exact_mut(4).zip(address.addr) {
+                chunk.copy_from_slice(&word.to_ne_bytes());
+            }
+            Ipv6Addr::from(octets)
+        })
+        .collect()
+}
+
+fn is_link_local(address: &Ipv6Addr) -> bool {
+    address.segments()[0] & 0xffc0 == 0xfe80
+}
+
+// Whether the station has an IPv6 address beyond link-local, from SLAAC.
+// Without one, connecting to a collector over IPv6 can't work. SLAAC only
+// completes a few seconds after the WiFi came up, hence asked every time.
+pub fn has_global_ipv6() -> bool {
+    ipv6_addresses(sta_netif()).iter().any(|address| !is_link_local(address) && !address.is_loopback())
+}
+
+// IPv4 address of the station, None while it has none
+pub fn ipv4_address() -> Option<Ipv4Addr> {
+    let netif = sta_netif();
+    if netif.is_null() {
+        return None;
+    }
+
+    let mut ip_info = esp_netif_ip_info_t::default();
+    esp!(unsafe { esp_netif_get_ip_info(netif, &mut ip_info) }).ok()?;
+    // Also in network byte order
+    let address = Ipv4Addr::from(ip_info.ip.addr.to_ne_bytes());
+    (!address.is_unspecified()).then_some(address)
+}
+
+// The station's netif, found by its key so callers don't need the driver.
+// A static IP configuration swaps in a netif with the same key.
+fn sta_netif() -> *mut esp_netif_t {
+    unsafe { esp_netif_get_handle_from_ifkey(b"WIFI_STA_DEF\0".as_ptr() as *const _) }
+}
+
+// Two letter country code (e.g. "DE", or "01" for worldwide), which
+// selects the channels the station may use and caps the TX power. Needs the
+// driver to be initialized, and should be set before the first scan. 802.11d
+// stays off, so an AP advertising another country doesn't override it.
+pub fn set_country(code: &str) -> Result<()> {
+    let code = CString::new(code)?;
+    esp!(unsafe { esp_wifi_set_country_code(code.as_ptr(), false) })?;
+    log::info!(
+        "WiFi country set to {}, channels {:?}, at most {:?} dBm",
+        code.to_string_lossy(), allowed_channels(), country().map(|country| country.max_tx_power),
+    );
+    Ok(())
+}
+
+// The regulatory domain in effect
+fn country() -> Option<wifi_country_t> {
+    let mut country = wifi_country_t::default();
+    esp!(unsafe { esp_wifi_get_country(&mut country) }).ok()?;
+    Some(country)
+}
+
+// Channels the regulatory domain in effect allows
+fn allowed_channels() -> Option<RangeInclusive<u8>> {
+    country()
+        .filter(|country| country.nchan > 0)
+        .map(|country| country.schan..=country.schan + country.nchan - 1)
+}
+
+// Channel to join the network on, from its sightings in the scan. An SSID
+// can show up on several channels (mesh nodes, repeaters, a neighbour using
+// the same name), the strongest sighting on an allowed channel wins. With
+// none on an allowed channel, None lets the driver find the AP itself
+// rather than failing the connect with an opaque error.
+fn scanned_channel(ssid: &str, sightings: &[&AccessPointInfo]) -> Option<u8> {
+    let mut channels = sightings.iter().map(|ap| ap.channel).collect::<Vec<_>>();
+    channels.sort_unstable();
+    channels.dedup();
+    if channels.len() > 1 {
+        log::info!("{} seen on channels {:?}", ssid, channels);
+    }
+
+    let allowed = allowed_channels();
+    let best = sightings.iter()
+        .filter(|ap| allowed.as_ref().map_or(true, |allowed| allowed.contains(&ap.channel)))
+        .max_by_key(|ap| ap.signal_strength);
+    match best {
+        Some(ap) => Some(ap.channel),
+        None => {
+            log::warn!("{} is on channel {:?}, outside the allowed channels {:?}, letting the driver pick",
+                ssid, channels, allowed);
+            None
+        },
+    }
+}
+
+pub fn connect_with_retry(
     wifi_mutex: Arc<Mutex<EspWifi>>,
-    ssid: &str,
-    psk: &str,
+    networks: &[Credentials],
+    static_ip: Option<StaticIp>,
     sysloop: EspSystemEventLoop,
-) -> Result<()> {
+    max_attempts: u32,
+    timeout: Duration,
+    backoff: &mut Backoff,
+) -> std::result::Result<(), RetriesExhausted> {
+
+    let mut attempt = 1;
+    loop {
+        log::info!("WiFi connection attempt {}/{}", attempt, max_attempts);
+
+        // Every attempt scans again, in case the AP moved to another channel
+        let error = match connect(wifi_mutex.clone(), networks, static_ip, sysloop.clone(), timeout) {
+            Ok(()) => return Ok(()),
+            Err(e) => e,
+        };
+
+        if attempt >= max_attempts || !error.is_retryable() {
+            return Err(RetriesExhausted { attempts: attempt, last_error: error });
+        }
 
-    let mut auth_method = AuthMethod::WPA2Personal;
-    if ssid.is_empty() {
-        bail!("No access point name");
+        let delay = backoff.next_delay();
+        log::warn!("WiFi connection attempt failed: {}. Retrying in {:?}", error, delay);
+        std::thread::sleep(delay);
+        attempt += 1;
     }
+}
 
-    if psk.is_empty() {
-        auth_method = AuthMethod::None;
+// Parses "ssid:psk" pairs separated by commas, e.g. "Lab:secret,Field:" (an
+// empty PSK means an open network). SSIDs can't contain ':' or ',' here.
+pub fn parse_networks<'a>(list: &'a str, auth_method: AuthMethod) -> Result<Vec<Credentials<'a>>> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| match entry.split_once(':') {
+            Some((ssid, psk)) => Ok(Credentials { ssid, psk, auth_method, enterprise: None }),
+            None => bail!("WiFi network entry \"{}\" is missing a ':' separator", entry),
+        })
+        .collect()
+}
+
+// Connects to the first of the given networks that works, in priority order.
+// The timeout applies to each network on its own.
+pub fn connect(
+    wifi_mutex: Arc<Mutex<EspWifi>>,
+    networks: &[Credentials],
+    static_ip: Option<StaticIp>,
+    sysloop: EspSystemEventLoop,
+    timeout: Duration,
+) -> std::result::Result<(), WifiError> {
+
+    if networks.is_empty() || networks.iter().any(|n| n.ssid.is_empty()) {
+        return Err(CredentialError::EmptySsid.into());
     }
 
     let mut wifi_locked = wifi_mutex.lock()
-        .or_else(|e| {
-            bail!("Wifi mutex is poisoned: {e}")
-        })?;
+        .map_err(|_| WifiError::MutexPoisoned)?;
+
+    // Without a static configuration the default STA netif runs a DHCP client
+    if let Some(static_ip) = static_ip {
+        let netif = EspNetif::new_with_conf(&static_ip.netif_configuration())?;
+        wifi_locked.swap_netif_sta(netif)?;
+    }
+
     let mut wifi = BlockingWifi::wrap(&mut *wifi_locked, sysloop.clone())?;
 
-    // First we need to scan to find the correct channel
+    // A restarted networking thread finds the station still associated
+    if wifi.is_connected()? {
+        wifi.disconnect()?;
+    }
+
     wifi.set_configuration(&Configuration::Client(ClientConfiguration::default()))?;
 
     log::info!("Starting WiFi");
 
     wifi.start()?;
 
-    let ap_infos = wifi.scan()?;
-    let channel = ap_infos.into_iter()
-        .find(|a| a.ssid == ssid)
-        .and_then(|a| Some(a.channel));
+    // Try the channel we know first, and only scan if the AP isn't there
+    // anymore
+    let last_ap = LAST_AP.lock().ok().and_then(|last_ap| last_ap.clone());
+    if let Some((network, channel)) = last_ap.and_then(|(ssid, channel)| networks.iter()
+        .find(|n| n.ssid == ssid)
+        .map(|n| (n, channel)))
+    {
+        log::info!("Trying {} on cached channel {}", network.ssid, channel);
+        match join(&mut wifi, network, Some(channel), timeout) {
+            Ok(()) => return connected(&wifi, network, static_ip),
+            Err(e) => {
+                log::warn!("Failed to connect to {} on channel {}, scanning: {}", network.ssid, channel, e);
+                _ = wifi.disconnect();
+            },
+        }
+    }
+
+    // Otherwise we need to scan to find the correct channel
+    let ap_infos = wifi.scan().map_err(WifiError::Scan)?;
+
+    // Only try networks that are actually around. If none of them showed up
+    // in the scan, try them all anyway, they might just be hidden.
+    let mut candidates = networks.iter()
+        .filter_map(|n| {
+            let sightings = ap_infos.iter().filter(|a| a.ssid == n.ssid).collect::<Vec<_>>();
+            (!sightings.is_empty()).then(|| (n, scanned_channel(n.ssid, &sightings), true))
+        })
+        .collect::<Vec<_>>();
+    if candidates.is_empty() {
+        candidates = networks.iter().map(|n| (n, None, false)).collect();
+    }
+
+    // Only give up for good if every network is misconfigured, one that may
+    // still show up is worth retrying
+    let mut error: Option<WifiError> = None;
+    for (network, channel, seen) in candidates {
+        match join(&mut wifi, network, channel, timeout) {
+            Ok(()) => {
+                if let (Some(channel), Ok(mut last_ap)) = (channel, LAST_AP.lock()) {
+                    *last_ap = Some((network.ssid.to_string(), channel));
+                }
+                return connected(&wifi, network, static_ip);
+            },
+            Err(e) => {
+                log::warn!("Failed to connect to {}: {}", network.ssid, e);
+                _ = wifi.disconnect();
+                let e = match (e, seen) {
+                    // The AP is there, it just didn't accept us
+                    (WifiError::Driver(_), true) => WifiError::Auth(network.ssid.to_string()),
+                    (WifiError::Driver(_), false) => WifiError::NotFound,
+                    (e, _) => e,
+                };
+                if error.as_ref().map_or(true, |error| !error.is_retryable()) {
+                    error = Some(e);
+                }
+            },
+        }
+    }
+
+    Err(error.unwrap_or(WifiError::NotFound))
+}
+
+fn connected(wifi: &BlockingWifi<&mut EspWifi>, network: &Credentials, static_ip: Option<StaticIp>) -> std::result::Result<(), WifiError> {
+    let netif = wifi.wifi().sta_netif();
+    let ip_info = netif.get_ip_info()?;
+    log::info!("Connected to {} ({}). IP info: {:?}", network.ssid, if static_ip.is_some() { "static" } else { "DHCP" }, ip_info);
+    log::info!("IPv6 addresses: {:?}", ipv6_addresses(netif.handle()));
+
+    Ok(())
+}
+
+fn join(wifi: &mut BlockingWifi<&mut EspWifi>, network: &Credentials, channel: Option<u8>, timeout: Duration) -> std::result::Result<(), WifiError> {
+
+    let Credentials { ssid, mut psk, mut auth_method, enterprise } = *network;
+    if enterprise.is_some() {
+        auth_method = AuthMethod::WPA2Enterprise;
+        psk = "";
+    } else if psk.is_empty() {
+        auth_method = AuthMethod::None;
+    }
 
     // Reconfigure with correct info
     wifi.set_configuration(&Configuration::Client(ClientConfiguration {
-        ssid: ssid.try_into().or_else(|_| {
-            bail!("SSID {} couldn't be converted to heapless::String<32>", ssid)
-        })?,
-        password: psk.try_into().or_else(|_| {
-            bail!("PSK couldn't be converted to heapless::String<64>")
-        })?,
+        ssid: ssid.try_into()
+            .map_err(|_| CredentialError::SsidTooLong(ssid.to_string()))?,
+        password: psk.try_into()
+            .map_err(|_| CredentialError::PskTooLong(ssid.to_string()))?,
         channel,
         auth_method,
         ..Default::default()
     }))?;
 
-    log::info!("Connecting WiFi");
+    // Stays enabled across set_configuration(), so a PSK network tried
+    // after an enterprise one has to turn it off again
+    match enterprise {
+        Some(enterprise) => enterprise.enable()?,
+        None => esp!(unsafe { esp_wifi_sta_wpa2_ent_disable() })?,
+    }
+
+    log::info!("Connecting WiFi {}", ssid);
 
-    wifi.connect()?;
+    // BlockingWifi::connect() waits for the association without a limit, so
+    // start it on the driver and keep our own deadline for association and
+    // address together
+    let deadline = Instant::now() + timeout;
+    wifi.wifi_mut().connect()?;
 
-    wifi.wait_netif_up()?;
+    // Either address will do, an IPv6-only network never hands out an IPv4
+    // one
+    let mut ipv6_enabled = false;
+    loop {
+        if wifi.is_connected()? {
+            // The link-local address also starts SLAAC, which gets the
+            // routable address from the router's advertisements. IPv4 keeps
+            // working without.
+            if !ipv6_enabled {
+                ipv6_enabled = true;
+                let netif = wifi.wifi().sta_netif().handle();
+                if let Err(e) = esp!(unsafe { esp_netif_create_ip6_linklocal(netif) }) {
+                    log::warn!("Failed to enable IPv6: {}", e);
+                }
+            }
+            if wifi.wifi().sta_netif().is_up()? || has_global_ipv6() {
+                return Ok(());
+            }
+        }
 
-    let ip_info = wifi.wifi().sta_netif().get_ip_info()?;
+        if Instant::now() >= deadline {
+            return Err(WifiError::Timeout);
+        }
+        std::thread::sleep(CONNECT_POLL_INTERVAL);
+    }
+}
 
-    log::info!("Connected. DHCP info: {:?}", ip_info);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::credentials::{check_credentials, MAX_PSK_LEN};
 
-    Ok(())
+    #[test]
+    fn credential_errors_are_permanent() {
+        let errors = check_credentials("", &"b".repeat(MAX_PSK_LEN + 1));
+        assert_eq!(errors.len(), 2, "{:?}", errors);
+        assert!(errors.into_iter().map(WifiError::from).all(|e| !e.is_retryable()));
+    }
 }
@@ -1,109 +1,912 @@
-use std::net::TcpStream;
+use std::cell::Cell;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::io::{self, ErrorKind, Read, Write};
 use std::error::Error;
 use std::fmt::Display;
+use std::mem::ManuallyDrop;
+use std::os::fd::{AsRawFd, FromRawFd};
+use std::time::{Duration, Instant};
+use esp_idf_svc::{
+    sys::{self, EspError},
+    tls::{self, EspTls},
+};
 use rand::{
     self,
     rngs::ThreadRng,
 };
 use embedded_websocket as ews;
-use ews::{framer::Framer, WebSocketOptions};
 
+use crate::{mdns, wifi};
+use crate::endpoint::normalize_endpoint;
+use ews::{
+    framer::{Framer, FramerError, Stream},
+    WebSocketOptions, WebSocketReceiveMessageType, WebSocketSendMessageType,
+};
+
+// Size of each of the three buffers of a client (read, write and frame
+// assembly), and with that the largest message either way. The clients are
+// boxed, so it's heap rather than stack, once per collector connection.
+pub const BUFFER_SIZE: usize = 4096;
+
+// Largest header of a client frame: 2 bytes, up to 8 bytes of extended
+// payload length and a 4 byte masking key
+const MAX_FRAME_HEADER_LEN: usize = 14;
+
+// Length of the frame at the start of buf including its header, once enough
+// of the header is there to tell (RFC 6455 section 5.2)
+fn frame_len(buf: &[u8]) -> Option<usize> {
+    let &len_byte = buf.get(1)?;
+    let rest = &buf[2..];
+    let masked = len_byte & 0x80 != 0;
+    let (payload_len, extended_len) = match len_byte & 0x7F {
+        126 => (u64::from(u16::from_be_bytes(rest.get(..2)?.try_into().ok()?)), 2),
+        127 => (u64::from_be_bytes(rest.get(..8)?.try_into().ok()?), 8),
+        len => (u64::from(len), 0),
+    };
+    let header_len = 2 + extended_len + if masked { 4 } else { 0 };
+    usize::try_from(payload_len).ok()?.checked_add(header_len)
+}
+
+// mbedTLS "want read/write" codes, returned by a TLS read or write on a
+// non-blocking socket that has nothing to do yet
+const SSL_WANT_READ: i32 = -0x6900;
+const SSL_WANT_WRITE: i32 = -0x6880;
+
+#[derive(Clone, Copy, Debug)]
+pub enum Scheme {
+    Ws,
+    Wss(CertVerification),
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum CertVerification {
+    // Verify the server against the ESP-IDF certificate bundle
+    Bundle,
+    // Accept any certificate, for testing against self-signed brokers
+    Insecure,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConnectionState {
+    Disconnected,
+    // Between the TCP connect and the end of the upgrade handshake
+    Connecting,
+    Connected,
+    // Sending the Close frame
+    Closing,
+}
+
+// Extra parts of the upgrade request, e.g. for collectors that require
+// authentication
+#[derive(Clone, Debug, Default)]
+pub struct Handshake {
+    // Complete header lines like "Authorization: Bearer <token>"
+    pub headers: Vec<String>,
+    pub sub_protocol: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct SocketTimeouts {
+    // How long a failed DNS lookup is retried, zero for a single attempt
+    pub resolve: Duration,
+    // Establishing the TCP connection, per address
+    pub connect: Duration,
+    // Each read and write from the TCP connect until the upgrade is
+    // complete, TLS included. An HTTP server that isn't a WebSocket one may
+    // accept the connection and never answer the upgrade.
+    pub handshake: Duration,
+    pub read: Duration,
+    pub write: Duration,
+    // None leaves TCP keepalive off
+    pub keepalive: Option<Keepalive>,
+}
+
+impl Default for SocketTimeouts {
+    fn default() -> Self {
+        Self {
+            resolve: Duration::from_secs(1),
+            connect: Duration::from_secs(5),
+            handshake: Duration::from_secs(5),
+            read: Duration::from_secs(5),
+            write: Duration::from_secs(5),
+            keepalive: None,
+        }
+    }
+}
+
+// After idle without traffic the stack starts probing the peer every
+// interval and closes the socket after count unanswered probes, so a
+// half-open connection fails the next send instead of blocking on it
+#[derive(Clone, Copy, Debug)]
+pub struct Keepalive {
+    pub idle: Duration,
+    pub interval: Duration,
+    pub count: u32,
+}
+
+impl Keepalive {
+    fn apply(&self, stream: &TcpStream) -> io::Result<()> {
+        let fd = stream.as_raw_fd();
+        set_option(fd, sys::SOL_SOCKET as i32, sys::SO_KEEPALIVE as i32, 1)?;
+        set_option(fd, sys::IPPROTO_TCP as i32, sys::TCP_KEEPIDLE as i32, duration_secs(self.idle))?;
+        set_option(fd, sys::IPPROTO_TCP as i32, sys::TCP_KEEPINTVL as i32, duration_secs(self.interval))?;
+        set_option(fd, sys::IPPROTO_TCP as i32, sys::TCP_KEEPCNT as i32, self.count.try_into().unwrap_or(i32::MAX))
+    }
+}
+
+// lwIP counts keepalive times in whole seconds
+fn duration_secs(duration: Duration) -> i32 {
+    duration.as_secs().clamp(1, i32::MAX as u64) as i32
+}
+
+fn set_option(fd: i32, level: i32, name: i32, value: i32) -> io::Result<()> {
+    let result = unsafe {
+        sys::lwip_setsockopt(
+            fd,
+            level,
+            name,
+            &value as *const i32 as *const core::ffi::c_void,
+            core::mem::size_of::<i32>() as sys::socklen_t,
+        )
+    };
+    match result {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+// Right after the WiFi came up, DNS often doesn't answer yet, so a failed
+// lookup is retried for SocketTimeouts::resolve
+const DNS_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+// Resolved up front rather than by TcpStream::connect(), so a failed lookup
+// doesn't look like a refused connection. With AAAA and A records, IPv6
+// addresses come first as long as the station has a routable IPv6 address
+// itself, IPv4 ones otherwise. A literal IPv6 host may be in brackets.
+fn resolve(host: &str, port: u16, retry_for: Duration) -> Result<Vec<SocketAddr>, WebSocketClientError> {
+    if mdns::is_local(host) {
+        let address = mdns::resolve(host)
+            .map_err(WebSocketClientError::MdnsError)?;
+        return Ok(vec![SocketAddr::from((address, port))]);
+    }
+
+    let host = host.strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(host);
+    let prefer_ipv6 = wifi::has_global_ipv6();
+
+    let deadline = Instant::now() + retry_for;
+    let mut attempt = 1;
+    loop {
+        let result = (host, port).to_socket_addrs()
+            .map(|addresses| addresses.collect::<Vec<_>>())
+            .and_then(|addresses| match addresses.is_empty() {
+                true => Err(io::Error::new(ErrorKind::NotFound, "no addresses")),
+                false => Ok(addresses),
+            });
+        match result {
+            Ok(mut addresses) => {
+                addresses.sort_by_key(|address| address.is_ipv6() != prefer_ipv6);
+                return Ok(addresses);
+            },
+            Err(e) if Instant::now() + DNS_RETRY_DELAY > deadline => return Err(WebSocketClientError::DnsError(e)),
+            Err(e) => log::warn!("Resolving {} failed (attempt {}): {}", host, attempt, e),
+        }
+        attempt += 1;
+        std::thread::sleep(DNS_RETRY_DELAY);
+    }
+}
+
+fn set_timeouts(tcp_stream: &TcpStream, read: Duration, write: Duration) -> Result<(), WebSocketClientError> {
+    tcp_stream.set_read_timeout(Some(read))
+        .and_then(|_| tcp_stream.set_write_timeout(Some(write)))
+        .map_err(WebSocketClientError::TcpError)
+}
+
+// Tries the addresses in order, so an IPv6 address that turns out not to be
+// reachable falls back to IPv4
+fn connect_any(addresses: &[SocketAddr], timeout: Duration) -> Result<TcpStream, WebSocketClientError> {
+    let mut error = io::Error::new(ErrorKind::NotFound, "no addresses");
+    for address in addresses {
+        match TcpStream::connect_timeout(address, timeout) {
+            Ok(tcp_stream) => return Ok(tcp_stream),
+            Err(e) => {
+                log::warn!("Failed to connect to {}: {}", address, e);
+                error = e;
+            },
+        }
+    }
+
+    Err(WebSocketClientError::TcpError(error))
+}
+
+enum Connection {
+    Plain(TcpStream),
+    // The TLS session owns the socket, `socket` is a non-owning view of the
+    // same descriptor used for socket options and liveness checks
+    Tls {
+        session: EspTls<TcpStream>,
+        socket: ManuallyDrop<TcpStream>,
+    },
+}
+
+impl Connection {
+    fn socket(&self) -> &TcpStream {
+        match self {
+            Connection::Plain(tcp_stream) => tcp_stream,
+            Connection::Tls { socket, .. } => socket,
+        }
+    }
+}
+
+fn tls_io_error(e: EspError) -> io::Error {
+    match e.code() {
+        SSL_WANT_READ | SSL_WANT_WRITE => io::Error::from(ErrorKind::WouldBlock),
+        _ => io::Error::new(ErrorKind::Other, e),
+    }
+}
+
+impl Stream<io::Error> for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
+        match self {
+            Connection::Plain(tcp_stream) => Read::read(tcp_stream, buf),
+            Connection::Tls { session, .. } => session.read(buf).map_err(tls_io_error),
+        }
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> Result<(), io::Error> {
+        match self {
+            Connection::Plain(tcp_stream) => Write::write_all(tcp_stream, buf),
+            Connection::Tls { session, .. } => session.write_all(buf).map_err(tls_io_error),
+        }
+    }
+}
+
+// Concurrency model: the client belongs to a single thread (it's neither
+// Sync nor shared) which multiplexes both directions. Reads never block, see
+// read_frame(), and writes are bounded by the socket timeouts, so sending
+// data batches and handling incoming frames take turns without waiting on
+// each other. Every Framer is built for the one call that needs it, so no two
+// of them can work on read_buf/read_cursor at the same time. Whenever an
+// error may have left a frame half read or half written, the connection and
+// the read state are dropped together, so a later connect never continues
+// from stale framing state.
 pub struct WebSocketClient<'a, const BUFSIZE: usize> {
-    tcp_stream: Option<TcpStream>,
+    connection: Option<Connection>,
+    // A Cell so the liveness check in is_connected() can record a dead peer
+    state: Cell<ConnectionState>,
     websocket: ews::WebSocketClient<ThreadRng>,
-    ws_options: ews::WebSocketOptions<'a>,
+    host: &'a str,
+    endpoint: &'a str,
+    handshake: Handshake,
     read_buf: [u8; BUFSIZE],
     write_buf: [u8; BUFSIZE],
+    frame_buf: [u8; BUFSIZE],
     read_cursor: usize,
+    // Payload of the message being received collected so far in frame_buf.
+    // Kept across reads, a message can arrive in several frames and each
+    // frame in several TCP segments.
+    frame_cursor: usize,
+    port: u16,
+    scheme: Scheme,
+    timeouts: SocketTimeouts,
+}
+
+#[derive(Debug)]
+pub enum WsMessage {
+    Text(String),
+    Binary(Vec<u8>),
 }
 
 #[derive(Debug)]
 pub enum WebSocketClientError {
-    TcpError,
-    WebSocketError,
+    TcpError(io::Error),
+    TlsError(EspError),
+    MdnsError(EspError),
+    // Looking up the host failed even after retrying, usually because DNS
+    // isn't reachable (yet), not because the collector is down
+    DnsError(io::Error),
+    WebSocketError(FramerError<io::Error>),
+    // An incoming message doesn't fit into the frame buffer
+    ReceivedTooLarge,
+    // A message to send is longer than max_payload_len(), checked before
+    // anything is written so the connection stays usable
+    MessageTooLarge { len: usize, max: usize },
+    // The server refused the upgrade with 401 or 403, trying again with the
+    // same credentials won't help
+    Unauthorized(u16),
+    // The server refused the upgrade with another HTTP status
+    HandshakeRejected(u16),
+    // TCP connected, but the server didn't complete the TLS handshake or the
+    // upgrade within SocketTimeouts::handshake
+    HandshakeTimeout,
+    Timeout,
     NotConnected,
 }
 
+impl WebSocketClientError {
+    // A client error is the same on the next attempt, except for the ones
+    // that are about timing
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            WebSocketClientError::Unauthorized(_) => false,
+            WebSocketClientError::HandshakeRejected(code) => !(400..500).contains(code) || matches!(code, 408 | 429),
+            _ => true,
+        }
+    }
+}
+
 impl Display for WebSocketClientError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:?}", self)
+        match self {
+            WebSocketClientError::TcpError(e) => write!(f, "TCP error: {}", e),
+            WebSocketClientError::TlsError(e) => write!(f, "TLS error: {}", e),
+            WebSocketClientError::MdnsError(e) => write!(f, "mDNS resolution failed: {}", e),
+            WebSocketClientError::DnsError(e) => write!(f, "DNS resolution failed: {}", e),
+            WebSocketClientError::WebSocketError(FramerError::Io(e)) => write!(f, "WebSocket I/O error: {}", e),
+            WebSocketClientError::WebSocketError(e) => write!(f, "WebSocket error: {:?}", e),
+            WebSocketClientError::ReceivedTooLarge => write!(f, "Received message doesn't fit into the frame buffer"),
+            WebSocketClientError::MessageTooLarge { len, max } => write!(f, "Message of {} bytes is larger than the {} bytes that fit into a frame", len, max),
+            WebSocketClientError::Unauthorized(code) => write!(f, "Server rejected the handshake with HTTP {}, check ws_token", code),
+            WebSocketClientError::HandshakeRejected(code) => write!(f, "Server rejected the handshake with HTTP {}", code),
+            WebSocketClientError::HandshakeTimeout => write!(f, "Server accepted the connection but didn't complete the handshake"),
+            WebSocketClientError::Timeout => write!(f, "Timed out"),
+            WebSocketClientError::NotConnected => write!(f, "Not connected"),
+        }
     }
 }
 
-impl Error for WebSocketClientError {}
+impl Error for WebSocketClientError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            WebSocketClientError::TcpError(e) => Some(e),
+            WebSocketClientError::TlsError(e) => Some(e),
+            WebSocketClientError::MdnsError(e) => Some(e),
+            WebSocketClientError::DnsError(e) => Some(e),
+            WebSocketClientError::WebSocketError(FramerError::Io(e)) => Some(e),
+            WebSocketClientError::WebSocketError(_) => None,
+            WebSocketClientError::ReceivedTooLarge => None,
+            WebSocketClientError::MessageTooLarge { .. } => None,
+            WebSocketClientError::Unauthorized(_) => None,
+            WebSocketClientError::HandshakeRejected(_) => None,
+            WebSocketClientError::HandshakeTimeout => None,
+            WebSocketClientError::Timeout => None,
+            WebSocketClientError::NotConnected => None,
+        }
+    }
+}
+
+impl From<FramerError<io::Error>> for WebSocketClientError {
+    fn from(e: FramerError<io::Error>) -> Self {
+        match e {
+            // A blocking socket operation ran into its timeout, TLS reports
+            // this as "want read/write" just like a non-blocking socket
+            FramerError::Io(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                WebSocketClientError::Timeout
+            },
+            FramerError::WebSocket(ews::Error::HttpResponseCodeInvalid(Some(code @ (401 | 403)))) => {
+                WebSocketClientError::Unauthorized(code)
+            },
+            FramerError::WebSocket(ews::Error::HttpResponseCodeInvalid(Some(code))) => {
+                WebSocketClientError::HandshakeRejected(code)
+            },
+            e => WebSocketClientError::WebSocketError(e),
+        }
+    }
+}
 
 impl<'a, const BUFSIZE: usize> WebSocketClient<'a, BUFSIZE> {
     pub fn new() -> Self {
         let read_buf = [0; BUFSIZE];
         let write_buf = [0; BUFSIZE];
+        let frame_buf = [0; BUFSIZE];
         let read_cursor = 0;
         let websocket = ews::WebSocketClient::new_client(rand::thread_rng());
-        let ws_options = WebSocketOptions {
-            path: "",
-            host: "",
-            origin: "",
-            sub_protocols: None,
-            additional_headers: None,
-        };
 
         Self {
-            tcp_stream: None,
+            connection: None,
+            state: Cell::new(ConnectionState::Disconnected),
             websocket,
-            ws_options,
+            host: "",
+            endpoint: "",
+            handshake: Handshake::default(),
             read_buf,
             write_buf,
+            frame_buf,
             read_cursor,
+            frame_cursor: 0,
+            port: 0,
+            scheme: Scheme::Ws,
+            timeouts: SocketTimeouts::default(),
         }
     }
     
-    pub fn connect(&mut self, host: &'a str, port: u16, endpoint: &'a str) -> Result<(), WebSocketClientError> {
-        
-        let host_port = format!("{}:{}", host, port);
-        let mut tcp_stream = TcpStream::connect(host_port)
-            .map_err(|_| WebSocketClientError::TcpError)?;
+    // Used by every following connect and reconnect
+    pub fn set_handshake(&mut self, handshake: Handshake) {
+        self.handshake = handshake;
+    }
+
+    pub fn state(&self) -> ConnectionState {
+        self.state.get()
+    }
+
+    pub fn connect(&mut self, host: &'a str, port: u16, endpoint: &'a str, scheme: Scheme, timeouts: SocketTimeouts) -> Result<(), WebSocketClientError> {
+
+        self.state.set(ConnectionState::Connecting);
+        let result = self.open(host, port, endpoint, scheme, timeouts);
+        self.state.set(match result {
+            Ok(()) => ConnectionState::Connected,
+            Err(_) => ConnectionState::Disconnected,
+        });
+
+        result
+    }
+
+    fn open(&mut self, host: &'a str, port: u16, endpoint: &'a str, scheme: Scheme, timeouts: SocketTimeouts) -> Result<(), WebSocketClientError> {
+
+        // The host name itself is still used for the Host header and TLS
+        let addresses = resolve(host, port, timeouts.resolve)?;
+        log::info!("Resolved {} to {:?}", host, addresses.iter().map(SocketAddr::ip).collect::<Vec<_>>());
+        let tcp_stream = connect_any(&addresses, timeouts.connect)?;
+
+        // Without timeouts a black-holed connection blocks reads and writes
+        // forever. The regular ones apply once the upgrade is through.
+        set_timeouts(&tcp_stream, timeouts.handshake, timeouts.handshake)?;
+        if let Some(keepalive) = timeouts.keepalive {
+            keepalive.apply(&tcp_stream)
+                .map_err(WebSocketClientError::TcpError)?;
+        }
+
+        let mut connection = match scheme {
+            Scheme::Ws => Connection::Plain(tcp_stream),
+            Scheme::Wss(verification) => {
+                let socket = ManuallyDrop::new(unsafe { TcpStream::from_raw_fd(tcp_stream.as_raw_fd()) });
+                let mut session = EspTls::adopt(tcp_stream)
+                    .map_err(WebSocketClientError::TlsError)?;
+
+                let tls_config = match verification {
+                    CertVerification::Bundle => tls::Config {
+                        common_name: Some(host),
+                        use_crt_bundle_attach: true,
+                        ..Default::default()
+                    },
+                    // Needs CONFIG_ESP_TLS_INSECURE and
+                    // CONFIG_ESP_TLS_SKIP_SERVER_CERT_VERIFY, see sdkconfig.insecure
+                    CertVerification::Insecure => tls::Config {
+                        skip_common_name: true,
+                        ..Default::default()
+                    },
+                };
+                // Bounded by the handshake timeouts set above. mbedTLS reports
+                // running into them as want read/write, or as a failed
+                // handshake once it gives up.
+                let started = Instant::now();
+                session.negotiate(host, &tls_config).map_err(|e| {
+                    match matches!(e.code(), SSL_WANT_READ | SSL_WANT_WRITE) || started.elapsed() >= timeouts.handshake {
+                        true => WebSocketClientError::HandshakeTimeout,
+                        false => WebSocketClientError::TlsError(e),
+                    }
+                })?;
+
+                Connection::Tls { session, socket }
+            },
+        };
         
+        self.read_cursor = 0;
         let mut framer = Framer::new(&mut self.read_buf, &mut self.read_cursor, &mut self.write_buf, &mut self.websocket);
         
+        let headers = self.handshake.headers.iter().map(String::as_str).collect::<Vec<_>>();
+        let sub_protocols = self.handshake.sub_protocol.as_deref().into_iter().collect::<Vec<_>>();
+        let path = normalize_endpoint(endpoint);
         let ws_options = WebSocketOptions {
-            path: endpoint,
+            path: &path,
             host: host,
             origin: host,
-            ..self.ws_options
+            sub_protocols: (!sub_protocols.is_empty()).then_some(&sub_protocols[..]),
+            additional_headers: (!headers.is_empty()).then_some(&headers[..]),
         };
 
-        framer.connect(&mut tcp_stream, &ws_options)
-            .map_err(|_| WebSocketClientError::WebSocketError)?;
+        framer.connect(&mut connection, &ws_options).map_err(|e| match WebSocketClientError::from(e) {
+            WebSocketClientError::Timeout => WebSocketClientError::HandshakeTimeout,
+            e => e,
+        })?;
+        set_timeouts(connection.socket(), timeouts.read, timeouts.write)?;
     
-        self.ws_options = ws_options;
-        self.tcp_stream = Some(tcp_stream);
+        self.host = host;
+        self.endpoint = endpoint;
+        self.port = port;
+        self.scheme = scheme;
+        self.timeouts = timeouts;
+        self.connection = Some(connection);
 
         Ok(())
     }
 
+    pub fn is_connected(&self) -> bool {
+
+        if self.state() != ConnectionState::Connected {
+            return false;
+        }
+
+        let alive = match self.connection.as_ref().map(Connection::socket) {
+            None => false,
+            Some(tcp_stream) => {
+                // Peek without blocking: EOF means the peer closed the
+                // connection, WouldBlock means it's alive but idle
+                if tcp_stream.set_nonblocking(true).is_err() {
+                    return false;
+                }
+
+                let mut probe = [0u8; 1];
+                let alive = match tcp_stream.peek(&mut probe) {
+                    Ok(0) => false,
+                    Ok(_) => true,
+                    Err(e) => e.kind() == ErrorKind::WouldBlock,
+                };
+
+                alive && tcp_stream.set_nonblocking(false).is_ok()
+            }
+        };
+
+        if !alive {
+            self.state.set(ConnectionState::Disconnected);
+        }
+
+        alive
+    }
+
+    pub fn reconnect(&mut self) -> Result<(), WebSocketClientError> {
+
+        // Reconnect to wherever the last successful connect went
+        let host = self.host;
+        let endpoint = self.endpoint;
+        if host.is_empty() {
+            return Err(WebSocketClientError::NotConnected);
+        }
+
+        // Tear down the stale stream and start over with fresh framing state
+        self.drop_connection();
+        self.websocket = ews::WebSocketClient::new_client(rand::thread_rng());
+
+        self.connect(host, self.port, endpoint, self.scheme, self.timeouts)
+    }
+
+    pub fn max_payload_len(&self) -> usize {
+        BUFSIZE.saturating_sub(MAX_FRAME_HEADER_LEN)
+    }
+
+    // Bytes received but not decoded yet, out of buffer_size(). Close to
+    // the size means incoming messages are about to hit ReceivedTooLarge.
+    pub fn read_cursor(&self) -> usize {
+        self.read_cursor
+    }
+
+    pub fn buffer_size(&self) -> usize {
+        BUFSIZE
+    }
+
+    // Whether read_buf holds at least one whole frame, which the next
+    // read_frame() decodes without touching the socket
+    pub fn has_complete_frame(&self) -> bool {
+        frame_len(&self.read_buf[..self.read_cursor])
+            .is_some_and(|len| len <= self.read_cursor)
+    }
+
     pub fn send_text(&mut self, text: &str) -> Result<(), WebSocketClientError> {
 
-        match self.tcp_stream.as_mut() {
+        self.check_len(text.len())?;
+        match self.connection.as_mut() {
             None => Err(WebSocketClientError::NotConnected),
-            Some(tcp_stream) => {
+            Some(connection) => {
                 let mut framer = Framer::new(&mut self.read_buf, &mut self.read_cursor, &mut self.write_buf, &mut self.websocket);
-                framer.write(tcp_stream, ews::WebSocketSendMessageType::Text, true, text.as_bytes())
-                    .map_err(|_| WebSocketClientError::WebSocketError)
+                let result = framer.write(connection, ews::WebSocketSendMessageType::Text, true, text.as_bytes())
+                    .map_err(WebSocketClientError::from);
+                self.track_write(result)
             }
         }
     }
 
     pub fn send_binary(&mut self, buf: &[u8]) -> Result<(), WebSocketClientError> {
 
-        match self.tcp_stream.as_mut() {
+        self.check_len(buf.len())?;
+        match self.connection.as_mut() {
             None => Err(WebSocketClientError::NotConnected),
-            Some(tcp_stream) => {
+            Some(connection) => {
                 let mut framer = Framer::new(&mut self.read_buf, &mut self.read_cursor, &mut self.write_buf, &mut self.websocket);
-                framer.write(tcp_stream, ews::WebSocketSendMessageType::Binary, true, buf)
-                    .map_err(|e| {
-                        log::error!("Framer error: {:?}", e);
-                        WebSocketClientError::WebSocketError
-                    })
+                let result = framer.write(connection, ews::WebSocketSendMessageType::Binary, true, buf)
+                    .map_err(WebSocketClientError::from);
+                self.track_write(result)
+            }
+        }
+    }
+
+    // The framer would only notice once the frame is half in write_buf, and
+    // with a generic error
+    fn check_len(&self, len: usize) -> Result<(), WebSocketClientError> {
+        match self.max_payload_len() {
+            max if len > max => Err(WebSocketClientError::MessageTooLarge { len, max }),
+            _ => Ok(()),
+        }
+    }
+
+    // A failed write may have left part of a frame on the wire, even if it
+    // only timed out. Anything sent after it would be garbage to the server.
+    fn track_write(&mut self, result: Result<(), WebSocketClientError>) -> Result<(), WebSocketClientError> {
+        if result.is_err() {
+            self.drop_connection();
+        }
+
+        result
+    }
+
+    fn drop_connection(&mut self) {
+        self.connection = None;
+        self.read_cursor = 0;
+        self.frame_cursor = 0;
+        self.state.set(ConnectionState::Disconnected);
+    }
+
+    pub fn read_frame(&mut self) -> Result<Option<WsMessage>, WebSocketClientError> {
+
+        let result = self.decode_frame();
+
+        // Past a framing error read_cursor can't be trusted to point at the
+        // start of a frame anymore
+        if result.is_err() {
+            self.drop_connection();
+        }
+
+        result
+    }
+
+    // Decodes what's buffered and reads more without blocking until a whole
+    // message is there. Framer::read() can't be used for this, its position
+    // in a message that is still incomplete is lost once it returns.
+    fn decode_frame(&mut self) -> Result<Option<WsMessage>, WebSocketClientError> {
+
+        loop {
+            if self.read_cursor > 0 {
+                let to = &mut self.frame_buf[self.frame_cursor..];
+                match self.websocket.read(&self.read_buf[..self.read_cursor], to) {
+                    Ok(result) => {
+                        self.read_buf.copy_within(result.len_from..self.read_cursor, 0);
+                        self.read_cursor -= result.len_from;
+
+                        match result.message_type {
+                            WebSocketReceiveMessageType::Text | WebSocketReceiveMessageType::Binary => {
+                                self.frame_cursor += result.len_to;
+                                if result.end_of_message {
+                                    let payload = &self.frame_buf[..self.frame_cursor];
+                                    let message = match result.message_type {
+                                        WebSocketReceiveMessageType::Text => WsMessage::Text(String::from_utf8_lossy(payload).into_owned()),
+                                        _ => WsMessage::Binary(payload.to_vec()),
+                                    };
+                                    self.frame_cursor = 0;
+                                    return Ok(Some(message));
+                                }
+                                if self.frame_cursor == BUFSIZE {
+                                    return Err(WebSocketClientError::ReceivedTooLarge);
+                                }
+                            },
+                            // Control frames may come in between the frames of
+                            // a message, their payload lands behind it in
+                            // frame_buf without disturbing it
+                            WebSocketReceiveMessageType::Ping => {
+                                let start = self.frame_cursor;
+                                self.reply(WebSocketSendMessageType::Pong, start..start + result.len_to)?;
+                            },
+                            WebSocketReceiveMessageType::Pong => (),
+                            WebSocketReceiveMessageType::CloseMustReply => {
+                                let start = self.frame_cursor;
+                                _ = self.reply(WebSocketSendMessageType::CloseReply, start..start + result.len_to);
+                                return Err(WebSocketClientError::NotConnected);
+                            },
+                            WebSocketReceiveMessageType::CloseCompleted => return Err(WebSocketClientError::NotConnected),
+                        }
+                        continue;
+                    },
+                    // The rest of the frame header is still on its way
+                    Err(ews::Error::ReadFrameIncomplete) => (),
+                    Err(e) => return Err(WebSocketClientError::WebSocketError(FramerError::WebSocket(e))),
+                }
+            }
+
+            // No frame header is anywhere near this long
+            if self.read_cursor == BUFSIZE {
+                return Err(WebSocketClientError::ReceivedTooLarge);
+            }
+
+            let Some(connection) = self.connection.as_mut() else {
+                return Err(WebSocketClientError::NotConnected);
+            };
+
+            // Only this read doesn't block, so replies written above go out
+            // in one piece
+            connection.socket().set_nonblocking(true)
+                .map_err(WebSocketClientError::TcpError)?;
+            let received = Stream::read(connection, &mut self.read_buf[self.read_cursor..]);
+            connection.socket().set_nonblocking(false)
+                .map_err(WebSocketClientError::TcpError)?;
+
+            match received {
+                // EOF
+                Ok(0) => return Err(WebSocketClientError::NotConnected),
+                Ok(len) => self.read_cursor += len,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(None),
+                Err(e) => return Err(WebSocketClientError::TcpError(e)),
             }
         }
     }
+
+    // Answers a control frame with the payload that was just decoded into
+    // frame_buf
+    fn reply(&mut self, message_type: WebSocketSendMessageType, payload: std::ops::Range<usize>) -> Result<(), WebSocketClientError> {
+
+        let Some(connection) = self.connection.as_mut() else {
+            return Err(WebSocketClientError::NotConnected);
+        };
+
+        let len = self.websocket.write(message_type, true, &self.frame_buf[payload], &mut self.write_buf)
+            .map_err(|e| WebSocketClientError::WebSocketError(FramerError::WebSocket(e)))?;
+        Stream::write_all(connection, &self.write_buf[..len])
+            .map_err(WebSocketClientError::TcpError)
+    }
+
+    // Handles everything the server sent so far without blocking and returns
+    // the data messages. Pings are answered with a matching Pong by the framer
+    // while reading, a Close from the server surfaces as NotConnected.
+    pub fn poll(&mut self) -> Result<Vec<WsMessage>, WebSocketClientError> {
+
+        let mut messages = Vec::new();
+        while let Some(message) = self.read_frame()? {
+            messages.push(message);
+        }
+
+        Ok(messages)
+    }
+
+    // Closes the session with a Close frame, so the server doesn't keep a
+    // half-open connection around. Closing a disconnected client is a no-op.
+    pub fn close(&mut self, status: Option<ews::WebSocketCloseStatusCode>) -> Result<(), WebSocketClientError> {
+
+        let Some(mut connection) = self.connection.take() else {
+            return Ok(());
+        };
+
+        self.state.set(ConnectionState::Closing);
+        let status = status.unwrap_or(ews::WebSocketCloseStatusCode::NormalClosure);
+        let mut framer = Framer::new(&mut self.read_buf, &mut self.read_cursor, &mut self.write_buf, &mut self.websocket);
+        let result = framer.close(&mut connection, status, None)
+            .map_err(WebSocketClientError::from)
+            .and_then(|_| match &mut connection {
+                Connection::Plain(tcp_stream) => tcp_stream.flush()
+                    .map_err(WebSocketClientError::TcpError),
+                Connection::Tls { .. } => Ok(()),
+            });
+        self.state.set(ConnectionState::Disconnected);
+
+        result
+    }
+}
+
+impl<'a, const BUFSIZE: usize> Drop for WebSocketClient<'a, BUFSIZE> {
+    fn drop(&mut self) {
+        _ = self.close(None)
+            .inspect_err(|e| log::warn!("Failed to close WebSocket cleanly: {}", e));
+    }
+}
+
+// These need the board's network stack, they run with `cargo test` on a
+// connected unit. Loopback stands in for the collector.
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    // The TCP/IP stack for loopback, WiFi isn't needed. Initializing it
+    // again is a no-op.
+    fn listen() -> TcpListener {
+        sys::esp!(unsafe { sys::esp_netif_init() }).unwrap();
+        TcpListener::bind("127.0.0.1:0").unwrap()
+    }
+
+    // A client in the open state and the server's end of its connection,
+    // past the upgrade
+    fn connected<const BUFSIZE: usize>() -> (WebSocketClient<'static, BUFSIZE>, TcpStream) {
+        let listener = listen();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+
+        let mut client = WebSocketClient::<BUFSIZE>::new();
+        client.websocket.state = ews::WebSocketState::Open;
+        client.connection = Some(Connection::Plain(stream));
+        client.state.set(ConnectionState::Connected);
+        (client, server)
+    }
+
+    // Unmasked, as a server sends it
+    fn text_frame(payload: &[u8]) -> Vec<u8> {
+        assert!(payload.len() < 126);
+        [&[0x81, payload.len() as u8][..], payload].concat()
+    }
+
+    // Reads until something other than "nothing yet" comes back
+    fn next_frame<const BUFSIZE: usize>(client: &mut WebSocketClient<'_, BUFSIZE>) -> Result<Option<WsMessage>, WebSocketClientError> {
+        let deadline = Instant::now() + Duration::from_secs(2);
+        loop {
+            match client.read_frame() {
+                Ok(None) if Instant::now() < deadline => std::thread::sleep(Duration::from_millis(10)),
+                result => return result,
+            }
+        }
+    }
+
+    // Reads without expecting a message until len bytes are stored, either
+    // undecoded or as payload decoded so far
+    fn buffer<const BUFSIZE: usize>(client: &mut WebSocketClient<'_, BUFSIZE>, len: usize) {
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while client.read_cursor() + client.frame_cursor < len {
+            assert!(matches!(client.read_frame(), Ok(None)));
+            assert!(Instant::now() < deadline, "only {} of {} bytes arrived", client.read_cursor() + client.frame_cursor, len);
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn upgrade_request_carries_path_and_query() {
+        let listener = listen();
+        let port = listener.local_addr().unwrap().port();
+        // Takes the request and hangs up, which is enough to see it
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 256];
+            while !request.ends_with(b"\r\n\r\n") {
+                match stream.read(&mut buf).unwrap() {
+                    0 => break,
+                    len => request.extend_from_slice(&buf[..len]),
+                }
+            }
+            String::from_utf8(request).unwrap()
+        });
+
+        let mut client = WebSocketClient::<256>::new();
+        let timeouts = SocketTimeouts { handshake: Duration::from_secs(2), ..Default::default() };
+        assert!(client.connect("127.0.0.1", port, "ingest?device=feather&fmt=pb", Scheme::Ws, timeouts).is_err());
+
+        let request = server.join().unwrap();
+        assert!(request.starts_with("GET /ingest?device=feather&fmt=pb HTTP/1.1\r\n"), "{}", request);
+    }
+
+    #[test]
+    fn frame_split_across_two_reads_is_reassembled() {
+        let frame = text_frame(b"hello collector");
+        // Inside the header and inside the payload
+        for split in [1, 6] {
+            let (mut client, mut server) = connected::<256>();
+            server.write_all(&frame[..split]).unwrap();
+            // A complete header is decoded right away, only the payload
+            // after it is kept
+            buffer(&mut client, if split < 2 { split } else { split - 2 });
+
+            server.write_all(&frame[split..]).unwrap();
+            match next_frame(&mut client) {
+                Ok(Some(WsMessage::Text(text))) => assert_eq!(text, "hello collector"),
+                other => panic!("Expected the text message, got {:?}", other),
+            }
+            assert_eq!(client.read_cursor(), 0);
+        }
+    }
+
+    #[test]
+    fn oversized_message_is_rejected() {
+        let (mut client, mut server) = connected::<64>();
+        server.write_all(&text_frame(&[b'x'; 100])).unwrap();
+
+        assert!(matches!(next_frame(&mut client), Err(WebSocketClientError::ReceivedTooLarge)));
+        // The rest of the frame is still on the wire, the connection can't
+        // be used anymore
+        assert_eq!(client.state(), ConnectionState::Disconnected);
+        assert!(client.connection.is_none());
+    }
 }